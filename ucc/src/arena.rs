@@ -0,0 +1,311 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::{Expr, Map, Symbol};
+use fxhash::FxHasher;
+use std::hash::{Hash, Hasher};
+use std::rc::{Rc, Weak};
+
+/// Identifies a canonical expression node in an `ExprArena`.
+/// Ids are never reused, even after the node is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ExprId(u64);
+
+struct Entry {
+    id: ExprId,
+    hash: u64,
+    // Holding a `Weak` keeps the allocation, and so the address, from being
+    // reused for another node while this entry exists.
+    node: Weak<Expr>,
+}
+
+/// A hash-consing arena for shared expression nodes.
+///
+/// Interning an `Rc<Expr>` returns the canonical node that is structurally
+/// equal to it, so canonical nodes can be compared by id. Since the children
+/// of a canonical node are themselves canonical, its hash and equality are
+/// shallow, and interning a node whose children are already canonical costs
+/// time proportional to its length, not its size. The arena only holds weak
+/// references, and forgets nodes once they have been dropped.
+#[derive(Default)]
+pub(crate) struct ExprArena {
+    entries: Map<*const Expr, Entry>,
+    buckets: Map<u64, Vec<*const Expr>>,
+    next_id: u64,
+    live_at_last_prune: usize,
+}
+
+impl ExprArena {
+    /// The id of `e`, if `e` is canonical.
+    pub(crate) fn id(&self, e: &Rc<Expr>) -> Option<ExprId> {
+        self.entries.get(&Rc::as_ptr(e)).map(|entry| entry.id)
+    }
+
+    /// Return the canonical node that is structurally equal to `e`, and its id.
+    pub(crate) fn intern(&mut self, e: &Rc<Expr>) -> (Rc<Expr>, ExprId) {
+        if let Some(id) = self.id(e) {
+            return (e.clone(), id);
+        }
+        // Intern the nodes that aren't canonical yet, children first.
+        let mut interned: Map<*const Expr, Rc<Expr>> = Map::default();
+        let mut tasks = vec![(e.clone(), false)];
+        while let Some((node, are_children_interned)) = tasks.pop() {
+            if are_children_interned {
+                let new_node = self.intern_node(&node, &interned);
+                interned.insert(Rc::as_ptr(&node), new_node);
+            } else if !interned.contains_key(&Rc::as_ptr(&node)) {
+                tasks.push((node.clone(), true));
+                for_each_quote(&node, |qe| {
+                    if self.id(qe).is_none() && !interned.contains_key(&Rc::as_ptr(qe)) {
+                        tasks.push((qe.clone(), false));
+                    }
+                });
+            }
+        }
+        let node = interned.remove(&Rc::as_ptr(e)).unwrap();
+        let id = self.id(&node).unwrap();
+        (node, id)
+    }
+
+    /// The id of the canonical composition of `es`, if any, assuming that
+    /// their children are canonical, without building the composition.
+    pub(crate) fn find_compose(&self, es: &[Expr]) -> Option<ExprId> {
+        let mut hasher = FxHasher::default();
+        3u8.hash(&mut hasher);
+        es.len().hash(&mut hasher);
+        for e in es {
+            self.hash_into(e, &mut hasher);
+        }
+        for ptr in self.buckets.get(&hasher.finish())?.iter() {
+            let entry = &self.entries[ptr];
+            if let Some(node) = entry.node.upgrade() {
+                if let Expr::Compose(children) = &*node {
+                    if children.len() == es.len()
+                        && children.iter().zip(es).all(|(c, e)| shallow_eq(c, e))
+                    {
+                        return Some(entry.id);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn lookup(&self, e: &Expr, hash: u64) -> Option<Rc<Expr>> {
+        for ptr in self.buckets.get(&hash)?.iter() {
+            if let Some(node) = self.entries[ptr].node.upgrade() {
+                if shallow_eq(&node, e) {
+                    return Some(node);
+                }
+            }
+        }
+        None
+    }
+
+    /// Intern `e`, given the canonical nodes for its children that weren't.
+    fn intern_node(&mut self, e: &Rc<Expr>, interned: &Map<*const Expr, Rc<Expr>>) -> Rc<Expr> {
+        let rebuilt = map_quotes(e, |qe| {
+            interned
+                .get(&Rc::as_ptr(qe))
+                .filter(|new_qe| !Rc::ptr_eq(qe, new_qe))
+                .cloned()
+        });
+        let candidate = rebuilt.as_ref().unwrap_or(e);
+        let hash = self.shallow_hash(candidate);
+        if let Some(node) = self.lookup(candidate, hash) {
+            return node;
+        }
+        let node = match rebuilt {
+            Some(e) => Rc::new(e),
+            None => e.clone(),
+        };
+        let id = ExprId(self.next_id);
+        self.next_id += 1;
+        let ptr = Rc::as_ptr(&node);
+        self.entries.insert(
+            ptr,
+            Entry {
+                id,
+                hash,
+                node: Rc::downgrade(&node),
+            },
+        );
+        self.buckets.entry(hash).or_default().push(ptr);
+        if self.entries.len() > 2 * self.live_at_last_prune.max(1024) {
+            self.prune();
+        }
+        node
+    }
+
+    /// Hash `e`, assuming that its children are canonical.
+    fn shallow_hash(&self, e: &Expr) -> u64 {
+        let mut hasher = FxHasher::default();
+        self.hash_into(e, &mut hasher);
+        hasher.finish()
+    }
+
+    fn hash_into(&self, mut e: &Expr, hasher: &mut FxHasher) {
+        // `es` is only allocated if `e` has nested nodes.
+        let mut es = vec![];
+        loop {
+            match e {
+                Expr::Intrinsic(intr) => {
+                    0u8.hash(hasher);
+                    intr.hash(hasher);
+                }
+                Expr::Call(sym) => {
+                    1u8.hash(hasher);
+                    sym.hash(hasher);
+                }
+                Expr::Pinned(sym, version, pe) => {
+                    7u8.hash(hasher);
+                    sym.hash(hasher);
+                    version.hash(hasher);
+                    self.id(pe).hash(hasher);
+                }
+                Expr::Quote(qe) => {
+                    2u8.hash(hasher);
+                    self.id(qe).hash(hasher);
+                }
+                Expr::Compose(children) => {
+                    3u8.hash(hasher);
+                    children.len().hash(hasher);
+                    es.extend(children.iter().rev());
+                }
+                Expr::With(sym, e) => {
+                    4u8.hash(hasher);
+                    sym.hash(hasher);
+                    es.push(e);
+                }
+                Expr::Push(sym) => {
+                    5u8.hash(hasher);
+                    sym.hash(hasher);
+                }
+                Expr::Pop(sym) => {
+                    6u8.hash(hasher);
+                    sym.hash(hasher);
+                }
+            }
+            match es.pop() {
+                Some(next) => e = next,
+                None => return,
+            }
+        }
+    }
+
+    /// Forget the nodes that have been dropped.
+    fn prune(&mut self) {
+        let buckets = &mut self.buckets;
+        self.entries.retain(|ptr, entry| {
+            if entry.node.strong_count() > 0 {
+                true
+            } else {
+                let ptrs = buckets.get_mut(&entry.hash).unwrap();
+                ptrs.retain(|p| p != ptr);
+                if ptrs.is_empty() {
+                    buckets.remove(&entry.hash);
+                }
+                false
+            }
+        });
+        self.live_at_last_prune = self.entries.len();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Compare `e1` and `e2`, assuming that their children are canonical.
+fn shallow_eq(e1: &Expr, e2: &Expr) -> bool {
+    let mut pairs = vec![];
+    let mut pair = (e1, e2);
+    loop {
+        match pair {
+            (Expr::Intrinsic(intr1), Expr::Intrinsic(intr2)) if intr1 == intr2 => {}
+            (Expr::Call(sym1), Expr::Call(sym2)) if sym1 == sym2 => {}
+            (Expr::Pinned(sym1, version1, pe1), Expr::Pinned(sym2, version2, pe2))
+                if sym1 == sym2 && version1 == version2 && Rc::ptr_eq(pe1, pe2) => {}
+            (Expr::Quote(qe1), Expr::Quote(qe2)) if Rc::ptr_eq(qe1, qe2) => {}
+            (Expr::Compose(es1), Expr::Compose(es2)) if es1.len() == es2.len() => {
+                pairs.extend(es1.iter().zip(es2.iter()));
+            }
+            (Expr::With(sym1, e1), Expr::With(sym2, e2)) if sym1 == sym2 => pairs.push((e1, e2)),
+            (Expr::Push(sym1), Expr::Push(sym2)) if sym1 == sym2 => {}
+            (Expr::Pop(sym1), Expr::Pop(sym2)) if sym1 == sym2 => {}
+            _ => return false,
+        }
+        match pairs.pop() {
+            Some(next) => pair = next,
+            None => return true,
+        }
+    }
+}
+
+/// Call `f` on each quoted expression in `e` that isn't nested in another.
+fn for_each_quote<F: FnMut(&Rc<Expr>)>(e: &Expr, mut f: F) {
+    let mut es = vec![e];
+    while let Some(e) = es.pop() {
+        match e {
+            Expr::Intrinsic(_) | Expr::Call(_) | Expr::Push(_) | Expr::Pop(_) => {}
+            Expr::Quote(qe) | Expr::Pinned(_, _, qe) => f(qe),
+            Expr::Compose(children) => es.extend(children.iter()),
+            Expr::With(_, e) => es.push(e),
+        }
+    }
+}
+
+/// Rebuild `e`, replacing each quoted expression that isn't nested in another
+/// for which `f` returns a replacement. Returns `None` if nothing was replaced.
+fn map_quotes<F: FnMut(&Rc<Expr>) -> Option<Rc<Expr>>>(e: &Expr, mut f: F) -> Option<Expr> {
+    enum Task<'a> {
+        Map(&'a Expr),
+        Compose(&'a [Expr]),
+        With(Symbol),
+    }
+    let mut tasks = vec![Task::Map(e)];
+    let mut mapped: Vec<Option<Expr>> = vec![];
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Map(Expr::Intrinsic(_))
+            | Task::Map(Expr::Call(_))
+            | Task::Map(Expr::Push(_))
+            | Task::Map(Expr::Pop(_)) => mapped.push(None),
+            Task::Map(Expr::Quote(qe)) => mapped.push(f(qe).map(Expr::Quote)),
+            Task::Map(Expr::Pinned(sym, version, pe)) => {
+                mapped.push(f(pe).map(|pe| Expr::Pinned(*sym, *version, pe)))
+            }
+            Task::Map(Expr::Compose(es)) => {
+                tasks.push(Task::Compose(es));
+                tasks.extend(es.iter().rev().map(Task::Map));
+            }
+            Task::Map(Expr::With(sym, e)) => {
+                tasks.push(Task::With(*sym));
+                tasks.push(Task::Map(e));
+            }
+            Task::Compose(es) => {
+                let new_es = mapped.split_off(mapped.len() - es.len());
+                if new_es.iter().all(Option::is_none) {
+                    mapped.push(None);
+                } else {
+                    mapped.push(Some(Expr::Compose(
+                        new_es
+                            .into_iter()
+                            .zip(es.iter())
+                            .map(|(new_e, e)| new_e.unwrap_or_else(|| e.clone()))
+                            .collect(),
+                    )));
+                }
+            }
+            Task::With(sym) => {
+                let new_e = mapped.pop().unwrap();
+                mapped.push(new_e.map(|e| Expr::With(sym, Box::new(e))));
+            }
+        }
+    }
+    mapped.pop().unwrap()
+}
@@ -0,0 +1,224 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::*;
+use crate::diagnostic::{Diagnostic, Span, SyntaxError};
+use crate::display::*;
+
+/// The maximum number of small steps that a big-step assertion may take.
+pub const BIG_STEP_FUEL: Fuel = Fuel(1_000_000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssertionKind {
+    /// `⟶`: a single small step
+    SmallStep,
+    /// `⇓`: small steps until the expression is empty, or is the expected one
+    BigStep,
+}
+
+/// An assertion that evaluating an expression on a value stack results in
+/// another value stack and expression, e.g. `⟨[a] [b]⟩ swap ⇓ ⟨[b] [a]⟩`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assertion {
+    pub kind: AssertionKind,
+    pub input: (ValueStack, Expr),
+    pub expected: (ValueStack, Expr),
+    /// Where the assertion is in its source
+    pub span: Span,
+}
+
+/// Why an assertion failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssertionFailure {
+    /// Evaluation resulted in a different value stack or expression. Holds
+    /// the expected value stack and the actual value stack and expression,
+    /// compressed.
+    Mismatch {
+        expected: ValueStack,
+        actual: (ValueStack, Expr),
+    },
+    /// Evaluation failed, with the value stack and the functions being
+    /// unfolded at the time.
+    Eval(EvalError, ValueStack, CallStack),
+    /// Evaluation didn't reach the expected expression within the fuel.
+    OutOfFuel(ValueStack, Expr),
+}
+
+/// A part of a test file, as parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TestItem {
+    FnDef(FnDef),
+    Assertion(Assertion),
+    /// A syntax error, with where the item that has it starts
+    Error(usize, SyntaxError),
+}
+
+impl Assertion {
+    /// Check the assertion against the definitions in `ctx`. Values are equal
+    /// if they are the same quotation, so `⟨[swap drop]⟩` and `⟨true⟩` are
+    /// equal if `true` is defined as `[swap drop]`.
+    pub fn check(&self, ctx: &mut Context, fuel: Fuel) -> Result<(), AssertionFailure> {
+        let mut vs = pin_versions(ctx, &self.input.0);
+        let mut e = self.input.1.clone();
+        let mut calls = CallStack::default();
+        let mut steps = 0;
+        loop {
+            let is_done = match self.kind {
+                AssertionKind::SmallStep => steps == 1,
+                AssertionKind::BigStep => e == Expr::default() || e == self.expected.1,
+            };
+            if is_done {
+                break;
+            }
+            if steps == fuel.0 {
+                return Err(AssertionFailure::OutOfFuel(vs, e));
+            }
+            if let Err(err) = ctx.small_step_with_calls(&mut vs, &mut e, &mut calls, &mut ()) {
+                return Err(AssertionFailure::Eval(err, vs, calls));
+            }
+            steps += 1;
+        }
+        let mut expected_vs = pin_versions(ctx, &self.expected.0);
+        let is_equal = e == self.expected.1
+            && values_eq(ctx, &vs.0, &expected_vs.0)
+            && vs.1.len() == expected_vs.1.len()
+            && vs.1.iter().all(|(name, values)| {
+                expected_vs
+                    .1
+                    .get(name)
+                    .is_some_and(|expected_values| values_eq(ctx, values, expected_values))
+            });
+        if is_equal {
+            Ok(())
+        } else {
+            ctx.compress(&mut vs);
+            ctx.compress(&mut expected_vs);
+            Err(AssertionFailure::Mismatch {
+                expected: expected_vs,
+                actual: (vs, e),
+            })
+        }
+    }
+
+    /// Describe `failure` as a diagnostic about the assertion.
+    pub fn diagnose(&self, ctx: &Context, failure: &AssertionFailure) -> Diagnostic {
+        let (message, notes) = match failure {
+            AssertionFailure::Mismatch {
+                expected: expected_vs,
+                actual: (vs, e),
+            } => {
+                let expected = state(ctx, expected_vs, &self.expected.1);
+                let actual = state(ctx, vs, e);
+                let caret = format!(
+                    "          {}^",
+                    " ".repeat(first_difference(&expected, &actual))
+                );
+                (
+                    "Assertion failed.".to_owned(),
+                    vec![
+                        format!("expected: {}", expected),
+                        format!("actual:   {}", actual),
+                        caret,
+                    ],
+                )
+            }
+            AssertionFailure::Eval(err, vs, calls) => {
+                let report = EvalErrorReport::new(ctx, err, vs, calls);
+                (
+                    format!("Assertion failed: {}", report.error),
+                    report.notes(),
+                )
+            }
+            AssertionFailure::OutOfFuel(vs, e) => (
+                "Assertion failed: evaluation didn't finish in time.".to_owned(),
+                vec![format!("actual:   {}", state(ctx, vs, e))],
+            ),
+        };
+        Diagnostic {
+            span: self.span,
+            message,
+            notes,
+        }
+    }
+}
+
+/// Pin the values in `vs` that were parsed before the functions they call
+/// were defined to the current definitions, as in a test file, where the
+/// assertions are parsed along with the definitions before them.
+fn pin_versions(ctx: &Context, vs: &ValueStack) -> ValueStack {
+    let pin = |v: &Value| match v {
+        Value::Call(sym, version) if *version == Version::default() => {
+            Value::Call(*sym, ctx.version(*sym))
+        }
+        v => v.clone(),
+    };
+    ValueStack(
+        vs.0.iter().map(pin).collect(),
+        vs.1.iter()
+            .map(|(&name, values)| (name, values.iter().map(pin).collect()))
+            .collect(),
+    )
+}
+
+/// Whether `values` and `expected_values` are equal, up to calls to
+/// functions defined as the quotations they are compared with, and up to
+/// pinned calls.
+fn values_eq(ctx: &Context, values: &[Value], expected_values: &[Value]) -> bool {
+    values.len() == expected_values.len()
+        && values
+            .iter()
+            .zip(expected_values.iter())
+            .all(|(v, expected_v)| {
+                v == expected_v
+                    || matches!(
+                        (ctx.unquote_value(v.clone()), ctx.unquote_value(expected_v.clone())),
+                        (Ok(e), Ok(expected_e)) if exprs_eq(ctx, &e, &expected_e)
+                    )
+            })
+}
+
+/// Whether `e` and `expected_e` are equal, up to pinned calls, which are
+/// equal to plain calls to the same functions if their versions are current,
+/// and otherwise to the expressions they hold.
+fn exprs_eq(ctx: &Context, e: &Expr, expected_e: &Expr) -> bool {
+    let mut pairs = vec![(e, expected_e)];
+    while let Some(pair) = pairs.pop() {
+        match pair {
+            (Expr::Pinned(sym, version, _), Expr::Call(call_sym))
+            | (Expr::Call(call_sym), Expr::Pinned(sym, version, _))
+                if sym == call_sym && !ctx.is_stale(*sym, *version) => {}
+            (Expr::Pinned(sym1, version1, _), Expr::Pinned(sym2, version2, _))
+                if sym1 == sym2 && version1 == version2 => {}
+            (Expr::Pinned(_, _, pe), e) => pairs.push((pe, e)),
+            (e, Expr::Pinned(_, _, pe)) => pairs.push((e, pe)),
+            (Expr::Quote(qe1), Expr::Quote(qe2)) => pairs.push((qe1, qe2)),
+            (Expr::Compose(es1), Expr::Compose(es2)) if es1.len() == es2.len() => {
+                pairs.extend(es1.iter().zip(es2.iter()));
+            }
+            (Expr::With(sym1, e1), Expr::With(sym2, e2)) if sym1 == sym2 => pairs.push((e1, e2)),
+            (e1, e2) => {
+                if e1 != e2 {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Display a value stack followed by an expression, if it isn't empty.
+fn state(ctx: &Context, vs: &ValueStack, e: &Expr) -> String {
+    if *e == Expr::default() {
+        vs.resolve(ctx).to_string()
+    } else {
+        format!("{} {}", vs.resolve(ctx), e.resolve(ctx))
+    }
+}
+
+/// The number of characters that `a` and `b` have in common at their start.
+fn first_difference(a: &str, b: &str) -> usize {
+    a.chars().zip(b.chars()).take_while(|(a, b)| a == b).count()
+}
@@ -4,7 +4,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-pub(crate) static FN_DEF_SRCS: [&'static str; 23] = [
+pub(crate) static FN_DEF_SRCS: [&str; 23] = [
     "{fn v1 = []}",
     "{fn v2 = []}",
     "{fn v3 = []}",
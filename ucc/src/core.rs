@@ -4,8 +4,20 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use crate::arena::{ExprArena, ExprId};
+use crate::diagnostic::{error_location, Diagnostic};
+use crate::machine::{compile_fn, Compiled};
+use crate::observer::{EvalObserver, Step};
+use crate::parse::{ExprParser, FnDefParser, ValueStackParser};
+use crate::types::{TypeCache, TypeChecker, TypeError};
+use fxhash::FxHashSet;
 pub(crate) use lasso::Rodeo as Interner;
-use std::hash::Hash;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 
 pub(crate) type Map<K, V> = fxhash::FxHashMap<K, V>;
 
@@ -16,7 +28,7 @@ macro_rules! map {
     };
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct Symbol(pub(crate) lasso::Spur);
 
@@ -25,12 +37,32 @@ pub struct Symbol(pub(crate) lasso::Spur);
 ////////////
 
 /// Expressions
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+///
+/// Expressions can be nested arbitrarily deep, e.g. by a program that builds
+/// up a quotation in a loop, so everything that walks them, including
+/// `clone`, `drop`, `eq` and `hash`, keeps its own stack instead of recursing.
+#[derive(Debug, Eq)]
 pub enum Expr {
     Intrinsic(Intrinsic),
     Call(Symbol),
-    Quote(Box<Expr>),
+    /// A call to a version of a function, in place of the expression it
+    /// holds, which is what it evaluates to. Compression and `quote` put it in
+    /// place of an expression that matches the definition, so that the
+    /// expression keeps its meaning if the function is redefined. It has no
+    /// syntax.
+    Pinned(Symbol, Version, Rc<Expr>),
+    /// A quotation. Quoted expressions are shared rather than copied, so
+    /// cloning or quoting a value takes constant time, while composing or
+    /// applying quotations, or calling a function, takes time proportional
+    /// to the number of nodes outside of their quoted expressions.
+    Quote(Rc<Expr>),
     Compose(Vec<Expr>),
+    /// Evaluate an expression against a named stack, e.g. `{$s e}`.
+    With(Symbol, Box<Expr>),
+    /// Move the top value of the current stack onto a named stack, e.g. `{$s push}`.
+    Push(Symbol),
+    /// Move the top value of a named stack onto the current stack, e.g. `{$s pop}`.
+    Pop(Symbol),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -41,6 +73,10 @@ pub enum Intrinsic {
     Quote,
     Compose,
     Apply,
+    /// Mark a quotation as linear.
+    Linear,
+    /// Mark a quotation as affine.
+    Affine,
 }
 
 impl Default for Expr {
@@ -49,29 +85,596 @@ impl Default for Expr {
     }
 }
 
+impl Clone for Expr {
+    fn clone(&self) -> Self {
+        enum Task<'a> {
+            Clone(&'a Expr),
+            Compose(usize),
+            With(Symbol),
+        }
+        let mut tasks = vec![Task::Clone(self)];
+        let mut cloned = vec![];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Clone(Expr::Intrinsic(intr)) => cloned.push(Expr::Intrinsic(*intr)),
+                Task::Clone(Expr::Call(sym)) => cloned.push(Expr::Call(*sym)),
+                Task::Clone(Expr::Pinned(sym, version, pe)) => {
+                    cloned.push(Expr::Pinned(*sym, *version, pe.clone()))
+                }
+                Task::Clone(Expr::Quote(qe)) => cloned.push(Expr::Quote(qe.clone())),
+                Task::Clone(Expr::Compose(es)) => {
+                    tasks.push(Task::Compose(es.len()));
+                    tasks.extend(es.iter().rev().map(Task::Clone));
+                }
+                Task::Clone(Expr::With(sym, e)) => {
+                    tasks.push(Task::With(*sym));
+                    tasks.push(Task::Clone(e));
+                }
+                Task::Clone(Expr::Push(sym)) => cloned.push(Expr::Push(*sym)),
+                Task::Clone(Expr::Pop(sym)) => cloned.push(Expr::Pop(*sym)),
+                Task::Compose(len) => {
+                    let es = cloned.split_off(cloned.len() - len);
+                    cloned.push(Expr::Compose(es));
+                }
+                Task::With(sym) => {
+                    let e = cloned.pop().unwrap();
+                    cloned.push(Expr::With(sym, Box::new(e)));
+                }
+            }
+        }
+        cloned.pop().unwrap()
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pairs = vec![(self, other)];
+        while let Some(pair) = pairs.pop() {
+            match pair {
+                (Expr::Intrinsic(intr1), Expr::Intrinsic(intr2)) if intr1 == intr2 => {}
+                (Expr::Call(sym1), Expr::Call(sym2)) if sym1 == sym2 => {}
+                (Expr::Quote(e1), Expr::Quote(e2)) => {
+                    if !Rc::ptr_eq(e1, e2) {
+                        pairs.push((e1, e2));
+                    }
+                }
+                (Expr::Pinned(sym1, version1, e1), Expr::Pinned(sym2, version2, e2))
+                    if sym1 == sym2 && version1 == version2 =>
+                {
+                    if !Rc::ptr_eq(e1, e2) {
+                        pairs.push((e1, e2));
+                    }
+                }
+                (Expr::Compose(es1), Expr::Compose(es2)) if es1.len() == es2.len() => {
+                    pairs.extend(es1.iter().zip(es2.iter()));
+                }
+                (Expr::With(sym1, e1), Expr::With(sym2, e2)) if sym1 == sym2 => {
+                    pairs.push((e1, e2));
+                }
+                (Expr::Push(sym1), Expr::Push(sym2)) if sym1 == sym2 => {}
+                (Expr::Pop(sym1), Expr::Pop(sym2)) if sym1 == sym2 => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Hash for Expr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut es = vec![self];
+        while let Some(e) = es.pop() {
+            std::mem::discriminant(e).hash(state);
+            match e {
+                Expr::Intrinsic(intr) => intr.hash(state),
+                Expr::Call(sym) => sym.hash(state),
+                Expr::Pinned(sym, version, pe) => {
+                    sym.hash(state);
+                    version.hash(state);
+                    es.push(pe);
+                }
+                Expr::Quote(qe) => es.push(qe),
+                Expr::Compose(children) => {
+                    children.len().hash(state);
+                    es.extend(children.iter().rev());
+                }
+                Expr::With(sym, e) => {
+                    sym.hash(state);
+                    es.push(e);
+                }
+                Expr::Push(sym) | Expr::Pop(sym) => sym.hash(state),
+            }
+        }
+    }
+}
+
+thread_local! {
+    static EMPTY_EXPR: Rc<Expr> = Rc::new(Expr::default());
+}
+
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut es = vec![];
+        self.take_children(&mut es);
+        while let Some(mut e) = es.pop() {
+            e.take_children(&mut es);
+        }
+    }
+}
+
+impl Expr {
+    /// Move the children that would be dropped along with this expression into `es`.
+    fn take_children(&mut self, es: &mut Vec<Expr>) {
+        match self {
+            Expr::Intrinsic(_) | Expr::Call(_) | Expr::Push(_) | Expr::Pop(_) => {}
+            Expr::Quote(qe) | Expr::Pinned(_, _, qe) => {
+                if Rc::strong_count(qe) == 1 {
+                    if let Ok(empty) = EMPTY_EXPR.try_with(Rc::clone) {
+                        if let Ok(e) = Rc::try_unwrap(std::mem::replace(qe, empty)) {
+                            es.push(e);
+                        }
+                    }
+                }
+            }
+            Expr::Compose(children) => {
+                es.extend(children.drain(..).filter(|e| {
+                    matches!(
+                        e,
+                        Expr::Quote(_) | Expr::Pinned(..) | Expr::Compose(_) | Expr::With(..)
+                    )
+                }));
+            }
+            Expr::With(_, e) => es.push(std::mem::take(&mut **e)),
+        }
+    }
+}
+
+impl Expr {
+    /// Compose `es`, flattening nested compositions and unwrapping a single
+    /// expression. The result is canonical if each of `es` is.
+    pub fn compose<I: IntoIterator<Item = Expr>>(es: I) -> Expr {
+        let mut new_es = vec![];
+        let mut iters = vec![es.into_iter().collect::<Vec<_>>().into_iter()];
+        while let Some(iter) = iters.last_mut() {
+            match iter.next() {
+                None => {
+                    iters.pop();
+                }
+                Some(mut e) => {
+                    if let Expr::Compose(children) = &mut e {
+                        iters.push(std::mem::take(children).into_iter());
+                    } else {
+                        new_es.push(e);
+                    }
+                }
+            }
+        }
+        if new_es.len() == 1 {
+            new_es.pop().unwrap()
+        } else {
+            Expr::Compose(new_es)
+        }
+    }
+
+    /// The functions called by this expression, including in its quoted
+    /// expressions, in the order in which they appear.
+    pub(crate) fn calls(&self) -> Vec<Symbol> {
+        let mut calls = vec![];
+        let mut es = vec![self];
+        while let Some(e) = es.pop() {
+            match e {
+                Expr::Intrinsic(_) | Expr::Push(_) | Expr::Pop(_) => {}
+                Expr::Call(sym) => calls.push(*sym),
+                // A pinned call evaluates to the expression it holds.
+                Expr::Quote(qe) | Expr::Pinned(_, _, qe) => es.push(qe),
+                Expr::Compose(children) => es.extend(children.iter().rev()),
+                Expr::With(_, e) => es.push(e),
+            }
+        }
+        calls
+    }
+
+    /// The number of expressions composed by this one, which is 1 if it
+    /// isn't a composition.
+    pub(crate) fn elements_len(&self) -> usize {
+        match self {
+            Expr::Compose(es) => es.len(),
+            _ => 1,
+        }
+    }
+
+    /// Whether this expression is canonical, i.e. no composition in it,
+    /// including in its quoted expressions and stack contexts, has exactly
+    /// one expression or contains another composition.
+    ///
+    /// Canonical expressions are structurally equal if and only if they are
+    /// the same up to associativity and identity of composition.
+    pub fn is_canonical(&self) -> bool {
+        // Quoted expressions may be shared, so check each only once.
+        let mut seen: FxHashSet<*const Expr> = FxHashSet::default();
+        let mut es = vec![self];
+        while let Some(e) = es.pop() {
+            match e {
+                Expr::Intrinsic(_) | Expr::Call(_) | Expr::Push(_) | Expr::Pop(_) => {}
+                Expr::Quote(qe) | Expr::Pinned(_, _, qe) => {
+                    if seen.insert(Rc::as_ptr(qe)) {
+                        es.push(qe);
+                    }
+                }
+                Expr::Compose(children) => {
+                    if children.len() == 1 || children.iter().any(|e| matches!(e, Expr::Compose(_)))
+                    {
+                        return false;
+                    }
+                    es.extend(children.iter());
+                }
+                Expr::With(_, e) => es.push(e),
+            }
+        }
+        true
+    }
+
+    /// The canonical form of this expression.
+    pub fn normalize(self) -> Expr {
+        match self.normalized() {
+            Some(e) => e,
+            None => self,
+        }
+    }
+
+    /// The canonical form of this expression, or `None` if it is canonical.
+    fn normalized(&self) -> Option<Expr> {
+        enum Task<'a> {
+            Normalize(&'a Expr),
+            Quote(&'a Rc<Expr>),
+            Compose(&'a [Expr]),
+            With(Symbol),
+            Pinned(Symbol, Version),
+        }
+        // Quoted expressions may be shared, so normalize each only once.
+        let mut quotes: Map<*const Expr, Option<Rc<Expr>>> = Map::default();
+        let mut tasks = vec![Task::Normalize(self)];
+        let mut normalized: Vec<Option<Expr>> = vec![];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Normalize(Expr::Intrinsic(_))
+                | Task::Normalize(Expr::Call(_))
+                | Task::Normalize(Expr::Push(_))
+                | Task::Normalize(Expr::Pop(_)) => normalized.push(None),
+                Task::Normalize(Expr::Quote(qe)) => match quotes.get(&Rc::as_ptr(qe)) {
+                    Some(new_qe) => normalized.push(new_qe.clone().map(Expr::Quote)),
+                    None => {
+                        tasks.push(Task::Quote(qe));
+                        tasks.push(Task::Normalize(qe));
+                    }
+                },
+                Task::Normalize(Expr::Compose(es)) => {
+                    tasks.push(Task::Compose(es));
+                    tasks.extend(es.iter().rev().map(Task::Normalize));
+                }
+                Task::Normalize(Expr::With(sym, e)) => {
+                    tasks.push(Task::With(*sym));
+                    tasks.push(Task::Normalize(e));
+                }
+                Task::Normalize(Expr::Pinned(sym, version, pe)) => {
+                    tasks.push(Task::Pinned(*sym, *version));
+                    tasks.push(Task::Normalize(pe));
+                }
+                Task::Quote(qe) => {
+                    let new_qe = normalized.pop().unwrap().map(Rc::new);
+                    quotes.insert(Rc::as_ptr(qe), new_qe.clone());
+                    normalized.push(new_qe.map(Expr::Quote));
+                }
+                Task::Compose(es) => {
+                    let new_es = normalized.split_off(normalized.len() - es.len());
+                    if es.len() != 1
+                        && new_es.iter().all(Option::is_none)
+                        && !es.iter().any(|e| matches!(e, Expr::Compose(_)))
+                    {
+                        normalized.push(None);
+                    } else {
+                        normalized.push(Some(Expr::compose(
+                            new_es
+                                .into_iter()
+                                .zip(es.iter())
+                                .map(|(new_e, e)| new_e.unwrap_or_else(|| e.clone())),
+                        )));
+                    }
+                }
+                Task::With(sym) => {
+                    let new_e = normalized.pop().unwrap();
+                    normalized.push(new_e.map(|e| Expr::With(sym, Box::new(e))));
+                }
+                Task::Pinned(sym, version) => {
+                    let new_pe = normalized.pop().unwrap();
+                    normalized.push(new_pe.map(|pe| Expr::Pinned(sym, version, Rc::new(pe))));
+                }
+            }
+        }
+        normalized.pop().unwrap()
+    }
+}
+
 ///////////////
 // Semantics //
 ///////////////
 
+/// A version of the definition of a function. Each definition of a function
+/// is a new version, numbered from 1. Version 0 refers to no definition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Version(pub(crate) u32);
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Value {
-    Call(Symbol),
-    Quote(Box<Expr>),
+    /// A call to the version of a function, defined as a quotation, that was
+    /// current when the value was created
+    Call(Symbol, Version),
+    Quote(Rc<Expr>),
+    /// A quotation marked as affine or linear, which can't be cloned or, if
+    /// linear, dropped when linearity is checked.
+    Marked(Usage, Rc<Expr>),
+    /// Data of the host, e.g. from a native function, which isn't a quotation.
+    /// Cloning the value shares the data, which is dropped along with its last
+    /// value.
+    Host(Rc<dyn HostValue>),
+}
+
+/// How a value may be used. Each is more restrictive than the one before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Usage {
+    /// The value can be cloned and dropped.
+    Unrestricted,
+    /// The value can be dropped, but not cloned.
+    Affine,
+    /// The value can be neither cloned nor dropped.
+    Linear,
+}
+
+impl Usage {
+    /// The intrinsic that marks a quotation as restricted to this usage,
+    /// which must not be unrestricted.
+    pub(crate) fn intrinsic(self) -> Intrinsic {
+        match self {
+            Usage::Unrestricted => unreachable!(),
+            Usage::Affine => Intrinsic::Affine,
+            Usage::Linear => Intrinsic::Linear,
+        }
+    }
+}
+
+/// Data that the host can put on the value stack.
+pub trait HostValue: AsAny + fmt::Debug + fmt::Display {
+    /// Whether this is equal to `other`. By default, host values are equal
+    /// only to themselves.
+    fn eq_host(&self, other: &dyn HostValue) -> bool {
+        std::ptr::eq(
+            self as *const Self as *const u8,
+            other as *const dyn HostValue as *const u8,
+        )
+    }
+}
+
+/// Conversion to `Any`, so that host values can be downcast.
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl PartialEq for dyn HostValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_host(other)
+    }
+}
+
+impl Eq for dyn HostValue {}
+
+impl Value {
+    /// How this value may be used.
+    pub fn usage(&self) -> Usage {
+        match self {
+            Value::Marked(usage, _) => *usage,
+            _ => Usage::Unrestricted,
+        }
+    }
+
+    /// The quotation of `e`, marked with `usage` unless it is unrestricted.
+    pub(crate) fn quotation(usage: Usage, e: Rc<Expr>) -> Value {
+        match usage {
+            Usage::Unrestricted => Value::Quote(e),
+            usage => Value::Marked(usage, e),
+        }
+    }
+
+    /// A value that holds `data` of the host.
+    pub fn host<T: HostValue + 'static>(data: T) -> Value {
+        Value::Host(Rc::new(data))
+    }
+
+    /// The data of the host that this value holds, if it is a `T`.
+    pub fn as_host<T: HostValue + 'static>(&self) -> Option<&T> {
+        match self {
+            Value::Host(data) => (**data).as_any().downcast_ref(),
+            _ => None,
+        }
+    }
 }
 
+/// The main stack of values, along with the named stacks, e.g. `$s`, that
+/// aren't empty.
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
-pub struct ValueStack(pub(crate) Vec<Value>);
+pub struct ValueStack(
+    pub(crate) Vec<Value>,
+    pub(crate) BTreeMap<Symbol, Vec<Value>>,
+);
+
+impl ValueStack {
+    /// The values of the main stack, from the bottom of the stack to the top.
+    pub fn values(&self) -> &[Value] {
+        &self.0
+    }
+
+    pub fn into_values(self) -> Vec<Value> {
+        self.0
+    }
+
+    /// The values of the named stack `name`, from the bottom of the stack to
+    /// the top.
+    pub fn named(&self, name: Symbol) -> &[Value] {
+        self.1.get(&name).map_or(&[], |values| &values[..])
+    }
+
+    /// The named stacks that aren't empty, in an unspecified order.
+    pub fn named_stacks(&self) -> impl Iterator<Item = (Symbol, &[Value])> + '_ {
+        self.1.iter().map(|(&name, values)| (name, &values[..]))
+    }
+
+    /// The stack `name`, or the main stack if `name` is `None`.
+    fn stack_mut(&mut self, name: Option<Symbol>) -> &mut Vec<Value> {
+        match name {
+            None => &mut self.0,
+            Some(name) => self.1.entry(name).or_default(),
+        }
+    }
+
+    /// Move the top value of the stack `from` onto the stack `to`, where
+    /// `None` is the main stack. Returns whether there was a value to move.
+    fn transfer(&mut self, from: Option<Symbol>, to: Option<Symbol>) -> bool {
+        let moved = match self.stack_mut(from).pop() {
+            Some(v) => {
+                self.stack_mut(to).push(v);
+                true
+            }
+            None => false,
+        };
+        self.1.retain(|_, values| !values.is_empty());
+        moved
+    }
+}
+
+impl From<Vec<Value>> for ValueStack {
+    fn from(values: Vec<Value>) -> Self {
+        ValueStack(values, BTreeMap::default())
+    }
+}
 
 pub struct Context {
     pub(crate) interner: Interner,
     pub(crate) fns: Map<Symbol, Expr>,
-    pub(crate) exprs: Map<Expr, Symbol>,
+    pub(crate) arena: ExprArena,
+    /// The functions that are defined as quotations, by the id of the quoted
+    /// expression, in the order in which they were defined
+    pub(crate) quotes: Map<ExprId, Vec<Symbol>>,
+    /// The functions that are defined as compositions, by the id of their
+    /// body, which is held so that it keeps its id, in the order in which they
+    /// were defined
+    pub(crate) runs: Map<ExprId, (Vec<Symbol>, Rc<Expr>)>,
+    pub(crate) code: Map<Symbol, Compiled>,
+    /// The latest version of each function that has been defined, even if
+    /// the definitions have since been cleared
+    pub(crate) versions: Map<Symbol, Version>,
+    /// The quoted expression of each version of the functions defined as
+    /// quotations, which values may still refer to
+    pub(crate) quoted: Map<(Symbol, Version), Rc<Expr>>,
+    /// The functions that are implemented by the host
+    pub(crate) natives: Map<Symbol, Native>,
+    /// The canonical node of each quoted expression with plain calls in place
+    /// of its pinned calls to current versions, if it has any, by the id of
+    /// the quoted expression. It is cleared whenever a version changes.
+    pub(crate) unpinned: Map<ExprId, Option<Rc<Expr>>>,
+    /// The signatures of the functions that are annotated with one
+    pub(crate) signatures: Map<Symbol, Signature>,
+    /// The types of the functions that have been inferred, which type
+    /// checkers fill in as they infer them
+    pub(crate) types: RefCell<TypeCache>,
+    /// Whether cloning and dropping marked quotations fails
+    pub(crate) linearity: bool,
+}
+
+/// The implementation of a native function, which is called with the values
+/// it takes from the top of the stack, bottom-most first, and returns the
+/// values to push in their place or an error message.
+pub type NativeFn = dyn Fn(&Context, &[Value]) -> Result<Vec<Value>, String>;
+
+/// A function that is implemented by the host
+#[derive(Clone)]
+pub(crate) struct Native {
+    /// The number of values that the function takes from the stack
+    pub(crate) arity: usize,
+    pub(crate) f: Rc<NativeFn>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum EvalError {
-    TooFewValues { available: usize, expected: usize },
+    /// An intrinsic expected more values than the stack held.
+    TooFewValues {
+        intrinsic: Intrinsic,
+        available: usize,
+        expected: usize,
+    },
     UndefinedFn(Symbol),
+    /// A native function expected more values than the stack held.
+    NativeTooFewValues {
+        native: Symbol,
+        available: usize,
+        expected: usize,
+    },
+    /// A native function failed, with its error message.
+    NativeFailed(Symbol, String),
+    /// A value that calls a function that isn't defined as a quotation was
+    /// used as a quotation.
+    NotAQuotation(Symbol),
+    /// A host value was used as a quotation.
+    HostNotAQuotation(Rc<dyn HostValue>),
+    /// An intrinsic would clone or drop a value whose usage forbids it.
+    Restricted {
+        intrinsic: Intrinsic,
+        usage: Usage,
+    },
+    /// `{$s push}` was evaluated with the current stack empty.
+    PushEmpty(Symbol),
+    /// `{$s pop}` was evaluated with `$s` empty.
+    PopEmpty(Symbol),
+    /// An error occurred while evaluating against a named stack.
+    InStack(Symbol, Box<EvalError>),
+}
+
+/// The maximum number of small steps that `Context::eval` may take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fuel(pub usize);
+
+/// The number of quoted expressions that `Context::unpinned` may hold
+/// before it is cleared
+const MAX_UNPINNED: usize = 1 << 16;
+
+/// The result of a big-step evaluation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The expression was evaluated to the empty expression.
+    Normal,
+    /// The fuel ran out first. Holds the expression that remains to be evaluated.
+    OutOfFuel(Expr),
+}
+
+/// The functions whose definitions are being unfolded by small steps,
+/// innermost last. Each is paired with the number of expressions that follow
+/// its definition in the expression being evaluated.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CallStack(pub(crate) Vec<(Symbol, usize)>);
+
+impl CallStack {
+    /// The functions being unfolded, innermost first.
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol> + '_ {
+        self.0.iter().rev().map(|&(sym, _)| sym)
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
 }
 
 impl Default for Context {
@@ -80,141 +683,491 @@ impl Default for Context {
         Context {
             interner,
             fns: Map::default(),
-            exprs: Map::default(),
+            arena: ExprArena::default(),
+            quotes: Map::default(),
+            runs: Map::default(),
+            code: Map::default(),
+            versions: Map::default(),
+            quoted: Map::default(),
+            natives: Map::default(),
+            unpinned: Map::default(),
+            signatures: Map::default(),
+            types: RefCell::default(),
+            linearity: false,
         }
     }
 }
 
 impl Context {
-    fn unquote_value(&self, v: Value) -> Result<Expr, EvalError> {
+    /// The current version of `sym`, or version 0 if it isn't defined.
+    pub fn version(&self, sym: Symbol) -> Version {
+        if self.fns.contains_key(&sym) {
+            self.versions[&sym]
+        } else {
+            Version::default()
+        }
+    }
+
+    /// Whether `version` of `sym` has been redefined or cleared since.
+    pub(crate) fn is_stale(&self, sym: Symbol, version: Version) -> bool {
+        version != Version::default() && version != self.version(sym)
+    }
+
+    /// The value of a call, pinned to `version` of `sym`, that holds the
+    /// quotation of `qe`. It is a call to that version if `sym` was defined
+    /// as that quotation, and the quotation otherwise.
+    pub(crate) fn pinned_value(&self, sym: Symbol, version: Version, qe: &Rc<Expr>) -> Value {
+        match self.quoted.get(&(sym, version)) {
+            Some(def) if Rc::ptr_eq(def, qe) => Value::Call(sym, version),
+            _ => Value::Quote(qe.clone()),
+        }
+    }
+
+    /// Check that `v` can be unquoted, so that evaluation can fail before
+    /// the value stack is changed.
+    pub(crate) fn check_quotation(&self, v: &Value) -> Result<(), EvalError> {
         match v {
-            Value::Call(sym) => {
-                if let Some(e) = self.fns.get(&sym) {
-                    match e {
-                        Expr::Quote(e) => Ok((**e).clone()),
-                        _ => panic!(),
-                    }
+            Value::Call(sym, version) if !self.quoted.contains_key(&(*sym, *version)) => {
+                Err(self.not_a_quotation(*sym, *version))
+            }
+            Value::Host(data) => Err(EvalError::HostNotAQuotation(data.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that `intr` may be applied to `v`, given the most restrictive
+    /// usage that it allows, if linearity is checked.
+    fn check_usage(&self, intr: Intrinsic, v: &Value, allowed: Usage) -> Result<(), EvalError> {
+        let usage = v.usage();
+        if self.linearity && usage > allowed {
+            Err(EvalError::Restricted {
+                intrinsic: intr,
+                usage,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn not_a_quotation(&self, sym: Symbol, version: Version) -> EvalError {
+        if version == Version::default() {
+            EvalError::UndefinedFn(sym)
+        } else {
+            EvalError::NotAQuotation(sym)
+        }
+    }
+
+    /// Run `f` on `vs` with the stack `current` in place of the main stack,
+    /// where `None` is the main stack itself. Errors are attributed to
+    /// `current`, except for calls to undefined functions.
+    pub(crate) fn focus<T, F>(
+        &mut self,
+        vs: &mut ValueStack,
+        current: Option<Symbol>,
+        f: F,
+    ) -> Result<T, EvalError>
+    where
+        F: FnOnce(&mut Context, &mut ValueStack) -> Result<T, EvalError>,
+    {
+        let name = match current {
+            None => return f(self, vs),
+            Some(name) => name,
+        };
+        let main = std::mem::replace(&mut vs.0, vs.1.remove(&name).unwrap_or_default());
+        let result = f(self, vs);
+        let named = std::mem::replace(&mut vs.0, main);
+        if !named.is_empty() {
+            vs.1.insert(name, named);
+        }
+        result.map_err(|err| match err {
+            EvalError::UndefinedFn(_) => err,
+            err => EvalError::InStack(name, Box::new(err)),
+        })
+    }
+
+    /// Evaluate `{$s push}`, where `sym` is `$s`, with `current` as the
+    /// current stack.
+    pub(crate) fn step_push(
+        &mut self,
+        vs: &mut ValueStack,
+        current: Option<Symbol>,
+        sym: Symbol,
+    ) -> Result<(), EvalError> {
+        // Within the focus, the current stack is the main stack.
+        let target = Some(sym).filter(|&sym| current != Some(sym));
+        self.focus(vs, current, |_, vs| {
+            if vs.transfer(None, target) {
+                Ok(())
+            } else {
+                Err(EvalError::PushEmpty(sym))
+            }
+        })
+    }
+
+    /// Evaluate `{$s pop}`, where `sym` is `$s`, with `current` as the
+    /// current stack.
+    pub(crate) fn step_pop(
+        &mut self,
+        vs: &mut ValueStack,
+        current: Option<Symbol>,
+        sym: Symbol,
+    ) -> Result<(), EvalError> {
+        let source = Some(sym).filter(|&sym| current != Some(sym));
+        self.focus(vs, current, |_, vs| {
+            if vs.transfer(source, None) {
+                Ok(())
+            } else {
+                Err(EvalError::PopEmpty(sym))
+            }
+        })
+    }
+
+    /// The expression that `v` quotes.
+    pub fn unquote_value(&self, v: Value) -> Result<Expr, EvalError> {
+        match v {
+            // TODO: this copies the top-level nodes of a shared quotation, so
+            // composing or applying it isn't constant time. That needs
+            // compositions made of shared segments, which is left for later.
+            Value::Call(sym, version) => match self.quoted.get(&(sym, version)) {
+                Some(e) => Ok((**e).clone()),
+                None => Err(self.not_a_quotation(sym, version)),
+            },
+            Value::Quote(e) | Value::Marked(_, e) => {
+                Ok(Rc::try_unwrap(e).unwrap_or_else(|e| (*e).clone()))
+            }
+            Value::Host(data) => Err(EvalError::HostNotAQuotation(data)),
+        }
+    }
+
+    /// Apply an intrinsic to the value stack. Returns the expression that
+    /// evaluation continues with, if any.
+    pub(crate) fn step_intrinsic(
+        &mut self,
+        vs: &mut ValueStack,
+        intr: Intrinsic,
+    ) -> Result<Option<Expr>, EvalError> {
+        match intr {
+            Intrinsic::Swap => {
+                if vs.0.len() < 2 {
+                    Err(EvalError::TooFewValues {
+                        intrinsic: intr,
+                        available: vs.0.len(),
+                        expected: 2,
+                    })
                 } else {
-                    Err(EvalError::UndefinedFn(sym))
+                    let v = vs.0.remove(vs.0.len() - 2);
+                    vs.0.push(v);
+                    Ok(None)
+                }
+            }
+            Intrinsic::Clone => {
+                if vs.0.is_empty() {
+                    Err(EvalError::TooFewValues {
+                        intrinsic: intr,
+                        available: vs.0.len(),
+                        expected: 1,
+                    })
+                } else {
+                    let v = vs.0.last().unwrap();
+                    self.check_usage(intr, v, Usage::Unrestricted)?;
+                    vs.0.push(v.clone());
+                    Ok(None)
+                }
+            }
+            Intrinsic::Drop => {
+                if vs.0.is_empty() {
+                    Err(EvalError::TooFewValues {
+                        intrinsic: intr,
+                        available: vs.0.len(),
+                        expected: 1,
+                    })
+                } else {
+                    self.check_usage(intr, vs.0.last().unwrap(), Usage::Affine)?;
+                    vs.0.pop();
+                    Ok(None)
+                }
+            }
+            Intrinsic::Quote => {
+                if vs.0.is_empty() {
+                    Err(EvalError::TooFewValues {
+                        intrinsic: intr,
+                        available: vs.0.len(),
+                        expected: 1,
+                    })
+                } else {
+                    let v = vs.0.last_mut().unwrap();
+                    let usage = v.usage();
+                    let qe = match v {
+                        // A plain call would call whichever version is current
+                        // when it is evaluated, so pin the call to its version.
+                        Value::Call(sym, version) => match self.quoted.get(&(*sym, *version)) {
+                            Some(e) => {
+                                Expr::Pinned(*sym, *version, Rc::new(Expr::Quote(e.clone())))
+                            }
+                            None if *version == Version::default() => Expr::Call(*sym),
+                            None => return Err(EvalError::NotAQuotation(*sym)),
+                        },
+                        Value::Quote(e) => Expr::Quote(e.clone()),
+                        // Expressions can't hold marks, so mark the quotation
+                        // again when it is pushed.
+                        Value::Marked(usage, e) => Expr::compose([
+                            Expr::Quote(e.clone()),
+                            Expr::Intrinsic(usage.intrinsic()),
+                        ]),
+                        // Expressions can't hold host values.
+                        Value::Host(data) => {
+                            return Err(EvalError::HostNotAQuotation(data.clone()))
+                        }
+                    };
+                    *v = Value::quotation(usage, Rc::new(qe));
+                    Ok(None)
+                }
+            }
+            Intrinsic::Compose => {
+                if vs.0.len() < 2 {
+                    Err(EvalError::TooFewValues {
+                        intrinsic: intr,
+                        available: vs.0.len(),
+                        expected: 2,
+                    })
+                } else {
+                    self.check_quotation(&vs.0[vs.0.len() - 1])?;
+                    self.check_quotation(&vs.0[vs.0.len() - 2])?;
+                    let v2 = vs.0.pop().unwrap();
+                    let v1 = vs.0.pop().unwrap();
+                    let usage = v1.usage().max(v2.usage());
+                    let e2 = self.unquote_value(v2)?;
+                    let e1 = self.unquote_value(v1)?;
+                    vs.0.push(Value::quotation(usage, Rc::new(Expr::compose([e1, e2]))));
+                    Ok(None)
+                }
+            }
+            Intrinsic::Apply => {
+                if vs.0.is_empty() {
+                    Err(EvalError::TooFewValues {
+                        intrinsic: intr,
+                        available: vs.0.len(),
+                        expected: 1,
+                    })
+                } else {
+                    self.check_quotation(&vs.0[vs.0.len() - 1])?;
+                    let e1 = self.unquote_value(vs.0.pop().unwrap())?;
+                    Ok(Some(e1))
+                }
+            }
+            Intrinsic::Linear | Intrinsic::Affine => {
+                if vs.0.is_empty() {
+                    Err(EvalError::TooFewValues {
+                        intrinsic: intr,
+                        available: vs.0.len(),
+                        expected: 1,
+                    })
+                } else {
+                    self.check_quotation(&vs.0[vs.0.len() - 1])?;
+                    let v = vs.0.pop().unwrap();
+                    let mark = match intr {
+                        Intrinsic::Linear => Usage::Linear,
+                        _ => Usage::Affine,
+                    };
+                    let usage = v.usage().max(mark);
+                    let e = self.unquote_value(v)?;
+                    vs.0.push(Value::Marked(usage, Rc::new(e)));
+                    Ok(None)
                 }
             }
-            Value::Quote(e) => Ok(*e),
         }
     }
 
+    /// Take a single small step. If `e` and the quotations in `vs` are
+    /// canonical, they remain canonical.
     pub fn small_step(&mut self, vs: &mut ValueStack, e: &mut Expr) -> Result<(), EvalError> {
-        match e {
-            Expr::Intrinsic(intr) => match intr {
-                Intrinsic::Swap => {
-                    if vs.0.len() < 2 {
-                        Err(EvalError::TooFewValues {
-                            available: vs.0.len(),
-                            expected: 2,
-                        })
-                    } else {
-                        let v = vs.0.remove(vs.0.len() - 2);
-                        vs.0.push(v);
-                        *e = Expr::default();
-                        Ok(())
-                    }
+        enum Frame {
+            Compose(Vec<Expr>),
+            With(Symbol),
+        }
+        // Instead of recursing into the first expression of each composition
+        // and stack context, detach them along the way down and reattach them
+        // on the way back up.
+        let mut spine: Vec<Frame> = vec![];
+        let mut current = None;
+        let mut e1 = std::mem::take(e);
+        loop {
+            match &mut e1 {
+                Expr::Compose(es) if !es.is_empty() => {
+                    let first = std::mem::take(&mut es[0]);
+                    spine.push(Frame::Compose(std::mem::take(es)));
+                    e1 = first;
                 }
-                Intrinsic::Clone => {
-                    if vs.0.len() < 1 {
-                        Err(EvalError::TooFewValues {
-                            available: vs.0.len(),
-                            expected: 1,
-                        })
-                    } else {
-                        vs.0.push(vs.0.last().unwrap().clone());
-                        *e = Expr::default();
-                        Ok(())
-                    }
+                Expr::With(sym, inner) => {
+                    let (sym, inner) = (*sym, std::mem::take(&mut **inner));
+                    spine.push(Frame::With(sym));
+                    current = Some(sym);
+                    e1 = inner;
                 }
-                Intrinsic::Drop => {
-                    if vs.0.len() < 1 {
-                        Err(EvalError::TooFewValues {
-                            available: vs.0.len(),
-                            expected: 1,
-                        })
+                _ => break,
+            }
+        }
+        let result = match e1 {
+            Expr::Push(sym) => self
+                .step_push(vs, current, sym)
+                .map(|()| e1 = Expr::default()),
+            Expr::Pop(sym) => self
+                .step_pop(vs, current, sym)
+                .map(|()| e1 = Expr::default()),
+            _ => self.focus(vs, current, |ctx, vs| ctx.small_step_first(vs, &mut e1)),
+        };
+        for frame in spine.into_iter().rev() {
+            e1 = match (frame, &mut e1) {
+                (Frame::Compose(mut es), Expr::Compose(e1s)) if result.is_ok() => {
+                    let mut new_es = std::mem::take(e1s);
+                    new_es.extend(es.drain(1..));
+                    if new_es.len() == 1 {
+                        new_es.pop().unwrap()
                     } else {
-                        vs.0.pop();
-                        *e = Expr::default();
-                        Ok(())
+                        Expr::Compose(new_es)
                     }
                 }
-                Intrinsic::Quote => {
-                    if vs.0.len() < 1 {
-                        Err(EvalError::TooFewValues {
-                            available: vs.0.len(),
-                            expected: 1,
-                        })
-                    } else {
-                        let v = vs.0.pop().unwrap();
-                        let qe = match v {
-                            Value::Call(sym) => Expr::Call(sym),
-                            Value::Quote(e) => Expr::Quote(e),
-                        };
-                        vs.0.push(Value::Quote(Box::new(qe)));
-                        *e = Expr::default();
-                        Ok(())
-                    }
+                (Frame::Compose(mut es), _) => {
+                    es[0] = e1;
+                    Expr::Compose(es)
                 }
-                Intrinsic::Compose => {
-                    if vs.0.len() < 2 {
-                        Err(EvalError::TooFewValues {
-                            available: vs.0.len(),
-                            expected: 2,
-                        })
-                    } else {
-                        let e2 = self.unquote_value(vs.0.pop().unwrap())?;
-                        let e1 = self.unquote_value(vs.0.pop().unwrap())?;
-                        let mut new_es = match (e1, e2) {
-                            (Expr::Compose(mut e1s), Expr::Compose(mut e2s)) => {
-                                e1s.extend(e2s.drain(..));
-                                e1s
-                            }
-                            (Expr::Compose(mut e1s), e2) => {
-                                e1s.push(e2);
-                                e1s
-                            }
-                            (e1, Expr::Compose(mut e2s)) => {
-                                e2s.insert(0, e1);
-                                e2s
-                            }
-                            (e1, e2) => vec![e1, e2],
-                        };
-                        let new_e = if new_es.len() == 1 {
-                            new_es.drain(..).next().unwrap()
-                        } else {
-                            Expr::Compose(new_es)
-                        };
-                        vs.0.push(Value::Quote(Box::new(new_e)));
-                        *e = Expr::default();
-                        Ok(())
-                    }
+                // A stack context ends once its expression has been evaluated.
+                (Frame::With(_), Expr::Compose(e1s)) if e1s.is_empty() => Expr::default(),
+                (Frame::With(sym), _) => Expr::With(sym, Box::new(e1)),
+            };
+        }
+        *e = e1;
+        result
+    }
+
+    /// Take a small step as with `small_step`, notifying `observer` of it.
+    pub fn small_step_observed(
+        &mut self,
+        vs: &mut ValueStack,
+        e: &mut Expr,
+        observer: &mut dyn EvalObserver,
+    ) -> Result<(), EvalError> {
+        let step = self.next_step(e);
+        match self.small_step(vs, e) {
+            Ok(()) => {
+                if let Some(step) = step {
+                    observer.step(self, step, vs, e);
                 }
-                Intrinsic::Apply => {
-                    if vs.0.len() < 1 {
-                        Err(EvalError::TooFewValues {
-                            available: vs.0.len(),
-                            expected: 1,
-                        })
-                    } else {
-                        let e1 = self.unquote_value(vs.0.pop().unwrap())?;
-                        *e = e1;
-                        Ok(())
-                    }
+                Ok(())
+            }
+            Err(err) => {
+                observer.error(self, &err, vs, e);
+                Err(err)
+            }
+        }
+    }
+
+    /// What the next small step of `e` will do, if anything.
+    fn next_step(&self, mut e: &Expr) -> Option<Step> {
+        loop {
+            match e {
+                Expr::Compose(es) => e = es.first()?,
+                Expr::With(_, inner) => e = inner,
+                Expr::Push(sym) => return Some(Step::Push(*sym)),
+                Expr::Pop(sym) => return Some(Step::Pop(*sym)),
+                Expr::Intrinsic(intr) => return Some(Step::Intrinsic(*intr)),
+                Expr::Call(sym) if self.natives.contains_key(sym) => {
+                    return Some(Step::Native(*sym))
                 }
+                Expr::Call(sym) | Expr::Pinned(sym, ..) => return Some(Step::Call(*sym)),
+                Expr::Quote(_) => return Some(Step::Quote),
+            }
+        }
+    }
+
+    /// Take a small step as with `small_step_observed`, keeping track in
+    /// `calls` of the functions whose definitions are being unfolded. `e` must
+    /// be canonical.
+    ///
+    /// A call that ends the definitions it is in replaces them on the call
+    /// stack, so that tail calls don't grow it.
+    pub fn small_step_with_calls(
+        &mut self,
+        vs: &mut ValueStack,
+        e: &mut Expr,
+        calls: &mut CallStack,
+        observer: &mut dyn EvalObserver,
+    ) -> Result<(), EvalError> {
+        let len = e.elements_len();
+        let first = match &*e {
+            Expr::Compose(es) => es.first(),
+            e => Some(e),
+        };
+        let unfolded = match first {
+            Some(Expr::Call(sym)) => match self.fns.get(sym) {
+                None | Some(Expr::Quote(_)) => None,
+                Some(_) => Some(*sym),
             },
+            Some(Expr::Pinned(sym, _, pe)) if !matches!(**pe, Expr::Quote(_)) => Some(*sym),
+            _ => None,
+        };
+        self.small_step_observed(vs, e, observer)?;
+        let rest = len.saturating_sub(1);
+        if let Some(sym) = unfolded {
+            while calls.0.last().is_some_and(|&(_, r)| r >= rest) {
+                calls.0.pop();
+            }
+            calls.0.push((sym, rest));
+        }
+        let new_len = e.elements_len();
+        while calls.0.last().is_some_and(|&(_, r)| r >= new_len) {
+            calls.0.pop();
+        }
+        Ok(())
+    }
+
+    /// Call the native function `sym`, replacing the values it takes with the
+    /// values it returns. If it fails, `vs` is left unchanged.
+    pub(crate) fn call_native(&self, vs: &mut ValueStack, sym: Symbol) -> Result<(), EvalError> {
+        let native = &self.natives[&sym];
+        if vs.0.len() < native.arity {
+            return Err(EvalError::NativeTooFewValues {
+                native: sym,
+                available: vs.0.len(),
+                expected: native.arity,
+            });
+        }
+        let start = vs.0.len() - native.arity;
+        match (native.f)(self, &vs.0[start..]) {
+            Ok(results) => {
+                vs.0.truncate(start);
+                vs.0.extend(results);
+                Ok(())
+            }
+            Err(message) => Err(EvalError::NativeFailed(sym, message)),
+        }
+    }
+
+    /// Step an expression that is not a non-empty composition, a stack
+    /// context, or a push or pop.
+    fn small_step_first(&mut self, vs: &mut ValueStack, e: &mut Expr) -> Result<(), EvalError> {
+        match e {
+            Expr::Intrinsic(intr) => {
+                let intr = *intr;
+                *e = self.step_intrinsic(vs, intr)?.unwrap_or_default();
+                Ok(())
+            }
+            Expr::Call(sym) if self.natives.contains_key(sym) => {
+                self.call_native(vs, *sym)?;
+                *e = Expr::default();
+                Ok(())
+            }
             Expr::Call(sym) => {
                 if let Some(new_e) = self.fns.get(sym) {
                     match new_e {
                         Expr::Quote(_) => {
-                            vs.0.push(Value::Call(*sym));
+                            vs.0.push(Value::Call(*sym, self.version(*sym)));
                             *e = Expr::default();
                             Ok(())
                         }
                         _ => {
+                            // TODO: calls copy the top-level nodes of the body
+                            // too, see `unquote_value`.
                             *e = new_e.clone();
                             Ok(())
                         }
@@ -223,68 +1176,548 @@ impl Context {
                     Err(EvalError::UndefinedFn(*sym))
                 }
             }
+            Expr::Pinned(sym, version, pe) => {
+                match &**pe {
+                    Expr::Quote(qe) => {
+                        vs.0.push(self.pinned_value(*sym, *version, qe));
+                        *e = Expr::default();
+                    }
+                    pe => *e = pe.clone(),
+                }
+                Ok(())
+            }
             Expr::Quote(qe) => {
                 vs.0.push(Value::Quote(qe.clone()));
                 *e = Expr::default();
                 Ok(())
             }
-            Expr::Compose(ref mut es) => {
-                let es_len = es.len();
-                if es_len == 0 {
-                    Ok(())
-                } else {
-                    let e1 = es.first_mut().unwrap();
-                    self.small_step(vs, e1)?;
-                    match e1 {
-                        Expr::Compose(e1s) => {
-                            let mut new_es = Vec::with_capacity(e1s.len() + es_len - 1);
-                            new_es.extend(e1s.drain(..));
-                            new_es.extend(es.drain(1..));
-                            let new_e = if new_es.len() == 1 {
-                                new_es.drain(..).next().unwrap()
-                            } else {
-                                Expr::Compose(new_es)
-                            };
-                            *e = new_e;
-                        }
-                        _ => {}
-                    }
-                    Ok(())
-                }
+            Expr::Compose(_) => Ok(()),
+            Expr::With(..) | Expr::Push(_) | Expr::Pop(_) => unreachable!(),
+        }
+    }
+
+    /// Evaluate `e` until it is empty or until `fuel` small steps have been taken.
+    /// In either case, `vs` is left holding the resulting values.
+    pub fn eval(&mut self, vs: &mut ValueStack, e: Expr, fuel: Fuel) -> Result<Outcome, EvalError> {
+        self.eval_observed(vs, e, fuel, &mut ())
+    }
+
+    /// Evaluate `e` as with `eval`, notifying `observer` of each small step.
+    pub fn eval_observed(
+        &mut self,
+        vs: &mut ValueStack,
+        mut e: Expr,
+        fuel: Fuel,
+        observer: &mut dyn EvalObserver,
+    ) -> Result<Outcome, EvalError> {
+        for _ in 0..fuel.0 {
+            if e == Expr::default() {
+                return Ok(Outcome::Normal);
             }
+            self.small_step_observed(vs, &mut e, observer)?;
+        }
+        if e == Expr::default() {
+            Ok(Outcome::Normal)
+        } else {
+            Ok(Outcome::OutOfFuel(e))
         }
     }
 
+    /// Replace each quotation in `vs` that matches the definition of a function
+    /// with a call to that function. Returns whether anything was replaced.
+    /// Pinned calls to the current versions of functions match plain calls to
+    /// them.
+    ///
+    /// The remaining quotations are replaced with their canonical nodes from
+    /// the arena, so that compressing them again is a single lookup.
     pub fn compress(&mut self, vs: &mut ValueStack) -> bool {
         let mut compressed = false;
-        for v in vs.0.iter_mut() {
+        for v in vs.0.iter_mut().chain(vs.1.values_mut().flatten()) {
             match v {
-                Value::Call(_) => {}
+                Value::Call(..) | Value::Marked(..) | Value::Host(_) => {}
                 Value::Quote(e) => {
-                    // TODO: we shouldn't have to clone this expr in order to hash it
-                    if let Some(sym) = self.exprs.get(&Expr::Quote((*e).clone())) {
-                        *v = Value::Call(*sym);
+                    let (new_e, id) = self.arena.intern(e);
+                    let sym = match self.quote_fn(id) {
+                        Some(sym) => Some(sym),
+                        None => self
+                            .unpinned(&new_e)
+                            .and_then(|key| self.quote_fn(self.arena.id(&key)?)),
+                    };
+                    if let Some(sym) = sym {
+                        *v = Value::Call(sym, self.version(sym));
                         compressed = true;
+                    } else {
+                        *e = new_e;
                     }
                 }
             }
         }
         compressed
     }
+
+    /// The canonical node for `e` with plain calls in place of its pinned
+    /// calls to current versions, or `None` if it has none. `e` must be
+    /// canonical.
+    fn unpinned(&mut self, e: &Rc<Expr>) -> Option<Rc<Expr>> {
+        enum Task<'a> {
+            Unpin(&'a Expr),
+            Quote(&'a Rc<Expr>),
+            Compose(&'a [Expr]),
+            With(Symbol),
+        }
+        if self.unpinned.len() > MAX_UNPINNED {
+            self.unpinned.clear();
+        }
+        let mut tasks = vec![Task::Unpin(e)];
+        let mut results: Vec<Option<Expr>> = vec![];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Unpin(Expr::Pinned(sym, version, _)) if !self.is_stale(*sym, *version) => {
+                    results.push(Some(Expr::Call(*sym)))
+                }
+                Task::Unpin(Expr::Compose(es)) => {
+                    tasks.push(Task::Compose(es));
+                    tasks.extend(es.iter().rev().map(Task::Unpin));
+                }
+                Task::Unpin(Expr::With(sym, e)) => {
+                    tasks.push(Task::With(*sym));
+                    tasks.push(Task::Unpin(e));
+                }
+                Task::Unpin(Expr::Quote(qe)) => {
+                    match self.arena.id(qe).and_then(|id| self.unpinned.get(&id)) {
+                        Some(key) => results.push(key.clone().map(Expr::Quote)),
+                        None => {
+                            tasks.push(Task::Quote(qe));
+                            tasks.push(Task::Unpin(qe));
+                        }
+                    }
+                }
+                Task::Unpin(_) => results.push(None),
+                Task::Quote(qe) => {
+                    let key = results
+                        .pop()
+                        .unwrap()
+                        .map(|key| self.arena.intern(&Rc::new(key)).0);
+                    if let Some(id) = self.arena.id(qe) {
+                        self.unpinned.insert(id, key.clone());
+                    }
+                    results.push(key.map(Expr::Quote));
+                }
+                Task::Compose(es) => {
+                    let keys = results.split_off(results.len() - es.len());
+                    if keys.iter().all(Option::is_none) {
+                        results.push(None);
+                    } else {
+                        results.push(Some(Expr::Compose(
+                            keys.into_iter()
+                                .zip(es.iter())
+                                .map(|(key, e)| key.unwrap_or_else(|| e.clone()))
+                                .collect(),
+                        )));
+                    }
+                }
+                Task::With(sym) => {
+                    let key = results.pop().unwrap();
+                    results.push(key.map(|e| Expr::With(sym, Box::new(e))));
+                }
+            }
+        }
+        results
+            .pop()
+            .unwrap()
+            .map(|key| self.arena.intern(&Rc::new(key)).0)
+    }
+
+    /// Like `compress`, but also compress the quotations nested in `vs`,
+    /// bottom-up, and replace each run of expressions in a composition that
+    /// matches the definition of a function with a call to that function.
+    /// The calls are pinned, so that the values keep their meaning if the
+    /// functions are redefined. Returns whether anything was replaced.
+    pub fn compress_deep(&mut self, vs: &mut ValueStack) -> bool {
+        let mut compressed = false;
+        for v in vs.0.iter_mut().chain(vs.1.values_mut().flatten()) {
+            if let Value::Quote(e) = v {
+                match &self.compress_quote(&e.clone(), &mut compressed).0 {
+                    Expr::Pinned(sym, version, _) => *v = Value::Call(*sym, *version),
+                    Expr::Quote(new_e) => *e = new_e.clone(),
+                    _ => unreachable!(),
+                }
+            }
+        }
+        compressed
+    }
+
+    /// Compress the quotation of `e`. Returns either a pinned call to the
+    /// function that it matches, or the quotation of the canonical node for
+    /// `e` with its nested quotations and runs compressed, along with the key
+    /// of the result.
+    ///
+    /// The key of a compressed expression has plain calls in place of the
+    /// pinned calls to current versions, so that it can be matched with the
+    /// definitions that call those functions. Its quotations are canonical.
+    fn compress_quote(&mut self, e: &Rc<Expr>, compressed: &mut bool) -> (Expr, Expr) {
+        enum Task<'a> {
+            Compress(&'a Expr),
+            Quote(&'a Rc<Expr>),
+            Compose(usize),
+        }
+        // Intern `e` first, so that the nested quotations are canonical, and
+        // interning them again is a single lookup.
+        let e = &self.arena.intern(e).0;
+        // Quoted expressions may be shared, so compress each only once.
+        let mut quotes: Map<*const Expr, (Expr, Expr)> = Map::default();
+        let mut tasks = vec![Task::Quote(e), Task::Compress(e)];
+        let mut results = vec![];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Compress(Expr::Quote(qe)) => match quotes.get(&Rc::as_ptr(qe)) {
+                    Some(result) => results.push(result.clone()),
+                    None => {
+                        tasks.push(Task::Quote(qe));
+                        tasks.push(Task::Compress(qe));
+                    }
+                },
+                Task::Compress(Expr::Compose(es)) => {
+                    tasks.push(Task::Compose(es.len()));
+                    tasks.extend(es.iter().rev().map(Task::Compress));
+                }
+                Task::Compress(e @ Expr::Pinned(sym, version, _))
+                    if !self.is_stale(*sym, *version) =>
+                {
+                    results.push((e.clone(), Expr::Call(*sym)))
+                }
+                Task::Compress(e) => results.push((e.clone(), e.clone())),
+                Task::Quote(qe) => {
+                    let (new_qe, key_qe) = results.pop().unwrap();
+                    let id = self.arena.intern(qe).1;
+                    let (new_qe, new_id) = self.arena.intern(&Rc::new(new_qe));
+                    let (key_qe, key_id) = self.arena.intern(&Rc::new(key_qe));
+                    let result = match (self.quote_fn(id), self.quote_fn(key_id)) {
+                        (Some(sym), _) => {
+                            *compressed = true;
+                            let pe = Rc::new(Expr::Quote(qe.clone()));
+                            (Expr::Pinned(sym, self.version(sym), pe), Expr::Call(sym))
+                        }
+                        (None, Some(sym)) => {
+                            *compressed = true;
+                            let pe = Rc::new(Expr::Quote(new_qe));
+                            (Expr::Pinned(sym, self.version(sym), pe), Expr::Call(sym))
+                        }
+                        (None, None) => {
+                            if new_id != id {
+                                *compressed = true;
+                            }
+                            (Expr::Quote(new_qe), Expr::Quote(key_qe))
+                        }
+                    };
+                    quotes.insert(Rc::as_ptr(qe), result.clone());
+                    results.push(result);
+                }
+                Task::Compose(len) => {
+                    let (es, keys) = results.split_off(results.len() - len).into_iter().unzip();
+                    let (es, keys) = self.compress_runs(es, keys, compressed);
+                    results.push((Expr::compose(es), Expr::compose(keys)));
+                }
+            }
+        }
+        results.pop().unwrap()
+    }
+
+    /// Replace each run of `es` whose keys match the definition of a function
+    /// with a pinned call to that function, preferring the leftmost and then
+    /// the longest, until no run matches. Returns the expressions and their
+    /// keys. The quotations in `keys` must be canonical.
+    fn compress_runs(
+        &self,
+        mut es: Vec<Expr>,
+        mut keys: Vec<Expr>,
+        compressed: &mut bool,
+    ) -> (Vec<Expr>, Vec<Expr>) {
+        let mut lens: Vec<usize> = self.runs.values().map(|(_, body)| body_len(body)).collect();
+        lens.sort_unstable_by(|len1, len2| len2.cmp(len1));
+        lens.dedup();
+        loop {
+            let mut new_es = vec![];
+            let mut new_keys = vec![];
+            let mut i = 0;
+            while i < es.len() {
+                let run = lens
+                    .iter()
+                    .filter(|&&len| i + len <= es.len())
+                    .find_map(|&len| {
+                        let id = self.arena.find_compose(&keys[i..i + len])?;
+                        self.run_fn(id).map(|sym| (len, sym))
+                    });
+                match run {
+                    Some((len, sym)) => {
+                        let pe = Rc::new(Expr::Compose(es[i..i + len].to_vec()));
+                        new_es.push(Expr::Pinned(sym, self.version(sym), pe));
+                        new_keys.push(Expr::Call(sym));
+                        i += len;
+                    }
+                    None => {
+                        new_es.push(es[i].clone());
+                        new_keys.push(keys[i].clone());
+                        i += 1;
+                    }
+                }
+            }
+            if new_es.len() == es.len() {
+                return (es, keys);
+            }
+            *compressed = true;
+            es = new_es;
+            keys = new_keys;
+        }
+    }
+}
+
+fn body_len(e: &Expr) -> usize {
+    match e {
+        Expr::Compose(es) => es.len(),
+        _ => 1,
+    }
 }
 
 //////////////////////////
 // Function Definitions //
 //////////////////////////
 
+/// A function definition, with its signature if it is annotated with one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FnDef(pub Symbol, pub Expr, pub Option<Signature>);
+
+/// A stack-effect annotation, e.g. `a b -> b a`. The rest of the stack
+/// below the values that the input and output give the types of can be
+/// named by a row variable, e.g. `..r a (..r a -> ..r b) -> ..r b`. The input
+/// and output of a signature that don't name it share a row of their own.
+/// Variables with the same name are the same variable throughout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub input_row: Option<Symbol>,
+    pub input: Vec<TypeAnnotation>,
+    pub output_row: Option<Symbol>,
+    pub output: Vec<TypeAnnotation>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct FnDef(pub Symbol, pub Expr);
+pub enum TypeAnnotation {
+    Var(Symbol),
+    /// The type of a quotation, with the stack effect of the signature
+    Fn(Signature),
+}
 
 impl Context {
-    pub fn define_fn(&mut self, fn_def: FnDef) -> Option<FnDef> {
-        let result = self.fns.remove(&fn_def.0).map(|e| FnDef(fn_def.0, e));
-        self.fns.insert(fn_def.0, fn_def.1.clone());
-        self.exprs.insert(fn_def.1, fn_def.0);
-        result
+    /// The symbol for `name`, which is created if there isn't one yet.
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        Symbol(self.interner.get_or_intern(name))
+    }
+
+    /// The symbol for `name`, if there is one.
+    pub fn symbol(&self, name: &str) -> Option<Symbol> {
+        self.interner.get(name).map(Symbol)
+    }
+
+    /// The name of `sym`.
+    pub fn name(&self, sym: Symbol) -> &str {
+        self.interner.resolve(&sym.0)
+    }
+
+    /// The definition of the function `sym`, if it is defined.
+    pub fn fn_def(&self, sym: Symbol) -> Option<&Expr> {
+        self.fns.get(&sym)
+    }
+
+    /// Whether linearity is checked, so that cloning an affine or linear
+    /// quotation, or dropping a linear one, fails.
+    pub fn linearity(&self) -> bool {
+        self.linearity
+    }
+
+    /// Set whether linearity is checked.
+    pub fn set_linearity(&mut self, linearity: bool) {
+        self.linearity = linearity;
+    }
+
+    /// The signature that the function `sym` is annotated with, if any.
+    pub fn signature(&self, sym: Symbol) -> Option<&Signature> {
+        self.signatures.get(&sym)
+    }
+
+    /// The defined functions with their definitions, each after the
+    /// functions that it calls unless they call each other recursively, and
+    /// otherwise in order of name.
+    pub fn fn_defs(&self) -> impl Iterator<Item = (Symbol, &Expr)> + '_ {
+        self.fns_in_dependency_order()
+            .into_iter()
+            .map(move |sym| (sym, &self.fns[&sym]))
+    }
+
+    pub fn parse_expr(&mut self, source: &str) -> Result<Expr, Diagnostic> {
+        ExprParser::new().parse(self, source).map_err(|err| {
+            let mut scratch = Context::default();
+            Diagnostic::from_parse_error(&err, source, 0, |source| {
+                ExprParser::new()
+                    .parse(&mut scratch, source)
+                    .err()
+                    .map(|err| error_location(&err))
+            })
+        })
+    }
+
+    pub fn parse_fn_def(&mut self, source: &str) -> Result<FnDef, Diagnostic> {
+        FnDefParser::new().parse(self, source).map_err(|err| {
+            let mut scratch = Context::default();
+            Diagnostic::from_parse_error(&err, source, 0, |source| {
+                FnDefParser::new()
+                    .parse(&mut scratch, source)
+                    .err()
+                    .map(|err| error_location(&err))
+            })
+        })
+    }
+
+    /// Parse a value stack, e.g. `⟨true [swap]⟩`.
+    pub fn parse_value_stack(&mut self, source: &str) -> Result<ValueStack, Diagnostic> {
+        ValueStackParser::new().parse(self, source).map_err(|err| {
+            let mut scratch = Context::default();
+            Diagnostic::from_parse_error(&err, source, 0, |source| {
+                ValueStackParser::new()
+                    .parse(&mut scratch, source)
+                    .err()
+                    .map(|err| error_location(&err))
+            })
+        })
+    }
+
+    /// A call to the current version of `sym`, as `parse_value_stack` makes
+    /// for a name in a value stack.
+    pub fn call_value(&self, sym: Symbol) -> Value {
+        Value::Call(sym, self.version(sym))
+    }
+
+    /// Whether `sym` is defined as a native function.
+    pub fn is_native(&self, sym: Symbol) -> bool {
+        self.natives.contains_key(&sym)
+    }
+
+    /// Define `name` as a native function that takes `arity` values from the
+    /// top of the stack and is implemented by `f`, replacing any definition
+    /// of `name`. See `NativeFn`.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, f: F) -> Symbol
+    where
+        F: Fn(&Context, &[Value]) -> Result<Vec<Value>, String> + 'static,
+    {
+        let sym = self.intern(name);
+        self.remove_fn(sym);
+        let f = Rc::new(f);
+        self.natives.insert(sym, Native { arity, f });
+        sym
+    }
+
+    /// The function defined as the quotation of the expression `id`, or the
+    /// latest defined if there are several.
+    fn quote_fn(&self, id: ExprId) -> Option<Symbol> {
+        self.quotes.get(&id)?.last().copied()
+    }
+
+    /// The function defined as the composition `id`, or the latest defined if
+    /// there are several.
+    fn run_fn(&self, id: ExprId) -> Option<Symbol> {
+        self.runs.get(&id)?.0.last().copied()
+    }
+
+    /// Remove the definition of `sym`, if any, returning it if it was
+    /// defined by an expression.
+    fn remove_fn(&mut self, sym: Symbol) -> Option<FnDef> {
+        self.natives.remove(&sym);
+        self.code.remove(&sym);
+        self.unpinned.clear();
+        self.types.get_mut().invalidate(sym);
+        let signature = self.signatures.remove(&sym);
+        let e = self.fns.remove(&sym)?;
+        self.quotes.retain(|_, syms| {
+            syms.retain(|&quote_sym| quote_sym != sym);
+            !syms.is_empty()
+        });
+        self.runs.retain(|_, (syms, _)| {
+            syms.retain(|&run_sym| run_sym != sym);
+            !syms.is_empty()
+        });
+        Some(FnDef(sym, e, signature))
+    }
+
+    /// Define a function, returning its previous definition, if any. If the
+    /// definition is annotated with a signature that its type doesn't match,
+    /// it is rejected and the previous definition is kept. Only the
+    /// definition is checked, given the cached types of the functions it
+    /// calls, and the types of the functions that call it are inferred again
+    /// when they are next needed.
+    pub fn define_fn(&mut self, fn_def: FnDef) -> Result<Option<FnDef>, TypeError> {
+        if fn_def.2.is_some() {
+            TypeChecker::new(self).check_fn_def(&fn_def)?;
+        }
+        let FnDef(sym, e, signature) = fn_def;
+        let mut e = e.normalize();
+        let result = self.remove_fn(sym);
+        if let Some(signature) = signature {
+            self.signatures.insert(sym, signature);
+        }
+        let version = Version(self.versions.get(&sym).map_or(0, |version| version.0) + 1);
+        self.versions.insert(sym, version);
+        match &mut e {
+            Expr::Quote(qe) => {
+                let (new_qe, id) = self.arena.intern(qe);
+                *qe = new_qe;
+                self.quotes.entry(id).or_default().push(sym);
+                self.quoted.insert((sym, version), qe.clone());
+            }
+            Expr::Compose(es) if es.len() > 1 => {
+                let (body, id) = self.arena.intern(&Rc::new(e.clone()));
+                self.runs.entry(id).or_insert((vec![], body)).0.push(sym);
+            }
+            _ => {}
+        }
+        self.code.insert(sym, compile_fn(&e));
+        self.fns.insert(sym, e);
+        Ok(result)
+    }
+
+    /// Remove all function definitions. Values that refer to them keep
+    /// referring to their versions.
+    pub fn clear_fns(&mut self) {
+        self.fns.clear();
+        self.unpinned.clear();
+        self.signatures.clear();
+        *self.types.get_mut() = TypeCache::default();
+        self.quotes.clear();
+        self.runs.clear();
+        self.code.clear();
+    }
+
+    /// The defined functions, each after the functions that it calls unless
+    /// they call each other recursively, and otherwise in order of name.
+    pub(crate) fn fns_in_dependency_order(&self) -> Vec<Symbol> {
+        let mut syms: Vec<Symbol> = self.fns.keys().copied().collect();
+        syms.sort_unstable_by_key(|sym| self.interner.resolve(&sym.0));
+        let mut visited = FxHashSet::default();
+        let mut order = vec![];
+        for sym in syms {
+            // Add each definition once the functions it calls are added.
+            let mut stack = vec![(sym, false)];
+            while let Some((sym, is_visited)) = stack.pop() {
+                if is_visited {
+                    order.push(sym);
+                } else if visited.insert(sym) {
+                    stack.push((sym, true));
+                    let mut callees = self.fns[&sym].calls();
+                    callees.retain(|callee| self.fns.contains_key(callee));
+                    callees.sort_unstable_by_key(|callee| self.interner.resolve(&callee.0));
+                    callees.dedup();
+                    stack.extend(callees.into_iter().rev().map(|callee| (callee, false)));
+                }
+            }
+        }
+        order
     }
 }
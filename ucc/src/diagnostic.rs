@@ -0,0 +1,292 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use lalrpop_util::ParseError;
+use std::fmt;
+
+/// A range of byte offsets into the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A message about a span of the source, with notes that give its context
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+    pub notes: Vec<String>,
+}
+
+/// A syntax error, with its token as text, as the parsers that recover from
+/// syntax errors hold it until the source is at hand
+pub(crate) type SyntaxError = ParseError<usize, String, &'static str>;
+
+impl Diagnostic {
+    /// Render the message, followed by the line of `source` that contains the
+    /// start of the span, with carets under the span, and then the notes.
+    pub fn render(&self, source: &str) -> String {
+        let start = self.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find(['\n', '\r'])
+            .map_or(source.len(), |i| start + i);
+        let line = &source[line_start..line_end];
+        let line_number = source[..line_start].matches('\n').count() + 1;
+        let column = source[line_start..start].chars().count();
+        let end = self.span.end.max(start).min(line_end);
+        let width = source[start..end].chars().count().max(1);
+        let gutter = line_number.to_string();
+        let padding = " ".repeat(gutter.len());
+        let mut rendered = format!(
+            "{}\n{} | {}\n{} | {}{}\n",
+            self.message,
+            gutter,
+            line,
+            padding,
+            " ".repeat(column),
+            "^".repeat(width)
+        );
+        for note in &self.notes {
+            rendered.push_str(&format!("{} = {}\n", padding, note));
+        }
+        rendered
+    }
+
+    /// The diagnostic for a syntax error in `source`, in the item that starts
+    /// at `start`, where `error_location` parses the start of an item without
+    /// recovering from errors and returns where parsing failed, if it did. It
+    /// should parse with a scratch `Context`, so that the names it interns
+    /// aren't kept.
+    ///
+    /// The terminals that the parser reports it expected are those of the
+    /// state it was in after reducing what it could, which can be both too
+    /// few and too many, so instead the diagnostic lists each terminal that,
+    /// put at the location of the error, the parser gets past. Only the item
+    /// up to there is parsed, since that decides whether it does. If parsing
+    /// that fails sooner, because recovering from an error hid another, it
+    /// lists none. A reserved word where a name could be is reported as such.
+    pub fn from_parse_error<T: fmt::Display, E: fmt::Display>(
+        err: &ParseError<usize, T, E>,
+        source: &str,
+        start: usize,
+        mut error_location: impl FnMut(&str) -> Option<usize>,
+    ) -> Self {
+        let mut expected = |location: usize| {
+            let before = &source[start..location];
+            if error_location(before).is_some_and(|location| location < before.trim_end().len()) {
+                return vec![];
+            }
+            TERMINALS
+                .iter()
+                .filter(|(_, sample)| {
+                    let source = format!("{} {}", before, sample);
+                    error_location(&source) != Some(before.len() + 1)
+                })
+                .map(|&(terminal, _)| terminal)
+                .collect()
+        };
+        let (span, message) = match err {
+            ParseError::InvalidToken { location } => (
+                Span {
+                    start: *location,
+                    end: *location,
+                },
+                "Invalid token.".to_owned(),
+            ),
+            ParseError::UnrecognizedEOF { location, .. } => (
+                Span {
+                    start: *location,
+                    end: *location,
+                },
+                format!(
+                    "Unexpected end of input{}.",
+                    describe_expected(&expected(*location))
+                ),
+            ),
+            ParseError::UnrecognizedToken {
+                token: (token_start, token, token_end),
+                ..
+            } => {
+                let token = token.to_string();
+                let expected = expected(*token_start);
+                let message = if is_reserved_word(&token) && expected.contains(&"IDENT") {
+                    format!(
+                        "`{}` is a reserved word{}.",
+                        token,
+                        describe_expected(&expected)
+                    )
+                } else {
+                    format!("Unexpected `{}`{}.", token, describe_expected(&expected))
+                };
+                (
+                    Span {
+                        start: *token_start,
+                        end: *token_end,
+                    },
+                    message,
+                )
+            }
+            ParseError::ExtraToken {
+                token: (start, token, end),
+            } => (
+                Span {
+                    start: *start,
+                    end: *end,
+                },
+                format!("Unexpected `{}`; expected the end of input.", token),
+            ),
+            ParseError::User { error } => (Span::default(), format!("{}.", error)),
+        };
+        Diagnostic {
+            span,
+            message: format!("Parse error: {}", message),
+            notes: vec![],
+        }
+    }
+}
+
+/// The location at which parsing failed.
+pub(crate) fn error_location<T, E>(err: &ParseError<usize, T, E>) -> usize {
+    match err {
+        ParseError::InvalidToken { location } | ParseError::UnrecognizedEOF { location, .. } => {
+            *location
+        }
+        ParseError::UnrecognizedToken { token, .. } | ParseError::ExtraToken { token } => token.0,
+        ParseError::User { .. } => 0,
+    }
+}
+
+/// Each terminal of the grammar, with a sample of its text
+pub(crate) static TERMINALS: [(&str, &str); 46] = [
+    ("AFFINE", "affine"),
+    ("APPLY", "apply"),
+    ("BRA", "⟨"),
+    ("CLONE", "clone"),
+    ("COLON", ":"),
+    ("COLON_ASSERT", ":assert"),
+    ("COLON_CLEAR", ":clear"),
+    ("COLON_COMPRESS", ":compress"),
+    ("COLON_DROP", ":drop"),
+    ("COLON_EFFECT", ":effect"),
+    ("COLON_HELP", ":help"),
+    ("COLON_LINEARITY", ":linearity"),
+    ("COLON_LIST", ":list"),
+    ("COLON_LOAD", ":load"),
+    ("COLON_RELOAD", ":reload"),
+    ("COLON_RESET", ":reset"),
+    ("COLON_ROLLBACK", ":rollback"),
+    ("COLON_SAVE", ":save"),
+    ("COLON_SHOW", ":show"),
+    ("COLON_TRACE", ":trace"),
+    ("COLON_TYPE", ":type"),
+    ("COLON_TYPECHECK", ":typecheck"),
+    ("COMPOSE", "compose"),
+    ("DDARROW", "⇓"),
+    ("DROP", "drop"),
+    ("EQ", "="),
+    ("FN", "fn"),
+    ("IDENT", "x"),
+    ("KET", "⟩"),
+    ("LBRACE", "{"),
+    ("LBRACE_STACK", "{$s"),
+    ("LBRACKET", "["),
+    ("LINEAR", "linear"),
+    ("LONG_RARROW", "⟶"),
+    ("LPAREN", "("),
+    ("PATH", "./x"),
+    ("POP_STACK", "{$s pop}"),
+    ("PUSH_STACK", "{$s push}"),
+    ("QUOTE", "quote"),
+    ("RARROW", "->"),
+    ("RBRACE", "}"),
+    ("RBRACKET", "]"),
+    ("ROW", "..r"),
+    ("RPAREN", ")"),
+    ("STACK", "$s"),
+    ("SWAP", "swap"),
+];
+
+/// The terminals that can start an expression
+pub(crate) static EXPR_TERMINALS: [&str; 14] = [
+    "SWAP",
+    "CLONE",
+    "DROP",
+    "QUOTE",
+    "COMPOSE",
+    "APPLY",
+    "LINEAR",
+    "AFFINE",
+    "IDENT",
+    "LBRACKET",
+    "LPAREN",
+    "LBRACE_STACK",
+    "PUSH_STACK",
+    "POP_STACK",
+];
+
+/// Whether `token` is a word that can't be a name, such as `linear`.
+fn is_reserved_word(token: &str) -> bool {
+    TERMINALS
+        .iter()
+        .any(|&(terminal, sample)| terminal != "IDENT" && sample == token)
+        && token.starts_with(|c: char| c.is_ascii_alphabetic())
+}
+
+/// Describe the terminals that the parser expected, in plain English.
+fn describe_expected(expected: &[&str]) -> String {
+    let mut descriptions = vec![];
+    let is_expr_expected = EXPR_TERMINALS
+        .iter()
+        .all(|terminal| expected.iter().any(|e| e == terminal));
+    if is_expr_expected {
+        descriptions.push("an expression".to_owned());
+    }
+    for terminal in expected {
+        if is_expr_expected && EXPR_TERMINALS.contains(terminal) {
+            continue;
+        }
+        descriptions.push(describe_terminal(terminal));
+    }
+    match descriptions.split_last() {
+        None => String::new(),
+        Some((last, [])) => format!("; expected {}", last),
+        Some((last, rest)) => format!("; expected {} or {}", rest.join(", "), last),
+    }
+}
+
+fn describe_terminal(terminal: &str) -> String {
+    let s = match terminal {
+        "IDENT" => return "a name".to_owned(),
+        "STACK" => return "a stack name".to_owned(),
+        "ROW" => return "a row variable".to_owned(),
+        "LBRACE_STACK" => return "a stack context".to_owned(),
+        "PUSH_STACK" => return "a push onto a stack".to_owned(),
+        "POP_STACK" => return "a pop from a stack".to_owned(),
+        "LPAREN" => "(",
+        "RPAREN" => ")",
+        "LBRACKET" => "[",
+        "RBRACKET" => "]",
+        "LBRACE" => "{",
+        "RBRACE" => "}",
+        "EQ" => "=",
+        "COLON" => ":",
+        "RARROW" => "->",
+        "BRA" => "⟨",
+        "KET" => "⟩",
+        "LONG_RARROW" => "⟶",
+        "DDARROW" => "⇓",
+        _ => {
+            let name = terminal.to_lowercase();
+            return match name.strip_prefix("colon_") {
+                Some(command) => format!("`:{}`", command),
+                None => format!("`{}`", name),
+            };
+        }
+    };
+    format!("`{}`", s)
+}
@@ -4,45 +4,161 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::core::{EvalError, Expr, Interner, Intrinsic, Symbol, Value, ValueStack};
+use crate::core::{
+    CallStack, Context, EvalError, Expr, Intrinsic, Map, Signature, Symbol, TypeAnnotation, Usage,
+    Value, ValueStack,
+};
+use crate::effect::{Effect, EffectError};
+use crate::types::{FnType, RowVar, StackType, Type, TypeError, TypeVar};
 use std::fmt;
 
-pub(crate) type ResolvedSymbol = String;
+pub type ResolvedSymbol = String;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub enum ResolvedExpr {
+    #[default]
     Empty,
     Intrinsic(Intrinsic),
     Call(ResolvedSymbol),
+    /// A pinned call to a version of a function that has since been
+    /// redefined or cleared, with the number of that version. Pinned calls to
+    /// current versions are resolved as calls.
+    StaleCall(ResolvedSymbol, usize),
     Quote(Box<ResolvedExpr>),
     Compose(Vec<ResolvedExpr>),
+    With(ResolvedSymbol, Box<ResolvedExpr>),
+    Push(ResolvedSymbol),
+    Pop(ResolvedSymbol),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ResolvedValue {
     Call(ResolvedSymbol),
+    /// A call to a version of a function that has since been redefined or
+    /// cleared, with the number of that version.
+    StaleCall(ResolvedSymbol, usize),
     Quote(Box<ResolvedExpr>),
+    Marked(Usage, Box<ResolvedExpr>),
+    /// A host value, as displayed by the host
+    Host(String),
 }
 
+/// The main stack, followed by the named stacks that aren't empty, sorted
+/// by name
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ResolvedValueStack(pub(crate) Vec<ResolvedValue>);
+pub struct ResolvedValueStack(
+    pub Vec<ResolvedValue>,
+    pub Vec<(ResolvedSymbol, Vec<ResolvedValue>)>,
+);
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ResolvedEvalError {
-    TooFewValues { available: usize, expected: usize },
+    TooFewValues {
+        intrinsic: Intrinsic,
+        available: usize,
+        expected: usize,
+    },
     UndefinedFn(String),
+    NativeTooFewValues {
+        native: String,
+        available: usize,
+        expected: usize,
+    },
+    NativeFailed(String, String),
+    NotAQuotation(String),
+    HostNotAQuotation(String),
+    Restricted {
+        intrinsic: Intrinsic,
+        usage: Usage,
+    },
+    PushEmpty(String),
+    PopEmpty(String),
+    InStack(String, Box<ResolvedEvalError>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolvedTypeError {
+    UndefinedFn(String),
+    /// The intrinsic or call, the variable, and the type that contains it
+    InfiniteType(String, String, String),
+    /// The intrinsic or call, the row, and the stack type that contains it
+    InfiniteStack(String, String, String),
+    InFn(String, Box<ResolvedTypeError>),
+    /// The function, its signature, and its type
+    SignatureMismatch(String, ResolvedSignature, String),
+    /// The stack context, push or pop
+    NamedStack(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedSignature {
+    pub input_row: Option<String>,
+    pub input: Vec<ResolvedTypeAnnotation>,
+    pub output_row: Option<String>,
+    pub output: Vec<ResolvedTypeAnnotation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolvedTypeAnnotation {
+    Var(String),
+    Fn(ResolvedSignature),
+}
+
+/// An effect, displayed as a rewrite rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedEffect {
+    pub inputs: ResolvedValueStack,
+    pub outputs: ResolvedValueStack,
+    pub residual: ResolvedExpr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolvedEffectError {
+    Eval(ResolvedEvalError),
+    OutOfFuel(ResolvedEffect),
+}
+
+/// An evaluation error, with the values on the stack and the functions being
+/// unfolded, innermost first, when it occurred
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalErrorReport {
+    pub error: ResolvedEvalError,
+    pub vs: ResolvedValueStack,
+    pub calls: Vec<ResolvedSymbol>,
+}
+
+impl EvalErrorReport {
+    pub fn new(ctx: &Context, err: &EvalError, vs: &ValueStack, calls: &CallStack) -> Self {
+        EvalErrorReport {
+            error: err.resolve(ctx),
+            vs: vs.resolve(ctx),
+            calls: calls.resolve(ctx),
+        }
+    }
+
+    /// Lines that give the context of the error.
+    pub fn notes(&self) -> Vec<String> {
+        let mut notes = vec![format!("stack: {}", self.vs)];
+        if let Some((innermost, rest)) = self.calls.split_first() {
+            let mut note = format!("in `{}`", innermost);
+            for sym in rest {
+                note.push_str(&format!(", called from `{}`", sym));
+            }
+            notes.push(note);
+        }
+        notes
+    }
 }
 
-pub(crate) trait Resolve {
+/// Resolve the symbols in a value to their names, for display or inspection.
+pub trait Resolve {
     type Output;
-    fn resolve(&self, interner: &Interner) -> Self::Output;
+    fn resolve(&self, ctx: &Context) -> Self::Output;
 }
 
 impl Resolve for () {
     type Output = ();
-    fn resolve(&self, _: &Interner) -> Self::Output {
-        ()
-    }
+    fn resolve(&self, _: &Context) -> Self::Output {}
 }
 
 impl<T, E> Resolve for Result<T, E>
@@ -51,75 +167,282 @@ where
     E: Resolve,
 {
     type Output = Result<<T as Resolve>::Output, <E as Resolve>::Output>;
-    fn resolve(&self, interner: &Interner) -> Self::Output {
+    fn resolve(&self, ctx: &Context) -> Self::Output {
         match self {
-            Ok(t) => Ok(t.resolve(interner)),
-            Err(e) => Err(e.resolve(interner)),
+            Ok(t) => Ok(t.resolve(ctx)),
+            Err(e) => Err(e.resolve(ctx)),
         }
     }
 }
 
 impl Resolve for Symbol {
     type Output = ResolvedSymbol;
-    fn resolve(&self, interner: &Interner) -> Self::Output {
-        interner.resolve(&self.0).to_owned()
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        ctx.interner.resolve(&self.0).to_owned()
     }
 }
 
 impl Resolve for Expr {
     type Output = ResolvedExpr;
-    fn resolve(&self, interner: &Interner) -> Self::Output {
-        match self {
-            Expr::Intrinsic(i) => ResolvedExpr::Intrinsic(*i),
-            Expr::Call(sym) => ResolvedExpr::Call(sym.resolve(interner)),
-            Expr::Quote(e) => ResolvedExpr::Quote(Box::new(e.resolve(interner))),
-            Expr::Compose(es) => {
-                ResolvedExpr::Compose(es.iter().map(|e| e.resolve(interner)).collect())
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        enum Task<'a> {
+            Resolve(&'a Expr),
+            Quote,
+            Compose(usize),
+            With(Symbol),
+        }
+        let mut tasks = vec![Task::Resolve(self)];
+        let mut resolved = vec![];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Resolve(Expr::Intrinsic(i)) => resolved.push(ResolvedExpr::Intrinsic(*i)),
+                Task::Resolve(Expr::Call(sym)) => {
+                    resolved.push(ResolvedExpr::Call(sym.resolve(ctx)))
+                }
+                Task::Resolve(Expr::Pinned(sym, version, _)) => {
+                    resolved.push(if ctx.is_stale(*sym, *version) {
+                        ResolvedExpr::StaleCall(sym.resolve(ctx), version.0 as usize)
+                    } else {
+                        ResolvedExpr::Call(sym.resolve(ctx))
+                    })
+                }
+                Task::Resolve(Expr::Quote(e)) => {
+                    tasks.push(Task::Quote);
+                    tasks.push(Task::Resolve(e));
+                }
+                Task::Resolve(Expr::Compose(es)) => {
+                    tasks.push(Task::Compose(es.len()));
+                    tasks.extend(es.iter().rev().map(Task::Resolve));
+                }
+                Task::Resolve(Expr::With(sym, e)) => {
+                    tasks.push(Task::With(*sym));
+                    tasks.push(Task::Resolve(e));
+                }
+                Task::Resolve(Expr::Push(sym)) => {
+                    resolved.push(ResolvedExpr::Push(sym.resolve(ctx)))
+                }
+                Task::Resolve(Expr::Pop(sym)) => resolved.push(ResolvedExpr::Pop(sym.resolve(ctx))),
+                Task::Quote => {
+                    let e = resolved.pop().unwrap();
+                    resolved.push(ResolvedExpr::Quote(Box::new(e)));
+                }
+                Task::Compose(len) => {
+                    let es = resolved.split_off(resolved.len() - len);
+                    resolved.push(ResolvedExpr::Compose(es));
+                }
+                Task::With(sym) => {
+                    let e = resolved.pop().unwrap();
+                    resolved.push(ResolvedExpr::With(sym.resolve(ctx), Box::new(e)));
+                }
             }
         }
+        resolved.pop().unwrap()
     }
 }
 
 impl Resolve for Value {
     type Output = ResolvedValue;
-    fn resolve(&self, interner: &Interner) -> Self::Output {
+    fn resolve(&self, ctx: &Context) -> Self::Output {
         match self {
-            Value::Call(sym) => ResolvedValue::Call(sym.resolve(interner)),
-            Value::Quote(e) => ResolvedValue::Quote(Box::new(e.resolve(interner))),
+            Value::Call(sym, version) => {
+                if ctx.is_stale(*sym, *version) {
+                    ResolvedValue::StaleCall(sym.resolve(ctx), version.0 as usize)
+                } else {
+                    ResolvedValue::Call(sym.resolve(ctx))
+                }
+            }
+            Value::Quote(e) => ResolvedValue::Quote(Box::new(e.resolve(ctx))),
+            Value::Marked(usage, e) => ResolvedValue::Marked(*usage, Box::new(e.resolve(ctx))),
+            Value::Host(data) => ResolvedValue::Host(data.to_string()),
         }
     }
 }
 
 impl Resolve for ValueStack {
     type Output = ResolvedValueStack;
-    fn resolve(&self, interner: &Interner) -> Self::Output {
-        ResolvedValueStack(self.0.iter().map(|v| v.resolve(interner)).collect())
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        let resolve = |values: &[Value]| values.iter().map(|v| v.resolve(ctx)).collect();
+        let mut named: Vec<_> = self
+            .named_stacks()
+            .map(|(name, values)| (name.resolve(ctx), resolve(values)))
+            .collect();
+        named.sort_by(|(name1, _), (name2, _)| name1.cmp(name2));
+        ResolvedValueStack(resolve(&self.0), named)
     }
 }
 
 impl Resolve for EvalError {
     type Output = ResolvedEvalError;
-    fn resolve(&self, interner: &Interner) -> Self::Output {
-        match self {
-            &EvalError::TooFewValues {
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        match *self {
+            EvalError::TooFewValues {
+                intrinsic,
                 available,
                 expected,
             } => ResolvedEvalError::TooFewValues {
+                intrinsic,
                 available,
                 expected,
             },
-            &EvalError::UndefinedFn(sym) => ResolvedEvalError::UndefinedFn(sym.resolve(interner)),
+            EvalError::UndefinedFn(sym) => ResolvedEvalError::UndefinedFn(sym.resolve(ctx)),
+            EvalError::NativeTooFewValues {
+                native,
+                available,
+                expected,
+            } => ResolvedEvalError::NativeTooFewValues {
+                native: native.resolve(ctx),
+                available,
+                expected,
+            },
+            EvalError::NativeFailed(sym, ref message) => {
+                ResolvedEvalError::NativeFailed(sym.resolve(ctx), message.clone())
+            }
+            EvalError::NotAQuotation(sym) => ResolvedEvalError::NotAQuotation(sym.resolve(ctx)),
+            EvalError::HostNotAQuotation(ref data) => {
+                ResolvedEvalError::HostNotAQuotation(data.to_string())
+            }
+            EvalError::Restricted { intrinsic, usage } => {
+                ResolvedEvalError::Restricted { intrinsic, usage }
+            }
+            EvalError::PushEmpty(sym) => ResolvedEvalError::PushEmpty(sym.resolve(ctx)),
+            EvalError::PopEmpty(sym) => ResolvedEvalError::PopEmpty(sym.resolve(ctx)),
+            EvalError::InStack(sym, ref err) => {
+                ResolvedEvalError::InStack(sym.resolve(ctx), Box::new(err.resolve(ctx)))
+            }
+        }
+    }
+}
+
+impl Resolve for Effect {
+    type Output = ResolvedEffect;
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        ResolvedEffect {
+            inputs: self.inputs.resolve(ctx),
+            outputs: self.outputs.resolve(ctx),
+            residual: match &self.residual {
+                Expr::Compose(es) if es.is_empty() => ResolvedExpr::Empty,
+                e => e.resolve(ctx),
+            },
+        }
+    }
+}
+
+impl Resolve for EffectError {
+    type Output = ResolvedEffectError;
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        match self {
+            EffectError::Eval(err) => ResolvedEffectError::Eval(err.resolve(ctx)),
+            EffectError::OutOfFuel(effect) => ResolvedEffectError::OutOfFuel(effect.resolve(ctx)),
+        }
+    }
+}
+
+impl Resolve for TypeError {
+    type Output = ResolvedTypeError;
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        match self {
+            TypeError::UndefinedFn(sym) => ResolvedTypeError::UndefinedFn(sym.resolve(ctx)),
+            TypeError::InfiniteType(at, v, t) => {
+                let (mut types, rows) = t.vars();
+                types.insert(0, *v);
+                let names = TypeNames::new(types, rows);
+                ResolvedTypeError::InfiniteType(
+                    at.resolve(ctx).to_string(),
+                    names.types[v].clone(),
+                    names.display(|f| names.write_type(f, t)),
+                )
+            }
+            TypeError::InfiniteStack(at, r, s) => {
+                let (types, mut rows) = s.vars();
+                rows.insert(0, *r);
+                let names = TypeNames::new(types, rows);
+                ResolvedTypeError::InfiniteStack(
+                    at.resolve(ctx).to_string(),
+                    names.rows[r].clone(),
+                    names.display(|f| names.write_stack(f, s)),
+                )
+            }
+            TypeError::InFn(sym, err) => {
+                ResolvedTypeError::InFn(sym.resolve(ctx), Box::new(err.resolve(ctx)))
+            }
+            TypeError::SignatureMismatch(sym, sig, fn_type) => {
+                ResolvedTypeError::SignatureMismatch(
+                    sym.resolve(ctx),
+                    sig.resolve(ctx),
+                    fn_type.to_string(),
+                )
+            }
+            TypeError::NamedStack(e) => ResolvedTypeError::NamedStack(e.resolve(ctx).to_string()),
+        }
+    }
+}
+
+impl Resolve for Signature {
+    type Output = ResolvedSignature;
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        let resolve = |ts: &[TypeAnnotation]| ts.iter().map(|t| t.resolve(ctx)).collect();
+        ResolvedSignature {
+            input_row: self.input_row.map(|sym| sym.resolve(ctx)),
+            input: resolve(&self.input),
+            output_row: self.output_row.map(|sym| sym.resolve(ctx)),
+            output: resolve(&self.output),
+        }
+    }
+}
+
+impl Resolve for TypeAnnotation {
+    type Output = ResolvedTypeAnnotation;
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        match self {
+            TypeAnnotation::Var(sym) => ResolvedTypeAnnotation::Var(sym.resolve(ctx)),
+            TypeAnnotation::Fn(sig) => ResolvedTypeAnnotation::Fn(sig.resolve(ctx)),
+        }
+    }
+}
+
+impl Resolve for CallStack {
+    type Output = Vec<ResolvedSymbol>;
+    fn resolve(&self, ctx: &Context) -> Self::Output {
+        self.symbols().map(|sym| sym.resolve(ctx)).collect()
+    }
+}
+
+impl Drop for ResolvedExpr {
+    fn drop(&mut self) {
+        let mut es = vec![];
+        self.take_children(&mut es);
+        while let Some(mut e) = es.pop() {
+            e.take_children(&mut es);
         }
     }
 }
 
 impl ResolvedExpr {
     fn is_compose(&self) -> bool {
+        matches!(self, ResolvedExpr::Compose(..))
+    }
+
+    fn is_empty(&self) -> bool {
         match self {
-            ResolvedExpr::Compose(..) => true,
+            ResolvedExpr::Empty => true,
+            ResolvedExpr::Compose(es) => es.is_empty(),
             _ => false,
         }
     }
+
+    /// Move the children of this expression into `es`.
+    fn take_children(&mut self, es: &mut Vec<ResolvedExpr>) {
+        match self {
+            ResolvedExpr::Empty
+            | ResolvedExpr::Intrinsic(_)
+            | ResolvedExpr::Call(_)
+            | ResolvedExpr::StaleCall(..)
+            | ResolvedExpr::Push(_)
+            | ResolvedExpr::Pop(_) => {}
+            ResolvedExpr::Quote(e) | ResolvedExpr::With(_, e) => es.push(std::mem::take(&mut **e)),
+            ResolvedExpr::Compose(children) => es.append(children),
+        }
+    }
 }
 
 impl fmt::Display for Intrinsic {
@@ -131,36 +454,73 @@ impl fmt::Display for Intrinsic {
             Intrinsic::Quote => "quote".fmt(f),
             Intrinsic::Compose => "compose".fmt(f),
             Intrinsic::Apply => "apply".fmt(f),
+            Intrinsic::Linear => "linear".fmt(f),
+            Intrinsic::Affine => "affine".fmt(f),
         }
     }
 }
 
-impl fmt::Display for ResolvedExpr {
+impl fmt::Display for Usage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ResolvedExpr::Empty => Ok(()),
-            ResolvedExpr::Intrinsic(i) => i.fmt(f),
-            ResolvedExpr::Call(sym) => sym.fmt(f),
-            ResolvedExpr::Quote(e) => write!(f, "[{}]", e),
-            ResolvedExpr::Compose(es) => {
-                if let Some(e) = es.first() {
-                    if e.is_compose() {
-                        write!(f, "({})", e)?;
-                    } else {
-                        write!(f, "{}", e)?;
+            Usage::Unrestricted => "unrestricted".fmt(f),
+            Usage::Affine => "affine".fmt(f),
+            Usage::Linear => "linear".fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for ResolvedExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        enum Task<'a> {
+            Write(&'a ResolvedExpr),
+            Str(&'static str),
+            Name(&'a str),
+        }
+        let mut tasks = vec![Task::Write(self)];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Str(s) => s.fmt(f)?,
+                Task::Name(s) => s.fmt(f)?,
+                Task::Write(ResolvedExpr::Empty) => {}
+                Task::Write(ResolvedExpr::Intrinsic(i)) => i.fmt(f)?,
+                Task::Write(ResolvedExpr::Call(sym)) => sym.fmt(f)?,
+                Task::Write(ResolvedExpr::StaleCall(sym, version)) => {
+                    write!(f, "{}@{}", sym, version)?
+                }
+                Task::Write(ResolvedExpr::Quote(e)) => {
+                    tasks.push(Task::Str("]"));
+                    tasks.push(Task::Write(e));
+                    tasks.push(Task::Str("["));
+                }
+                Task::Write(ResolvedExpr::Compose(es)) => {
+                    for (i, e) in es.iter().enumerate().rev() {
+                        if e.is_compose() {
+                            tasks.push(Task::Str(")"));
+                            tasks.push(Task::Write(e));
+                            tasks.push(Task::Str("("));
+                        } else {
+                            tasks.push(Task::Write(e));
+                        }
+                        if i > 0 {
+                            tasks.push(Task::Str(" "));
+                        }
                     }
                 }
-                for e in es.iter().skip(1) {
-                    " ".fmt(f)?;
-                    if e.is_compose() {
-                        write!(f, "({})", e)?;
-                    } else {
-                        write!(f, "{}", e)?;
+                Task::Write(ResolvedExpr::With(sym, e)) => {
+                    tasks.push(Task::Str("}"));
+                    if !e.is_empty() {
+                        tasks.push(Task::Write(e));
+                        tasks.push(Task::Str(" "));
                     }
+                    tasks.push(Task::Name(sym));
+                    tasks.push(Task::Str("{"));
                 }
-                Ok(())
+                Task::Write(ResolvedExpr::Push(sym)) => write!(f, "{{{} push}}", sym)?,
+                Task::Write(ResolvedExpr::Pop(sym)) => write!(f, "{{{} pop}}", sym)?,
             }
         }
+        Ok(())
     }
 }
 
@@ -168,21 +528,294 @@ impl fmt::Display for ResolvedValue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ResolvedValue::Call(sym) => sym.fmt(f),
+            ResolvedValue::StaleCall(sym, version) => write!(f, "{}@{}", sym, version),
             ResolvedValue::Quote(v) => write!(f, "[{}]", v),
+            ResolvedValue::Marked(usage, v) => write!(f, "[{}] {}", v, usage),
+            ResolvedValue::Host(s) => s.fmt(f),
         }
     }
 }
 
 impl fmt::Display for ResolvedValueStack {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        "⟨".fmt(f)?;
-        if let Some(v) = self.0.first() {
-            v.fmt(f)?;
+        write_values(f, &self.0)?;
+        for (name, values) in self.1.iter() {
+            write!(f, " {}", name)?;
+            write_values(f, values)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_values(f: &mut fmt::Formatter, values: &[ResolvedValue]) -> fmt::Result {
+    f.write_str("⟨")?;
+    for (i, v) in values.iter().enumerate() {
+        if i > 0 {
+            f.write_str(" ")?;
+        }
+        write!(f, "{}", v)?;
+    }
+    f.write_str("⟩")
+}
+
+impl fmt::Display for ResolvedEvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolvedEvalError::TooFewValues {
+                intrinsic,
+                available,
+                expected,
+            } => write_too_few_values(f, intrinsic, *available, *expected),
+            ResolvedEvalError::UndefinedFn(sym) => write!(f, "`{}` is not defined.", sym),
+            ResolvedEvalError::NativeTooFewValues {
+                native,
+                available,
+                expected,
+            } => write_too_few_values(f, native, *available, *expected),
+            ResolvedEvalError::NativeFailed(sym, message) => {
+                write!(f, "`{}` failed: {}", sym, message)
+            }
+            ResolvedEvalError::NotAQuotation(sym) => {
+                write!(f, "`{}` is not defined as a quotation.", sym)
+            }
+            ResolvedEvalError::HostNotAQuotation(data) => {
+                write!(f, "`{}` is a host value, not a quotation.", data)
+            }
+            ResolvedEvalError::Restricted { intrinsic, usage } => {
+                let article = if *usage == Usage::Affine { "an" } else { "a" };
+                write!(
+                    f,
+                    "`{}` can't be applied to {} {} value.",
+                    intrinsic, article, usage
+                )
+            }
+            ResolvedEvalError::PushEmpty(sym) => {
+                write!(
+                    f,
+                    "`{{{} push}}` needs 1 value, but the stack is empty.",
+                    sym
+                )
+            }
+            ResolvedEvalError::PopEmpty(sym) => {
+                write!(
+                    f,
+                    "`{{{} pop}}` needs 1 value, but `{}` is empty.",
+                    sym, sym
+                )
+            }
+            ResolvedEvalError::InStack(sym, err) => write!(f, "On `{}`: {}", sym, err),
+        }
+    }
+}
+
+impl fmt::Display for ResolvedEffect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ⟶ {}", self.inputs, self.outputs)?;
+        if self.residual != ResolvedExpr::Empty {
+            write!(f, " {}", self.residual)?;
         }
-        for v in self.0.iter().skip(1) {
-            " ".fmt(f)?;
-            v.fmt(f)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for ResolvedEffectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolvedEffectError::Eval(err) => err.fmt(f),
+            ResolvedEffectError::OutOfFuel(effect) => {
+                write!(f, "Ran out of fuel at {}.", effect)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ResolvedTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut err = self;
+        let mut is_first = true;
+        while let ResolvedTypeError::InFn(sym, inner) = err {
+            write!(f, "{} `{}`", if is_first { "In" } else { ", in" }, sym)?;
+            is_first = false;
+            err = inner;
+        }
+        if !is_first {
+            ": ".fmt(f)?;
+        }
+        match err {
+            ResolvedTypeError::UndefinedFn(sym) => write!(f, "`{}` is not defined.", sym),
+            ResolvedTypeError::InfiniteType(at, v, t) => {
+                write!(f, "`{}` needs an infinite type, {} = {}.", at, v, t)
+            }
+            ResolvedTypeError::InfiniteStack(at, r, s) => {
+                write!(f, "`{}` needs an infinite stack, {} = {}.", at, r, s)
+            }
+            ResolvedTypeError::SignatureMismatch(sym, sig, fn_type) => write!(
+                f,
+                "`{}` has the type {}, which doesn't match its signature {}.",
+                sym, fn_type, sig
+            ),
+            ResolvedTypeError::NamedStack(e) => write!(
+                f,
+                "`{}` uses a named stack, which stack-effect types don't describe.",
+                e
+            ),
+            ResolvedTypeError::InFn(..) => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Display for ResolvedSignature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(row) = &self.input_row {
+            write!(f, "..{} ", row)?;
+        }
+        for t in self.input.iter() {
+            write!(f, "{} ", t)?;
+        }
+        "->".fmt(f)?;
+        if let Some(row) = &self.output_row {
+            write!(f, " ..{}", row)?;
+        }
+        for t in self.output.iter() {
+            write!(f, " {}", t)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ResolvedTypeAnnotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolvedTypeAnnotation::Var(sym) => sym.fmt(f),
+            ResolvedTypeAnnotation::Fn(sig) => write!(f, "({})", sig),
+        }
+    }
+}
+
+/// The names of type and row variables, in the order in which they are given
+struct TypeNames {
+    types: Map<TypeVar, String>,
+    rows: Map<RowVar, String>,
+}
+
+impl TypeNames {
+    const ROWS: [&'static str; 8] = ["ρ", "σ", "τ", "υ", "φ", "χ", "ψ", "ω"];
+
+    fn new(types: Vec<TypeVar>, rows: Vec<RowVar>) -> Self {
+        let mut names = TypeNames {
+            types: Map::default(),
+            rows: Map::default(),
+        };
+        for v in types {
+            let i = names.types.len();
+            names.types.entry(v).or_insert_with(|| {
+                let letter = (b'a' + (i % 26) as u8) as char;
+                match i / 26 {
+                    0 => letter.to_string(),
+                    n => format!("{}{}", letter, n),
+                }
+            });
+        }
+        for r in rows {
+            let i = names.rows.len();
+            names.rows.entry(r).or_insert_with(|| {
+                let letter = Self::ROWS[i % Self::ROWS.len()];
+                match i / Self::ROWS.len() {
+                    0 => letter.to_owned(),
+                    n => format!("{}{}", letter, n),
+                }
+            });
+        }
+        names
+    }
+
+    fn display<F: Fn(&mut fmt::Formatter) -> fmt::Result>(&self, write: F) -> String {
+        struct Writer<F>(F);
+        impl<F: Fn(&mut fmt::Formatter) -> fmt::Result> fmt::Display for Writer<F> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                (self.0)(f)
+            }
+        }
+        Writer(write).to_string()
+    }
+
+    fn write_type(&self, f: &mut fmt::Formatter, t: &Type) -> fmt::Result {
+        match t {
+            Type::Var(v) => f.write_str(&self.types[v]),
+            Type::Fn(fn_type) => {
+                f.write_str("(")?;
+                self.write_fn(f, fn_type)?;
+                f.write_str(")")
+            }
+        }
+    }
+
+    fn write_stack(&self, f: &mut fmt::Formatter, s: &StackType) -> fmt::Result {
+        f.write_str(&self.rows[&s.row])?;
+        for t in s.types.iter() {
+            f.write_str(" ")?;
+            self.write_type(f, t)?;
+        }
+        Ok(())
+    }
+
+    fn write_fn(&self, f: &mut fmt::Formatter, fn_type: &FnType) -> fmt::Result {
+        self.write_stack(f, &fn_type.input)?;
+        f.write_str(" → ")?;
+        self.write_stack(f, &fn_type.output)
+    }
+}
+
+impl fmt::Display for FnType {
+    /// Write the type universally quantified over its variables, named in the
+    /// order in which they appear, e.g. `∀ρ a b. ρ a b → ρ b a`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (types, rows) = self.vars();
+        let names = TypeNames::new(types.clone(), rows.clone());
+        "∀".fmt(f)?;
+        for (i, r) in rows.iter().enumerate() {
+            if i > 0 {
+                " ".fmt(f)?;
+            }
+            names.rows[r].fmt(f)?;
+        }
+        for v in types.iter() {
+            write!(f, " {}", names.types[v])?;
+        }
+        ". ".fmt(f)?;
+        names.write_fn(f, self)
+    }
+}
+
+fn write_too_few_values(
+    f: &mut fmt::Formatter,
+    callee: &dyn fmt::Display,
+    available: usize,
+    expected: usize,
+) -> fmt::Result {
+    if available == 0 {
+        write!(
+            f,
+            "`{}` needs {} value{}, but the stack is empty.",
+            callee,
+            expected,
+            if expected == 1 { "" } else { "s" }
+        )
+    } else {
+        write!(
+            f,
+            "`{}` needs {} values, but the stack has only {}.",
+            callee, expected, available
+        )
+    }
+}
+
+impl fmt::Display for EvalErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)?;
+        for note in self.notes() {
+            write!(f, "\n{}", note)?;
         }
-        "⟩".fmt(f)
+        Ok(())
     }
 }
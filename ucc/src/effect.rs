@@ -0,0 +1,202 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::{Context, EvalError, Expr, Fuel, Map, Symbol, Value, ValueStack};
+use fxhash::FxHashSet;
+use std::rc::Rc;
+
+/// The effect of an expression, as a rewrite rule from a stack of symbolic
+/// quotations, e.g. `⟨[a] [b]⟩ ⟶ ⟨[b] [a]⟩` for `swap`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Effect {
+    /// The symbolic quotations that the expression takes, one for each value
+    /// it needs
+    pub inputs: ValueStack,
+    /// The values that the expression leaves
+    pub outputs: ValueStack,
+    /// What remains of the expression if evaluation got stuck on a call to
+    /// one of the symbolic quotations' variables, or the empty expression
+    pub residual: Expr,
+}
+
+/// Why the effect of an expression couldn't be derived
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EffectError {
+    Eval(EvalError),
+    /// The fuel ran out first. Holds the effect of the steps taken so far.
+    OutOfFuel(Effect),
+}
+
+impl Context {
+    /// The symbols called by `e`, or by the definitions of the functions
+    /// that it calls, transitively.
+    fn reachable_calls(&self, e: &Expr) -> FxHashSet<Symbol> {
+        let mut calls = FxHashSet::default();
+        let mut pending = e.calls();
+        while let Some(sym) = pending.pop() {
+            if calls.insert(sym) {
+                if let Some(body) = self.fns.get(&sym) {
+                    pending.extend(body.calls());
+                }
+            }
+        }
+        calls
+    }
+
+    /// Derive the effect of `e` by evaluating it on as many symbolic
+    /// quotations as it needs, taking at most `fuel` small steps. Each
+    /// quotation calls a variable, named `a`, `b` and so on from the bottom of
+    /// the stack, that is neither defined nor called by `e`, directly or
+    /// through the functions it calls.
+    ///
+    /// Whenever evaluation needs more values, quotations are added to the
+    /// bottom of the stack and evaluation resumes, since the values below
+    /// those that an expression has taken don't change how it evaluates. The
+    /// variables are named in the order they are added, from the top, and
+    /// named again from the bottom once evaluation stops. Only names that
+    /// are given to variables are interned, so deriving effects keeps using
+    /// the same few names.
+    ///
+    /// Evaluation stops with a residual expression when it gets stuck on a
+    /// call to a variable, e.g. `apply` has the effect `⟨[a]⟩ ⟶ ⟨⟩ a`.
+    pub fn effect(&mut self, e: &Expr, fuel: Fuel) -> Result<Effect, EffectError> {
+        let calls = self.reachable_calls(e);
+        let mut vars: Vec<Symbol> = vec![];
+        let mut names = (1..).flat_map(|n| {
+            ('a'..='z').map(move |c| match n {
+                1 => c.to_string(),
+                n => format!("{}{}", c, n),
+            })
+        });
+        let mut vs = ValueStack::default();
+        let mut e = e.clone();
+        let mut steps = 0;
+        let out_of_fuel = loop {
+            if e == Expr::default() {
+                break false;
+            }
+            if steps == fuel.0 {
+                break true;
+            }
+            let needed = match self.small_step(&mut vs, &mut e) {
+                Ok(()) => {
+                    steps += 1;
+                    continue;
+                }
+                Err(EvalError::UndefinedFn(sym)) if vars.contains(&sym) => break false,
+                Err(EvalError::TooFewValues {
+                    available,
+                    expected,
+                    ..
+                })
+                | Err(EvalError::NativeTooFewValues {
+                    available,
+                    expected,
+                    ..
+                }) => expected - available,
+                Err(EvalError::PushEmpty(_)) => 1,
+                Err(err) => return Err(EffectError::Eval(err)),
+            };
+            let first = vars.len();
+            while vars.len() < first + needed {
+                let name = names.next().unwrap();
+                let is_free = match self.symbol(&name) {
+                    None => true,
+                    Some(sym) => {
+                        !self.fns.contains_key(&sym)
+                            && !self.natives.contains_key(&sym)
+                            && !calls.contains(&sym)
+                    }
+                };
+                if is_free {
+                    vars.push(self.intern(&name));
+                }
+            }
+            vs.0.splice(0..0, vars[first..].iter().rev().map(|&sym| var_quote(sym)));
+        };
+        // Rename the variables so that they are named from the bottom.
+        let renamed: Map<Symbol, Symbol> = vars
+            .iter()
+            .zip(vars.iter().rev())
+            .filter(|(sym, new_sym)| sym != new_sym)
+            .map(|(&sym, &new_sym)| (sym, new_sym))
+            .collect();
+        let mut outputs = vs;
+        if !renamed.is_empty() {
+            for v in outputs.0.iter_mut().chain(outputs.1.values_mut().flatten()) {
+                rename_value(v, &renamed);
+            }
+            e = rename_calls(&e, &renamed);
+        }
+        let effect = Effect {
+            inputs: ValueStack::from(vars.iter().map(|&sym| var_quote(sym)).collect::<Vec<_>>()),
+            outputs,
+            residual: e,
+        };
+        if out_of_fuel {
+            Err(EffectError::OutOfFuel(effect))
+        } else {
+            Ok(effect)
+        }
+    }
+}
+
+/// The symbolic quotation of a variable.
+fn var_quote(sym: Symbol) -> Value {
+    Value::Quote(Rc::new(Expr::Call(sym)))
+}
+
+/// Rename the calls in the quotation `v`, if it is one.
+fn rename_value(v: &mut Value, renamed: &Map<Symbol, Symbol>) {
+    if let Value::Quote(e) | Value::Marked(_, e) = v {
+        *e = Rc::new(rename_calls(e, renamed));
+    }
+}
+
+/// `e`, with each call to a key of `renamed` replaced by a call to its value.
+/// The expressions that pinned calls hold are definitions, so they are left
+/// as they are.
+fn rename_calls(e: &Expr, renamed: &Map<Symbol, Symbol>) -> Expr {
+    enum Task<'a> {
+        Rename(&'a Expr),
+        Quote,
+        Compose(usize),
+        With(Symbol),
+    }
+    let mut tasks = vec![Task::Rename(e)];
+    let mut es = vec![];
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Rename(Expr::Call(sym)) => es.push(Expr::Call(*renamed.get(sym).unwrap_or(sym))),
+            Task::Rename(Expr::Quote(qe)) => {
+                tasks.push(Task::Quote);
+                tasks.push(Task::Rename(qe));
+            }
+            Task::Rename(Expr::Compose(children)) => {
+                tasks.push(Task::Compose(children.len()));
+                tasks.extend(children.iter().rev().map(Task::Rename));
+            }
+            Task::Rename(Expr::With(sym, e)) => {
+                tasks.push(Task::With(*sym));
+                tasks.push(Task::Rename(e));
+            }
+            Task::Rename(e) => es.push(e.clone()),
+            Task::Quote => {
+                let e = es.pop().unwrap();
+                es.push(Expr::Quote(Rc::new(e)));
+            }
+            Task::Compose(len) => {
+                let children = es.split_off(es.len() - len);
+                es.push(Expr::Compose(children));
+            }
+            Task::With(sym) => {
+                let e = es.pop().unwrap();
+                es.push(Expr::With(sym, Box::new(e)));
+            }
+        }
+    }
+    es.pop().unwrap()
+}
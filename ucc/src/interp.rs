@@ -4,98 +4,709 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use crate::assertion::*;
 use crate::builtin::FN_DEF_SRCS;
 use crate::core::*;
+use crate::diagnostic::{error_location, Diagnostic, Span, SyntaxError};
 use crate::display::*;
+use crate::observer::{EvalObserver, Step};
 use crate::parse::*;
+use crate::types::{TypeChecker, TypeError};
+use std::error::Error;
+use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) enum InterpItem {
+pub enum InterpItem {
     FnDef(FnDef),
     Expr(Expr),
+    /// Clear all definitions, as with `:clear`, which only source files can
+    /// do, so that a saved file can remove the builtins it doesn't keep.
+    Clear,
+}
+
+/// A source file, parsed as far as its syntax errors allow
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SourceFile {
+    /// The items that parsed, each with the spans of the elements of its
+    /// expression
+    pub items: Vec<(InterpItem, Vec<Span>)>,
+    /// A diagnostic for each syntax error
+    pub errors: Vec<Diagnostic>,
+}
+
+/// A part of a source file, as parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SourceItem {
+    FnDef(FnDef),
+    Clear,
+    /// Elements of an expression, with their spans
+    Elements(Vec<(Expr, Span)>),
+    /// A syntax error, with where the item that has it starts
+    Error(usize, SyntaxError),
+}
+
+impl SourceFile {
+    /// Parse `source`, recovering from syntax errors so that every one of
+    /// them is reported.
+    pub fn parse(ctx: &mut Context, source: &str) -> Self {
+        let items = match SourceFileParser::new().parse(ctx, source) {
+            Ok(items) => items,
+            Err(err) => vec![SourceItem::Error(0, err.map_token(|t| t.to_string()))],
+        };
+        SourceFile::from_items(items, |start, err| {
+            // Recovering from an error can hide another, so parse the item
+            // with the error without recovering.
+            let mut scratch = Context::default();
+            Diagnostic::from_parse_error(err, source, start, |source| {
+                InterpItemsParser::new()
+                    .parse(&mut scratch, source)
+                    .err()
+                    .map(|err| error_location(&err))
+            })
+        })
+    }
+
+    /// Collect parsed items, composing adjacent elements into expressions,
+    /// and diagnosing syntax errors with `diagnose`, given where the item
+    /// with the error starts. Expressions don't extend across syntax errors.
+    fn from_items(
+        source_items: Vec<SourceItem>,
+        mut diagnose: impl FnMut(usize, &SyntaxError) -> Diagnostic,
+    ) -> Self {
+        let mut file = SourceFile::default();
+        let mut elements = vec![];
+        for source_item in source_items {
+            if let SourceItem::Elements(es) = source_item {
+                elements.extend(es);
+                continue;
+            }
+            file.push_expr(std::mem::take(&mut elements));
+            match source_item {
+                SourceItem::FnDef(fn_def) => file.items.push((InterpItem::FnDef(fn_def), vec![])),
+                SourceItem::Clear => file.items.push((InterpItem::Clear, vec![])),
+                SourceItem::Error(start, err) => file.errors.push(diagnose(start, &err)),
+                SourceItem::Elements(_) => unreachable!(),
+            }
+        }
+        file.push_expr(elements);
+        file
+    }
+
+    fn push_expr(&mut self, elements: Vec<(Expr, Span)>) {
+        if !elements.is_empty() {
+            let (es, spans): (Vec<Expr>, Vec<Span>) = elements.into_iter().unzip();
+            self.items
+                .push((InterpItem::Expr(Expr::compose(es)), spans));
+        }
+    }
+}
+
+/// Why evaluating source code failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceError {
+    /// The source has syntax errors, so none of it was evaluated.
+    Parse(Vec<Diagnostic>),
+    /// A function definition doesn't match its signature, so neither it nor
+    /// the rest of the source was evaluated.
+    Type(TypeError),
+    Eval(EvalError),
+}
+
+/// The results of running a test file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TestSummary {
+    pub passed: usize,
+    pub failed: usize,
+    /// The number of syntax errors in the file
+    pub errors: usize,
+}
+
+impl TestSummary {
+    /// Whether every assertion passed and there were no syntax errors.
+    pub fn is_success(&self) -> bool {
+        self.failed == 0 && self.errors == 0
+    }
+}
+
+impl fmt::Display for TestSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} passed, {} failed", self.passed, self.failed)?;
+        match self.errors {
+            0 => {}
+            1 => ", 1 syntax error".fmt(f)?,
+            n => write!(f, ", {} syntax errors", n)?,
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) enum InterpCommand {
-    Eval(Vec<InterpItem>),
-    Trace(Expr),
+    /// Items to evaluate, each with the spans of the elements of its expression
+    Eval(Vec<(InterpItem, Vec<Span>)>),
+    /// An expression to trace, with the spans of its elements
+    Trace(Expr, Vec<Span>),
     Show(Symbol),
     List,
-    Drop,
+    Compress(Option<Symbol>),
+    Rollback(Option<Symbol>),
+    Type(Expr),
+    Typecheck(Option<Symbol>),
+    Effect(Expr),
+    Linearity(Option<Symbol>),
+    Assert(Assertion),
+    Load(PathBuf),
+    Save(PathBuf),
+    Reload,
+    /// Drop all the value stacks, or only the given named stack
+    Drop(Option<Symbol>),
     Clear,
     Reset,
     Help,
 }
 
-pub(crate) static HELP: &'static str = "\
+pub(crate) static HELP: &str = "\
 Commands available:
 
    <expr>                   evaluate <expr>
    {fn <sym> = <expr>}      define <sym> as <expr>
+   {fn <sym> : <sig> = <expr>}
+                            define <sym> as <expr> if it has the type
+                            <sig>, e.g. a b -> b a, or with row variables
+                            ..r a (..r a -> ..r b) -> ..r b; the functions
+                            that <expr> calls must already be defined
    :trace <expr>            trace the evaluation of <expr>
    :show <sym>              show the definition of <sym>
    :list                    list the defined symbols
-   :drop                    drop the current value stack
+   :compress [<mode>]       show or set how values are compressed:
+                            off, top (the default) or deep
+   :rollback [on|off]       show or set whether the value stack is
+                            rolled back when evaluating a line fails
+   :type <expr>             show the stack-effect type of <expr>
+   :typecheck [on|off]      show or set whether each expression is type
+                            checked before it is evaluated
+   :effect <expr>           show the effect of <expr> on symbolic values,
+                            e.g. ⟨[a] [b]⟩ ⟶ ⟨[b] [a]⟩ for swap
+   :linearity [on|off]      show or set whether cloning quotations marked
+                            with affine or linear, or dropping those marked
+                            with linear, is rejected
+   :assert <assertion>      check an assertion, e.g. ⟨[a] [b]⟩ swap ⇓ ⟨[b] [a]⟩,
+                            or ⟶ for a single small step
+   :load <path>             load the definitions and expressions in a file
+   :save <path>             save the definitions that differ from the
+                            builtins to a file, which starts with :clear
+                            if any builtin was cleared
+   :reload                  load every previously loaded file again
+   :drop [<stack>]          drop all the value stacks, or only the
+                            named stack <stack>, e.g. $s
    :clear                   clear all definitions
    :reset                   reset the interpreter
    :help                    display this list of commands
 ";
 
+/// How the values on the stack are compressed after each step
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Values are never compressed.
+    Off,
+    /// Quotations that match the definition of a function are replaced with
+    /// calls to that function.
+    #[default]
+    TopLevel,
+    /// As with `TopLevel`, but quotations nested in values, and runs of
+    /// expressions in them that match the definition of a function, are also
+    /// replaced, bottom-up. Since this walks every value, it is only done
+    /// once each line has been evaluated, rather than after each step.
+    Deep,
+}
+
+impl Compression {
+    fn name(self) -> &'static str {
+        match self {
+            Compression::Off => "off",
+            Compression::TopLevel => "top",
+            Compression::Deep => "deep",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Compression::Off, Compression::TopLevel, Compression::Deep]
+            .iter()
+            .copied()
+            .find(|c| c.name() == name)
+    }
+}
+
+/// An error in the interpreter itself. Errors in its input are written to its
+/// output instead.
+#[derive(Debug)]
+pub enum InterpError {
+    /// Writing to the output failed.
+    Io(io::Error),
+    /// `Interp::interp_step` was called when there was no command to step.
+    NoCommand,
+}
+
+impl fmt::Display for InterpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpError::Io(err) => write!(f, "failed to write output: {}", err),
+            InterpError::NoCommand => "no command to step".fmt(f),
+        }
+    }
+}
+
+impl Error for InterpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InterpError::Io(err) => Some(err),
+            InterpError::NoCommand => None,
+        }
+    }
+}
+
+impl From<io::Error> for InterpError {
+    fn from(err: io::Error) -> Self {
+        InterpError::Io(err)
+    }
+}
+
 pub struct Interp {
     ctx: Context,
     vs: ValueStack,
+    compression: Compression,
+    rollback: bool,
+    /// Whether expressions are type checked before they are evaluated
+    typecheck: bool,
+    /// The value stack before the current command, if rollback is on
+    checkpoint: Option<ValueStack>,
     command: Option<InterpCommand>,
     is_first_eval_step: bool,
+    /// The input of the current command
+    source: String,
+    /// The number of elements of the expression being evaluated that remain
+    /// from the source, which are the last elements of the expression
+    remaining: usize,
+    /// The functions being unfolded by the expression being evaluated
+    calls: CallStack,
+    /// The files that have been loaded, in the order they were first loaded
+    loaded: Vec<PathBuf>,
+    /// The files that remain to be loaded by the current command
+    to_load: Vec<PathBuf>,
+    /// The version of each builtin definition that the context started with.
+    /// A builtin is kept as long as it is defined with that version.
+    builtins: Map<Symbol, Version>,
+    /// The observer of evaluation, which survives resets
+    observer: Option<Box<dyn EvalObserver>>,
 }
 
 impl Default for Interp {
     fn default() -> Self {
         let mut ctx = Context::default();
+        let mut builtins = Map::default();
         for fn_def_src in FN_DEF_SRCS.iter() {
-            let fn_def = FnDefParser::new()
-                .parse(&mut ctx.interner, fn_def_src)
-                .unwrap();
-            assert_eq!(ctx.define_fn(fn_def), None);
+            let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+            let sym = fn_def.0;
+            assert_eq!(ctx.define_fn(fn_def), Ok(None));
+            builtins.insert(sym, ctx.version(sym));
         }
         Self {
             ctx,
             vs: ValueStack::default(),
+            compression: Compression::default(),
+            rollback: false,
+            typecheck: false,
+            checkpoint: None,
             command: None,
             is_first_eval_step: true,
+            source: String::new(),
+            remaining: 0,
+            calls: CallStack::default(),
+            loaded: vec![],
+            to_load: vec![],
+            builtins,
+            observer: None,
         }
     }
 }
 
 impl Interp {
+    pub fn context(&self) -> &Context {
+        &self.ctx
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.ctx
+    }
+
+    pub fn value_stack(&self) -> &ValueStack {
+        &self.vs
+    }
+
+    pub fn value_stack_mut(&mut self) -> &mut ValueStack {
+        &mut self.vs
+    }
+
+    /// Set the observer that is notified of each small step, compression and
+    /// evaluation error, in addition to any tracing.
+    pub fn set_observer(&mut self, observer: Option<Box<dyn EvalObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Define the functions and evaluate the expressions in `source`, on the
+    /// value stack, until `fuel` small steps have been taken in total. If the
+    /// fuel runs out, the rest of the source is skipped and the expression
+    /// that remained to be evaluated is returned. If evaluation fails, the
+    /// value stack is left as it was at the time.
+    pub fn eval(&mut self, source: &str, fuel: Fuel) -> Result<Outcome, SourceError> {
+        let file = SourceFile::parse(&mut self.ctx, source);
+        if !file.errors.is_empty() {
+            return Err(SourceError::Parse(file.errors));
+        }
+        let mut steps = 0;
+        for (item, _) in file.items {
+            match item {
+                InterpItem::FnDef(fn_def) => {
+                    self.ctx.define_fn(fn_def).map_err(SourceError::Type)?;
+                }
+                InterpItem::Clear => self.ctx.clear_fns(),
+                InterpItem::Expr(mut e) => {
+                    while e != Expr::default() {
+                        if steps == fuel.0 {
+                            self.compress(&e, &mut ());
+                            return Ok(Outcome::OutOfFuel(e));
+                        }
+                        self.ctx
+                            .small_step_observed(&mut self.vs, &mut e, &mut self.observer)
+                            .map_err(SourceError::Eval)?;
+                        steps += 1;
+                    }
+                }
+            }
+        }
+        self.compress(&Expr::default(), &mut ());
+        Ok(Outcome::Normal)
+    }
+
     pub fn is_done(&self) -> bool {
         self.command.is_none()
     }
 
-    pub fn interp_start(&mut self, input: &str, w: &mut dyn io::Write) -> io::Result<()> {
-        match InterpCommandParser::new().parse(&mut self.ctx.interner, input) {
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn rollback(&self) -> bool {
+        self.rollback
+    }
+
+    /// Set whether the value stack is restored to what it was before a line
+    /// when evaluating the line fails.
+    pub fn set_rollback(&mut self, rollback: bool) {
+        self.rollback = rollback;
+    }
+
+    pub fn typecheck(&self) -> bool {
+        self.typecheck
+    }
+
+    /// Set whether each expression is type checked before it is evaluated,
+    /// in which case a type error stops the line instead.
+    pub fn set_typecheck(&mut self, typecheck: bool) {
+        self.typecheck = typecheck;
+    }
+
+    /// Restore the value stack to the checkpoint, if any.
+    fn roll_back(&mut self, w: &mut dyn io::Write) -> Result<(), InterpError> {
+        if let Some(vs) = self.checkpoint.take() {
+            self.vs = vs;
+            w.write_fmt(format_args!(
+                "Rolled back to {}.\n",
+                self.vs.resolve(&self.ctx)
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Take a small step, keeping track of how many elements of `e` remain
+    /// from the source and which functions are being unfolded, and notify
+    /// both the observer and `tracer` of it.
+    fn small_step(&mut self, e: &mut Expr, tracer: &mut dyn EvalObserver) -> Result<(), EvalError> {
+        let len = e.elements_len();
+        self.ctx.small_step_with_calls(
+            &mut self.vs,
+            e,
+            &mut self.calls,
+            &mut (&mut self.observer, tracer),
+        )?;
+        // Evaluation only ever replaces the first element, so it is from the
+        // source if the expression is no longer than the remaining elements.
+        if len == self.remaining {
+            self.remaining -= 1;
+        }
+        Ok(())
+    }
+
+    /// Write `err`, pointing at the element of the source whose evaluation
+    /// failed, given the spans of the elements and what remains of `e`.
+    fn write_eval_error(
+        &self,
+        w: &mut dyn io::Write,
+        err: &EvalError,
+        spans: &[Span],
+        e: &Expr,
+    ) -> Result<(), InterpError> {
+        // If the first element isn't from the source, it is from evaluating
+        // the last element from the source that has been evaluated.
+        let evaluated = spans.len() - self.remaining;
+        let i = if e.elements_len() == self.remaining {
+            Some(evaluated)
+        } else {
+            evaluated.checked_sub(1)
+        };
+        let report = EvalErrorReport::new(&self.ctx, err, &self.vs, &self.calls);
+        match i.and_then(|i| spans.get(i)) {
+            Some(&span) => {
+                let diagnostic = Diagnostic {
+                    span,
+                    message: format!("Evaluation error: {}", report.error),
+                    notes: report.notes(),
+                };
+                w.write_all(diagnostic.render(&self.source).as_bytes())?;
+            }
+            None => w.write_fmt(format_args!("Evaluation error: {}\n", report))?,
+        }
+        Ok(())
+    }
+
+    /// Compress the value stack according to the compression policy, and
+    /// notify both the observer and `tracer` if anything was replaced, given
+    /// the expression that remains to be evaluated. Returns whether anything
+    /// was replaced.
+    fn compress(&mut self, e: &Expr, tracer: &mut dyn EvalObserver) -> bool {
+        let compressed = match self.compression {
+            Compression::Off => false,
+            Compression::TopLevel => self.ctx.compress(&mut self.vs),
+            Compression::Deep => self.ctx.compress_deep(&mut self.vs),
+        };
+        if compressed {
+            (&mut self.observer, tracer).compressed(&self.ctx, &self.vs, e);
+        }
+        compressed
+    }
+
+    /// Abandon the rest of the line or file being evaluated after an error,
+    /// rolling back, and go on to the next file that remains to be loaded,
+    /// if any.
+    fn abandon(&mut self, w: &mut dyn io::Write) -> Result<(), InterpError> {
+        self.roll_back(w)?;
+        self.load_next(w)
+    }
+
+    /// Start evaluating the next file that remains to be loaded, if any,
+    /// skipping any that can't be read. If rollback is on, an error in the
+    /// file rolls the value stack back to what it was before the file.
+    fn load_next(&mut self, w: &mut dyn io::Write) -> Result<(), InterpError> {
+        while !self.to_load.is_empty() {
+            let path = self.to_load.remove(0);
+            let source = match std::fs::read_to_string(&path) {
+                Ok(source) => source,
+                Err(err) => {
+                    w.write_fmt(format_args!(
+                        "Failed to read `{}`: {}.\n",
+                        path.display(),
+                        err
+                    ))?;
+                    continue;
+                }
+            };
+            w.write_fmt(format_args!("Loading `{}`.\n", path.display()))?;
+            let file = SourceFile::parse(&mut self.ctx, &source);
+            for diagnostic in &file.errors {
+                w.write_all(diagnostic.render(&source).as_bytes())?;
+            }
+            if !self.loaded.contains(&path) {
+                self.loaded.push(path);
+            }
+            self.source = source;
+            self.is_first_eval_step = true;
+            self.checkpoint = self.rollback.then(|| self.vs.clone());
+            self.command = Some(InterpCommand::Eval(file.items));
+            break;
+        }
+        Ok(())
+    }
+
+    /// Write every definition other than the builtins that are kept to
+    /// `path`, each after the ones it calls, so that loading the file into a
+    /// new interpreter defines them all again. If any builtin isn't defined
+    /// anymore, the file starts by clearing the definitions, and then defines
+    /// every function. Returns how many definitions were written.
+    fn save(&self, path: &Path) -> io::Result<usize> {
+        let mut source = String::new();
+        let cleared = self
+            .builtins
+            .keys()
+            .any(|&sym| !self.ctx.fns.contains_key(&sym));
+        if cleared {
+            source.push_str(":clear\n");
+        }
+        let mut n = 0;
+        for sym in self.ctx.fns_in_dependency_order() {
+            if !cleared && self.builtins.get(&sym) == Some(&self.ctx.version(sym)) {
+                continue;
+            }
+            source.push_str(&show_fn(&self.ctx, sym));
+            source.push('\n');
+            n += 1;
+        }
+        std::fs::write(path, source)?;
+        Ok(n)
+    }
+
+    /// Run a source file as a script: define its functions, evaluate its
+    /// expressions to completion, and write the resulting value stack to `w`.
+    /// Syntax and evaluation errors are written to `err_w` instead, and stop
+    /// the script before it is evaluated or when they occur, respectively.
+    /// Returns whether the script ran without errors.
+    pub fn run(
+        &mut self,
+        source: &str,
+        w: &mut dyn io::Write,
+        err_w: &mut dyn io::Write,
+    ) -> Result<bool, InterpError> {
+        let file = SourceFile::parse(&mut self.ctx, source);
+        for diagnostic in &file.errors {
+            err_w.write_all(diagnostic.render(source).as_bytes())?;
+        }
+        if !file.errors.is_empty() {
+            err_w.flush()?;
+            return Ok(false);
+        }
+        self.source = source.to_owned();
+        for (item, spans) in file.items {
+            match item {
+                InterpItem::FnDef(fn_def) => {
+                    if let Err(err) = self.ctx.define_fn(fn_def) {
+                        err_w
+                            .write_fmt(format_args!("Type error: {}\n", err.resolve(&self.ctx)))?;
+                        err_w.flush()?;
+                        return Ok(false);
+                    }
+                }
+                InterpItem::Clear => self.ctx.clear_fns(),
+                InterpItem::Expr(mut e) => {
+                    self.remaining = spans.len();
+                    self.calls.clear();
+                    while e != Expr::default() {
+                        if let Err(err) = self.small_step(&mut e, &mut ()) {
+                            self.write_eval_error(err_w, &err, &spans, &e)?;
+                            err_w.flush()?;
+                            return Ok(false);
+                        }
+                    }
+                }
+            }
+        }
+        self.compress(&Expr::default(), &mut ());
+        w.write_fmt(format_args!("{}\n", self.vs.resolve(&self.ctx)))?;
+        w.flush()?;
+        Ok(true)
+    }
+
+    /// Run a test file: define its functions and check its assertions in
+    /// order, writing a diagnostic for each syntax error and failure to `w`.
+    pub fn test(
+        &mut self,
+        source: &str,
+        w: &mut dyn io::Write,
+    ) -> Result<TestSummary, InterpError> {
+        let items = match TestFileParser::new().parse(&mut self.ctx, source) {
+            Ok(items) => items,
+            Err(err) => vec![TestItem::Error(0, err.map_token(|t| t.to_string()))],
+        };
+        let mut summary = TestSummary::default();
+        for item in items {
+            match item {
+                TestItem::FnDef(fn_def) => {
+                    if let Err(err) = self.ctx.define_fn(fn_def) {
+                        w.write_fmt(format_args!("Type error: {}\n", err.resolve(&self.ctx)))?;
+                        summary.failed += 1;
+                    }
+                }
+                TestItem::Assertion(assertion) => {
+                    match assertion.check(&mut self.ctx, BIG_STEP_FUEL) {
+                        Ok(()) => summary.passed += 1,
+                        Err(failure) => {
+                            let diagnostic = assertion.diagnose(&self.ctx, &failure);
+                            w.write_all(diagnostic.render(source).as_bytes())?;
+                            summary.failed += 1;
+                        }
+                    }
+                }
+                TestItem::Error(start, err) => {
+                    // As for source files, parse the item with the error
+                    // without recovering.
+                    let mut scratch = Context::default();
+                    let diagnostic = Diagnostic::from_parse_error(&err, source, start, |source| {
+                        StrictTestFileParser::new()
+                            .parse(&mut scratch, source)
+                            .err()
+                            .map(|err| error_location(&err))
+                    });
+                    w.write_all(diagnostic.render(source).as_bytes())?;
+                    summary.errors += 1;
+                }
+            }
+        }
+        w.flush()?;
+        Ok(summary)
+    }
+
+    pub fn interp_start(&mut self, input: &str, w: &mut dyn io::Write) -> Result<(), InterpError> {
+        self.checkpoint = None;
+        self.to_load.clear();
+        self.source = input.to_owned();
+        match InterpCommandParser::new().parse(&mut self.ctx, input) {
             Err(err) => {
-                // TODO: better error messages
-                w.write_fmt(format_args!("{:?}\n", err))?;
+                let mut scratch = Context::default();
+                let diagnostic = Diagnostic::from_parse_error(&err, input, 0, |input| {
+                    InterpCommandParser::new()
+                        .parse(&mut scratch, input)
+                        .err()
+                        .map(|err| error_location(&err))
+                });
+                w.write_all(diagnostic.render(input).as_bytes())?;
             }
             Ok(InterpCommand::Eval(is)) => {
                 self.is_first_eval_step = true;
+                self.checkpoint = self.rollback.then(|| self.vs.clone());
                 self.command = Some(InterpCommand::Eval(is));
             }
-            Ok(InterpCommand::Trace(e)) => {
+            Ok(InterpCommand::Trace(e, spans)) => {
                 w.write_fmt(format_args!(
                     "{} {}\n",
-                    self.vs.resolve(&self.ctx.interner),
-                    e.resolve(&self.ctx.interner)
+                    self.vs.resolve(&self.ctx),
+                    e.resolve(&self.ctx)
                 ))?;
-                self.command = Some(InterpCommand::Trace(e));
+                self.checkpoint = self.rollback.then(|| self.vs.clone());
+                self.remaining = spans.len();
+                self.calls.clear();
+                self.command = Some(InterpCommand::Trace(e, spans));
             }
             Ok(InterpCommand::Show(sym)) => {
-                if let Some(e) = self.ctx.fns.get(&sym) {
+                if self.ctx.fns.contains_key(&sym) {
+                    w.write_fmt(format_args!("{}\n", show_fn(&self.ctx, sym)))?;
+                } else if self.ctx.is_native(sym) {
                     w.write_fmt(format_args!(
-                        "{{fn {} = {}}}\n",
-                        sym.resolve(&self.ctx.interner),
-                        e.resolve(&self.ctx.interner)
+                        "{{fn {} = <native>}}\n",
+                        sym.resolve(&self.ctx)
                     ))?;
                 } else {
                     w.write_fmt(format_args!("Not defined.\n"))?;
@@ -106,7 +717,8 @@ impl Interp {
                     .ctx
                     .fns
                     .keys()
-                    .map(|sym| sym.resolve(&self.ctx.interner))
+                    .chain(self.ctx.natives.keys())
+                    .map(|sym| sym.resolve(&self.ctx))
                     .collect();
                 names.sort_unstable();
                 if let Some(name) = names.first() {
@@ -118,103 +730,315 @@ impl Interp {
                 }
                 w.write_all("\n".as_bytes())?;
             }
-            Ok(InterpCommand::Drop) => {
+            Ok(InterpCommand::Compress(None)) => {
+                w.write_fmt(format_args!("Compression: {}.\n", self.compression.name()))?;
+            }
+            Ok(InterpCommand::Compress(Some(sym))) => {
+                let name = sym.resolve(&self.ctx);
+                if let Some(compression) = Compression::from_name(&name) {
+                    self.compression = compression;
+                    w.write_fmt(format_args!("Compression set to {}.\n", name))?;
+                } else {
+                    w.write_fmt(format_args!(
+                        "Unknown compression `{}`. Expected off, top or deep.\n",
+                        name
+                    ))?;
+                }
+            }
+            Ok(InterpCommand::Rollback(None)) => {
+                let name = if self.rollback { "on" } else { "off" };
+                w.write_fmt(format_args!("Rollback: {}.\n", name))?;
+            }
+            Ok(InterpCommand::Rollback(Some(sym))) => {
+                let name = sym.resolve(&self.ctx);
+                match name.as_str() {
+                    "on" | "off" => {
+                        self.rollback = name == "on";
+                        w.write_fmt(format_args!("Rollback set to {}.\n", name))?;
+                    }
+                    _ => {
+                        w.write_fmt(format_args!(
+                            "Unknown rollback `{}`. Expected on or off.\n",
+                            name
+                        ))?;
+                    }
+                }
+            }
+            Ok(InterpCommand::Type(e)) => match TypeChecker::new(&self.ctx).infer(&e) {
+                Ok(fn_type) => {
+                    w.write_fmt(format_args!("{} : {}\n", e.resolve(&self.ctx), fn_type))?
+                }
+                Err(err) => {
+                    w.write_fmt(format_args!("Type error: {}\n", err.resolve(&self.ctx)))?
+                }
+            },
+            Ok(InterpCommand::Effect(e)) => match self.ctx.effect(&e, BIG_STEP_FUEL) {
+                Ok(effect) => w.write_fmt(format_args!(
+                    "{} : {}\n",
+                    e.resolve(&self.ctx),
+                    effect.resolve(&self.ctx)
+                ))?,
+                Err(err) => {
+                    w.write_fmt(format_args!("Effect error: {}\n", err.resolve(&self.ctx)))?
+                }
+            },
+            Ok(InterpCommand::Typecheck(None)) => {
+                let name = if self.typecheck { "on" } else { "off" };
+                w.write_fmt(format_args!("Typecheck: {}.\n", name))?;
+            }
+            Ok(InterpCommand::Typecheck(Some(sym))) => {
+                let name = sym.resolve(&self.ctx);
+                match name.as_str() {
+                    "on" | "off" => {
+                        self.typecheck = name == "on";
+                        w.write_fmt(format_args!("Typecheck set to {}.\n", name))?;
+                    }
+                    _ => {
+                        w.write_fmt(format_args!(
+                            "Unknown typecheck `{}`. Expected on or off.\n",
+                            name
+                        ))?;
+                    }
+                }
+            }
+            Ok(InterpCommand::Linearity(None)) => {
+                let name = if self.ctx.linearity() { "on" } else { "off" };
+                w.write_fmt(format_args!("Linearity: {}.\n", name))?;
+            }
+            Ok(InterpCommand::Linearity(Some(sym))) => {
+                let name = sym.resolve(&self.ctx);
+                match name.as_str() {
+                    "on" | "off" => {
+                        self.ctx.set_linearity(name == "on");
+                        w.write_fmt(format_args!("Linearity set to {}.\n", name))?;
+                    }
+                    _ => {
+                        w.write_fmt(format_args!(
+                            "Unknown linearity `{}`. Expected on or off.\n",
+                            name
+                        ))?;
+                    }
+                }
+            }
+            Ok(InterpCommand::Assert(assertion)) => {
+                match assertion.check(&mut self.ctx, BIG_STEP_FUEL) {
+                    Ok(()) => w.write_fmt(format_args!("Passed.\n"))?,
+                    Err(failure) => {
+                        let diagnostic = assertion.diagnose(&self.ctx, &failure);
+                        w.write_all(diagnostic.render(input).as_bytes())?;
+                    }
+                }
+            }
+            Ok(InterpCommand::Load(path)) => {
+                self.to_load = vec![path];
+                self.load_next(w)?;
+            }
+            Ok(InterpCommand::Save(path)) => match self.save(&path) {
+                Ok(n) => w.write_fmt(format_args!(
+                    "Saved {} definitions to `{}`.\n",
+                    n,
+                    path.display()
+                ))?,
+                Err(err) => w.write_fmt(format_args!(
+                    "Failed to write `{}`: {}.\n",
+                    path.display(),
+                    err
+                ))?,
+            },
+            Ok(InterpCommand::Reload) => {
+                if self.loaded.is_empty() {
+                    w.write_fmt(format_args!("No files loaded.\n"))?;
+                } else {
+                    self.to_load = self.loaded.clone();
+                    self.load_next(w)?;
+                }
+            }
+            Ok(InterpCommand::Drop(None)) => {
                 self.vs = ValueStack::default();
                 w.write_fmt(format_args!("Values dropped.\n"))?;
             }
+            Ok(InterpCommand::Drop(Some(sym))) => {
+                self.vs.1.remove(&sym);
+                w.write_fmt(format_args!(
+                    "Values on `{}` dropped.\n",
+                    sym.resolve(&self.ctx)
+                ))?;
+            }
             Ok(InterpCommand::Clear) => {
-                self.ctx.fns.clear();
-                self.ctx.exprs.clear();
+                self.ctx.clear_fns();
                 w.write_fmt(format_args!("Definitions cleared.\n"))?;
             }
             Ok(InterpCommand::Reset) => {
+                let observer = self.observer.take();
                 *self = Self::default();
+                self.observer = observer;
                 w.write_fmt(format_args!("Reset.\n"))?;
             }
             Ok(InterpCommand::Help) => {
                 w.write_all(HELP.as_bytes())?;
             }
         }
-        w.flush()
+        Ok(w.flush()?)
     }
 
-    pub fn interp_step(&mut self, w: &mut dyn io::Write) -> io::Result<()> {
+    pub fn interp_step(&mut self, w: &mut dyn io::Write) -> Result<(), InterpError> {
         match self.command.take() {
             Some(InterpCommand::Eval(mut is)) => {
                 if !is.is_empty() {
                     match is.remove(0) {
-                        InterpItem::FnDef(fn_def) => {
-                            let name = fn_def.0.resolve(&self.ctx.interner);
-                            if let Some(_) = self.ctx.define_fn(fn_def) {
-                                w.write_fmt(format_args!("Redefined `{}`.\n", name))?;
-                            } else {
-                                w.write_fmt(format_args!("Defined `{}`.\n", name))?;
+                        (InterpItem::FnDef(fn_def), _) => {
+                            let name = fn_def.0.resolve(&self.ctx);
+                            match self.ctx.define_fn(fn_def) {
+                                Ok(Some(_)) => {
+                                    w.write_fmt(format_args!("Redefined `{}`.\n", name))?
+                                }
+                                Ok(None) => w.write_fmt(format_args!("Defined `{}`.\n", name))?,
+                                Err(err) => {
+                                    w.write_fmt(format_args!(
+                                        "Type error: {}\n",
+                                        err.resolve(&self.ctx)
+                                    ))?;
+                                    self.abandon(w)?;
+                                    return Ok(w.flush()?);
+                                }
                             }
                         }
-                        InterpItem::Expr(mut e) => {
+                        (InterpItem::Clear, _) => {
+                            self.ctx.clear_fns();
+                            w.write_fmt(format_args!("Definitions cleared.\n"))?;
+                        }
+                        (InterpItem::Expr(mut e), spans) => {
                             if self.is_first_eval_step {
+                                if self.typecheck {
+                                    if let Err(err) = TypeChecker::new(&self.ctx).infer(&e) {
+                                        w.write_fmt(format_args!(
+                                            "Type error: {}\n",
+                                            err.resolve(&self.ctx)
+                                        ))?;
+                                        self.abandon(w)?;
+                                        return Ok(w.flush()?);
+                                    }
+                                }
+                                if self.ctx.linearity() {
+                                    if let Err((i, err)) = self.ctx.check_linearity(&self.vs, &e) {
+                                        let diagnostic = Diagnostic {
+                                            span: spans[i],
+                                            message: format!(
+                                                "Linearity error: {}",
+                                                err.resolve(&self.ctx)
+                                            ),
+                                            notes: vec![format!(
+                                                "stack: {}",
+                                                self.vs.resolve(&self.ctx)
+                                            )],
+                                        };
+                                        w.write_all(diagnostic.render(&self.source).as_bytes())?;
+                                        self.abandon(w)?;
+                                        return Ok(w.flush()?);
+                                    }
+                                }
+                                self.remaining = spans.len();
+                                self.calls.clear();
                                 w.write_fmt(format_args!(
                                     "{} {}\n",
-                                    self.vs.resolve(&self.ctx.interner),
-                                    e.resolve(&self.ctx.interner)
+                                    self.vs.resolve(&self.ctx),
+                                    e.resolve(&self.ctx)
                                 ))?;
                             }
                             if e != Expr::default() {
-                                if let Err(err) = self.ctx.small_step(&mut self.vs, &mut e) {
+                                if let Err(err) = self.small_step(&mut e, &mut ()) {
                                     w.write_fmt(format_args!(
                                         "⇓ {} {}\n",
-                                        self.vs.resolve(&self.ctx.interner),
-                                        e.resolve(&self.ctx.interner)
+                                        self.vs.resolve(&self.ctx),
+                                        e.resolve(&self.ctx)
                                     ))?;
-                                    // TODO: better error messages
-                                    w.write_fmt(format_args!(
-                                        "{:?}\n",
-                                        err.resolve(&self.ctx.interner)
-                                    ))?;
-                                    return w.flush();
+                                    self.write_eval_error(w, &err, &spans, &e)?;
+                                    self.abandon(w)?;
+                                    return Ok(w.flush()?);
                                 } else {
-                                    self.ctx.compress(&mut self.vs);
-                                    is.insert(0, InterpItem::Expr(e));
+                                    if self.compression != Compression::Deep {
+                                        self.compress(&e, &mut ());
+                                    }
+                                    is.insert(0, (InterpItem::Expr(e), spans));
                                     self.is_first_eval_step = false;
                                 }
                             } else {
+                                if self.compression == Compression::Deep {
+                                    self.compress(&e, &mut ());
+                                }
                                 w.write_fmt(format_args!(
                                     "⇓ {} {}\n",
-                                    self.vs.resolve(&self.ctx.interner),
-                                    e.resolve(&self.ctx.interner)
+                                    self.vs.resolve(&self.ctx),
+                                    e.resolve(&self.ctx)
                                 ))?;
                                 self.is_first_eval_step = true;
                             }
                         }
                     }
                     self.command = Some(InterpCommand::Eval(is));
+                } else {
+                    self.load_next(w)?;
                 }
             }
-            Some(InterpCommand::Trace(mut e)) => {
+            Some(InterpCommand::Trace(mut e, spans)) => {
                 if e != Expr::default() {
-                    if let Err(err) = self.ctx.small_step(&mut self.vs, &mut e) {
-                        // TODO: better error messages
-                        w.write_fmt(format_args!("{:?}\n", err.resolve(&self.ctx.interner)))?;
-                        return w.flush();
+                    let mut tracer = Tracer { w, result: Ok(()) };
+                    let result = self.small_step(&mut e, &mut tracer);
+                    if result.is_ok() {
+                        self.compress(&e, &mut tracer);
                     }
-                    // TODO: show function expansion as equality, not as small step?
-                    w.write_fmt(format_args!(
-                        "⟶ {} {}\n",
-                        self.vs.resolve(&self.ctx.interner),
-                        e.resolve(&self.ctx.interner)
-                    ))?;
-                    if self.ctx.compress(&mut self.vs) {
-                        w.write_fmt(format_args!(
-                            "= {} {}\n",
-                            self.vs.resolve(&self.ctx.interner),
-                            e.resolve(&self.ctx.interner)
-                        ))?;
+                    tracer.result?;
+                    if let Err(err) = result {
+                        self.write_eval_error(w, &err, &spans, &e)?;
+                        self.roll_back(w)?;
+                        return Ok(w.flush()?);
                     }
-                    self.command = Some(InterpCommand::Trace(e));
+                    self.command = Some(InterpCommand::Trace(e, spans));
                 }
             }
-            _ => panic!(),
+            _ => return Err(InterpError::NoCommand),
         }
-        w.flush()
+        Ok(w.flush()?)
+    }
+}
+
+/// The source of the definition of `sym` in `ctx`, with its signature if it
+/// has one.
+fn show_fn(ctx: &Context, sym: Symbol) -> String {
+    let e = ctx.fns[&sym].resolve(ctx);
+    match ctx.signature(sym) {
+        Some(sig) => format!("{{fn {} : {} = {}}}", sym.resolve(ctx), sig.resolve(ctx), e),
+        None => format!("{{fn {} = {}}}", sym.resolve(ctx), e),
+    }
+}
+
+/// Writes each small step and compression as it happens, for `:trace`.
+struct Tracer<'a> {
+    w: &'a mut dyn io::Write,
+    /// The result of the first write that failed, if any
+    result: io::Result<()>,
+}
+
+impl Tracer<'_> {
+    fn write_state(&mut self, relation: &str, ctx: &Context, vs: &ValueStack, e: &Expr) {
+        if self.result.is_ok() {
+            self.result = self.w.write_fmt(format_args!(
+                "{} {} {}\n",
+                relation,
+                vs.resolve(ctx),
+                e.resolve(ctx)
+            ));
+        }
+    }
+}
+
+impl EvalObserver for Tracer<'_> {
+    // TODO: show function expansion as equality, not as small step?
+    fn step(&mut self, ctx: &Context, _step: Step, vs: &ValueStack, e: &Expr) {
+        self.write_state("⟶", ctx, vs, e);
+    }
+
+    fn compressed(&mut self, ctx: &Context, vs: &ValueStack, e: &Expr) {
+        self.write_state("=", ctx, vs, e);
     }
 }
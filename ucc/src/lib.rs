@@ -8,15 +8,49 @@ pub mod core;
 #[cfg(test)]
 mod test_core;
 
+mod arena;
+#[cfg(test)]
+mod test_arena;
+
 mod builtin;
 
 pub mod display;
+#[cfg(test)]
+mod test_display;
+
+pub mod machine;
+#[cfg(test)]
+mod test_machine;
 
 pub mod interp;
 #[cfg(test)]
 mod test_interp;
 
+pub mod diagnostic;
+#[cfg(test)]
+mod test_diagnostic;
+
+pub mod assertion;
+#[cfg(test)]
+mod test_assertion;
+
+pub mod observer;
+#[cfg(test)]
+mod test_observer;
+
+pub mod types;
+#[cfg(test)]
+mod test_types;
+
+pub mod effect;
+#[cfg(test)]
+mod test_effect;
+
+pub mod linearity;
+#[cfg(test)]
+mod test_linearity;
+
 use lalrpop_util::lalrpop_mod;
-lalrpop_mod!(pub parse);
+lalrpop_mod!(#[allow(clippy::all, dead_code, unused_imports)] pub parse);
 #[cfg(test)]
 mod test_parse;
@@ -0,0 +1,64 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::{Context, EvalError, Expr, Intrinsic, Usage, ValueStack};
+
+impl Context {
+    /// Check, before evaluating `e` on `vs`, that it doesn't clone or drop a
+    /// value whose usage forbids it. On failure, returns the index of the
+    /// offending intrinsic among the elements of `e`, and the error that
+    /// evaluation would fail with.
+    ///
+    /// The usage of each value is followed through the elements of `e` up to
+    /// the first whose effect depends on the values it is applied to, i.e.
+    /// `apply`, a native function, or a function that isn't defined as a
+    /// quotation, or up to the first that needs more values than there are.
+    /// The rest is left to be checked as it is evaluated.
+    pub fn check_linearity(&self, vs: &ValueStack, e: &Expr) -> Result<(), (usize, EvalError)> {
+        let mut usages: Vec<Usage> = vs.values().iter().map(|v| v.usage()).collect();
+        let es = match e {
+            Expr::Compose(es) => &es[..],
+            e => std::slice::from_ref(e),
+        };
+        for (i, e) in es.iter().enumerate() {
+            let restricted =
+                |intrinsic, usage| Err((i, EvalError::Restricted { intrinsic, usage }));
+            let len = usages.len();
+            match e {
+                Expr::Quote(_) => usages.push(Usage::Unrestricted),
+                Expr::Pinned(_, _, pe) if matches!(**pe, Expr::Quote(_)) => {
+                    usages.push(Usage::Unrestricted)
+                }
+                Expr::Call(sym) if matches!(self.fns.get(sym), Some(Expr::Quote(_))) => {
+                    usages.push(Usage::Unrestricted)
+                }
+                Expr::Intrinsic(Intrinsic::Swap) if len >= 2 => usages.swap(len - 2, len - 1),
+                Expr::Intrinsic(Intrinsic::Clone) if len >= 1 => match usages[len - 1] {
+                    Usage::Unrestricted => usages.push(Usage::Unrestricted),
+                    usage => return restricted(Intrinsic::Clone, usage),
+                },
+                Expr::Intrinsic(Intrinsic::Drop) if len >= 1 => {
+                    if usages.pop() == Some(Usage::Linear) {
+                        return restricted(Intrinsic::Drop, Usage::Linear);
+                    }
+                }
+                Expr::Intrinsic(Intrinsic::Quote) if len >= 1 => {}
+                Expr::Intrinsic(Intrinsic::Compose) if len >= 2 => {
+                    let usage = usages.pop().unwrap();
+                    usages[len - 2] = usages[len - 2].max(usage);
+                }
+                Expr::Intrinsic(Intrinsic::Linear) if len >= 1 => {
+                    usages[len - 1] = Usage::Linear;
+                }
+                Expr::Intrinsic(Intrinsic::Affine) if len >= 1 => {
+                    usages[len - 1] = usages[len - 1].max(Usage::Affine);
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::*;
+use std::rc::Rc;
+
+/// Instructions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Instr {
+    Intrinsic(Intrinsic),
+    Call(Symbol),
+    Pinned(Symbol, Version, Rc<Expr>),
+    Quote(Rc<Expr>),
+    With(Symbol, Code),
+    Push(Symbol),
+    Pop(Symbol),
+}
+
+/// Flattened, shared code
+pub(crate) type Code = Rc<[Instr]>;
+
+/// The compiled form of a function definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Compiled {
+    /// The body of a function whose definition is not a quotation.
+    Code(Code),
+    /// The body of the quotation that a function is defined as.
+    Quote(Code),
+}
+
+thread_local! {
+    static EMPTY_CODE: Code = Rc::new([]);
+}
+
+impl Drop for Instr {
+    fn drop(&mut self) {
+        let mut codes = vec![];
+        self.take_code(&mut codes);
+        while let Some(mut code) = codes.pop() {
+            if let Some(instrs) = Rc::get_mut(&mut code) {
+                for instr in instrs {
+                    instr.take_code(&mut codes);
+                }
+            }
+        }
+    }
+}
+
+impl Instr {
+    /// Move the code that would be dropped along with this instruction into `codes`.
+    fn take_code(&mut self, codes: &mut Vec<Code>) {
+        if let Instr::With(_, code) = self {
+            if Rc::strong_count(code) == 1 {
+                if let Ok(empty) = EMPTY_CODE.try_with(Rc::clone) {
+                    codes.push(std::mem::replace(code, empty));
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn compile(e: &Expr) -> Code {
+    enum Task<'a> {
+        Compile(&'a Expr),
+        With(Symbol),
+    }
+    // The code of each stack context that is being compiled, innermost last.
+    let mut codes = vec![vec![]];
+    let mut tasks = vec![Task::Compile(e)];
+    while let Some(task) = tasks.pop() {
+        let code = codes.last_mut().unwrap();
+        match task {
+            Task::Compile(Expr::Intrinsic(intr)) => code.push(Instr::Intrinsic(*intr)),
+            Task::Compile(Expr::Call(sym)) => code.push(Instr::Call(*sym)),
+            Task::Compile(Expr::Pinned(sym, version, pe)) => {
+                code.push(Instr::Pinned(*sym, *version, pe.clone()))
+            }
+            Task::Compile(Expr::Quote(qe)) => code.push(Instr::Quote(qe.clone())),
+            Task::Compile(Expr::Compose(children)) => {
+                tasks.extend(children.iter().rev().map(Task::Compile))
+            }
+            Task::Compile(Expr::With(sym, e)) => {
+                codes.push(vec![]);
+                tasks.push(Task::With(*sym));
+                tasks.push(Task::Compile(e));
+            }
+            Task::Compile(Expr::Push(sym)) => code.push(Instr::Push(*sym)),
+            Task::Compile(Expr::Pop(sym)) => code.push(Instr::Pop(*sym)),
+            Task::With(sym) => {
+                let with_code = codes.pop().unwrap();
+                let code = codes.last_mut().unwrap();
+                code.push(Instr::With(sym, with_code.into()));
+            }
+        }
+    }
+    codes.pop().unwrap().into()
+}
+
+pub(crate) fn compile_fn(e: &Expr) -> Compiled {
+    match e {
+        Expr::Quote(qe) => Compiled::Quote(compile(qe)),
+        e => Compiled::Code(compile(e)),
+    }
+}
+
+/// An abstract machine for evaluating expressions.
+///
+/// Unlike `Context::small_step`, which rewrites the expression being evaluated,
+/// the machine keeps a stack of continuation frames, each of which is an
+/// instruction pointer into shared code. Calling a function pushes a frame
+/// pointing to its precompiled body instead of copying it, so each step costs
+/// O(1), except for `apply` on a quotation literal, which must compile it, and
+/// for a pinned call to a function that isn't defined as a quotation, which
+/// must compile the expression it holds.
+///
+/// Each frame also holds the named stack that its code is evaluated against,
+/// if any, which the frames it pushes inherit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Machine {
+    frames: Vec<(Code, usize, Option<Symbol>)>,
+}
+
+impl Machine {
+    pub fn new(e: &Expr) -> Self {
+        let mut m = Machine { frames: vec![] };
+        m.push(compile(e), None);
+        m
+    }
+
+    fn push(&mut self, code: Code, current: Option<Symbol>) {
+        if !code.is_empty() {
+            self.frames.push((code, 0, current));
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// The expression that remains to be evaluated. The code of consecutive
+    /// frames that are evaluated against the same named stack is put in one
+    /// stack context.
+    pub fn remaining(&self) -> Expr {
+        let mut es = vec![];
+        let mut run: Option<(Symbol, Vec<Expr>)> = None;
+        for (code, ip, current) in self.frames.iter().rev() {
+            if run.as_ref().map(|(sym, _)| *sym) != *current {
+                if let Some((sym, run_es)) = run.take() {
+                    es.push(Expr::With(sym, Box::new(Expr::compose(run_es))));
+                }
+                run = current.map(|sym| (sym, vec![]));
+            }
+            let target = match &mut run {
+                Some((_, run_es)) => run_es,
+                None => &mut es,
+            };
+            target.extend(code[*ip..].iter().map(decompile));
+        }
+        if let Some((sym, run_es)) = run {
+            es.push(Expr::With(sym, Box::new(Expr::compose(run_es))));
+        }
+        if es.len() == 1 {
+            es.drain(..).next().unwrap()
+        } else {
+            Expr::Compose(es)
+        }
+    }
+
+    /// Execute a single instruction. If it fails, the instruction is left in
+    /// place, as with `Context::small_step`. Entering a stack context isn't
+    /// an instruction of its own.
+    pub fn step(&mut self, ctx: &mut Context, vs: &mut ValueStack) -> Result<(), EvalError> {
+        let (instr, current) = loop {
+            match self.frames.last() {
+                None => return Ok(()),
+                Some((code, ip, current)) => match &code[*ip] {
+                    Instr::With(sym, code) => {
+                        let next = (code.clone(), Some(*sym));
+                        self.advance(Some(next));
+                    }
+                    instr => break (instr.clone(), *current),
+                },
+            }
+        };
+        let new_code = match instr {
+            Instr::Push(sym) => {
+                ctx.step_push(vs, current, sym)?;
+                None
+            }
+            Instr::Pop(sym) => {
+                ctx.step_pop(vs, current, sym)?;
+                None
+            }
+            instr => ctx.focus(vs, current, |ctx, vs| step_focused(ctx, vs, instr))?,
+        };
+        self.advance(new_code.map(|code| (code, current)));
+        Ok(())
+    }
+
+    /// Move past the current instruction, then push `next`, if any.
+    fn advance(&mut self, next: Option<(Code, Option<Symbol>)>) {
+        // Pop the current frame once it is finished, before pushing the next,
+        // so that tail calls don't grow the frame stack.
+        let (code, ip, _) = self.frames.last_mut().unwrap();
+        *ip += 1;
+        if *ip == code.len() {
+            self.frames.pop();
+        }
+        if let Some((code, current)) = next {
+            self.push(code, current);
+        }
+    }
+
+    /// Run until done or until `fuel` instructions have been executed.
+    pub fn run(
+        &mut self,
+        ctx: &mut Context,
+        vs: &mut ValueStack,
+        fuel: Fuel,
+    ) -> Result<Outcome, EvalError> {
+        for _ in 0..fuel.0 {
+            if self.is_done() {
+                return Ok(Outcome::Normal);
+            }
+            self.step(ctx, vs)?;
+        }
+        if self.is_done() {
+            Ok(Outcome::Normal)
+        } else {
+            Ok(Outcome::OutOfFuel(self.remaining()))
+        }
+    }
+}
+
+/// Execute an instruction other than a stack context, push or pop, against
+/// the main stack. Returns the code that execution continues with, if any.
+fn step_focused(
+    ctx: &mut Context,
+    vs: &mut ValueStack,
+    instr: Instr,
+) -> Result<Option<Code>, EvalError> {
+    Ok(match instr {
+        Instr::Intrinsic(Intrinsic::Apply) => match vs.0.last() {
+            None => {
+                return Err(EvalError::TooFewValues {
+                    intrinsic: Intrinsic::Apply,
+                    available: 0,
+                    expected: 1,
+                })
+            }
+            Some(v) => {
+                ctx.check_quotation(v)?;
+                match vs.0.pop().unwrap() {
+                    Value::Call(sym, version) => match ctx.code.get(&sym) {
+                        Some(Compiled::Quote(code)) if version == ctx.version(sym) => {
+                            Some(code.clone())
+                        }
+                        _ => Some(compile(&ctx.unquote_value(Value::Call(sym, version))?)),
+                    },
+                    v => Some(compile(&ctx.unquote_value(v)?)),
+                }
+            }
+        },
+        Instr::Intrinsic(intr) => {
+            ctx.step_intrinsic(vs, intr)?;
+            None
+        }
+        Instr::Call(sym) => match ctx.code.get(&sym) {
+            Some(Compiled::Quote(_)) => {
+                vs.0.push(Value::Call(sym, ctx.version(sym)));
+                None
+            }
+            Some(Compiled::Code(code)) => Some(code.clone()),
+            None if ctx.natives.contains_key(&sym) => {
+                ctx.call_native(vs, sym)?;
+                None
+            }
+            None => return Err(EvalError::UndefinedFn(sym)),
+        },
+        Instr::Pinned(sym, version, ref pe) => match &**pe {
+            Expr::Quote(qe) => {
+                vs.0.push(ctx.pinned_value(sym, version, qe));
+                None
+            }
+            pe => Some(compile(pe)),
+        },
+        Instr::Quote(ref qe) => {
+            vs.0.push(Value::Quote(qe.clone()));
+            None
+        }
+        Instr::With(..) | Instr::Push(_) | Instr::Pop(_) => unreachable!(),
+    })
+}
+
+/// The expression that `instr` was compiled from.
+fn decompile(instr: &Instr) -> Expr {
+    enum Task<'a> {
+        Decompile(&'a Instr),
+        With(Symbol, usize),
+    }
+    let mut tasks = vec![Task::Decompile(instr)];
+    let mut es = vec![];
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Decompile(Instr::Intrinsic(intr)) => es.push(Expr::Intrinsic(*intr)),
+            Task::Decompile(Instr::Call(sym)) => es.push(Expr::Call(*sym)),
+            Task::Decompile(Instr::Pinned(sym, version, pe)) => {
+                es.push(Expr::Pinned(*sym, *version, pe.clone()))
+            }
+            Task::Decompile(Instr::Quote(qe)) => es.push(Expr::Quote(qe.clone())),
+            Task::Decompile(Instr::With(sym, code)) => {
+                tasks.push(Task::With(*sym, code.len()));
+                tasks.extend(code.iter().rev().map(Task::Decompile));
+            }
+            Task::Decompile(Instr::Push(sym)) => es.push(Expr::Push(*sym)),
+            Task::Decompile(Instr::Pop(sym)) => es.push(Expr::Pop(*sym)),
+            Task::With(sym, len) => {
+                let children = es.split_off(es.len() - len);
+                es.push(Expr::With(sym, Box::new(Expr::compose(children))));
+            }
+        }
+    }
+    es.pop().unwrap()
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::{Context, EvalError, Expr, Intrinsic, Symbol, ValueStack};
+
+/// What a small step did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// An intrinsic was applied to the value stack.
+    Intrinsic(Intrinsic),
+    /// A function was called, unfolding its definition or, if it is defined
+    /// as a quotation, pushing a call to it.
+    Call(Symbol),
+    /// A native function was called.
+    Native(Symbol),
+    /// A quotation was pushed.
+    Quote,
+    /// A value was pushed onto a named stack.
+    Push(Symbol),
+    /// A value was popped from a named stack.
+    Pop(Symbol),
+}
+
+/// Hooks into small-step evaluation, e.g. for tracing, profiling or
+/// debugging. Each is given the context along with the state of evaluation
+/// after the event, and does nothing by default.
+pub trait EvalObserver {
+    /// A small step was taken.
+    fn step(&mut self, _ctx: &Context, _step: Step, _vs: &ValueStack, _e: &Expr) {}
+
+    /// The value stack was compressed, replacing quotations with calls.
+    fn compressed(&mut self, _ctx: &Context, _vs: &ValueStack, _e: &Expr) {}
+
+    /// A small step failed, leaving the state unchanged.
+    fn error(&mut self, _ctx: &Context, _err: &EvalError, _vs: &ValueStack, _e: &Expr) {}
+}
+
+/// Observe nothing.
+impl EvalObserver for () {}
+
+impl<T: EvalObserver + ?Sized> EvalObserver for &mut T {
+    fn step(&mut self, ctx: &Context, step: Step, vs: &ValueStack, e: &Expr) {
+        (**self).step(ctx, step, vs, e)
+    }
+
+    fn compressed(&mut self, ctx: &Context, vs: &ValueStack, e: &Expr) {
+        (**self).compressed(ctx, vs, e)
+    }
+
+    fn error(&mut self, ctx: &Context, err: &EvalError, vs: &ValueStack, e: &Expr) {
+        (**self).error(ctx, err, vs, e)
+    }
+}
+
+impl<T: EvalObserver + ?Sized> EvalObserver for Box<T> {
+    fn step(&mut self, ctx: &Context, step: Step, vs: &ValueStack, e: &Expr) {
+        (**self).step(ctx, step, vs, e)
+    }
+
+    fn compressed(&mut self, ctx: &Context, vs: &ValueStack, e: &Expr) {
+        (**self).compressed(ctx, vs, e)
+    }
+
+    fn error(&mut self, ctx: &Context, err: &EvalError, vs: &ValueStack, e: &Expr) {
+        (**self).error(ctx, err, vs, e)
+    }
+}
+
+impl<T: EvalObserver> EvalObserver for Option<T> {
+    fn step(&mut self, ctx: &Context, step: Step, vs: &ValueStack, e: &Expr) {
+        if let Some(o) = self {
+            o.step(ctx, step, vs, e)
+        }
+    }
+
+    fn compressed(&mut self, ctx: &Context, vs: &ValueStack, e: &Expr) {
+        if let Some(o) = self {
+            o.compressed(ctx, vs, e)
+        }
+    }
+
+    fn error(&mut self, ctx: &Context, err: &EvalError, vs: &ValueStack, e: &Expr) {
+        if let Some(o) = self {
+            o.error(ctx, err, vs, e)
+        }
+    }
+}
+
+/// Notify both observers, first then second.
+impl<A: EvalObserver, B: EvalObserver> EvalObserver for (A, B) {
+    fn step(&mut self, ctx: &Context, step: Step, vs: &ValueStack, e: &Expr) {
+        self.0.step(ctx, step, vs, e);
+        self.1.step(ctx, step, vs, e);
+    }
+
+    fn compressed(&mut self, ctx: &Context, vs: &ValueStack, e: &Expr) {
+        self.0.compressed(ctx, vs, e);
+        self.1.compressed(ctx, vs, e);
+    }
+
+    fn error(&mut self, ctx: &Context, err: &EvalError, vs: &ValueStack, e: &Expr) {
+        self.0.error(ctx, err, vs, e);
+        self.1.error(ctx, err, vs, e);
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::arena::*;
+use crate::core::*;
+use crate::parse::*;
+use std::rc::Rc;
+
+#[test]
+fn test_intern() {
+    let cases = [
+        ("", ""),
+        ("swap", "swap"),
+        ("foo bar", "foo bar"),
+        ("[foo] [[bar]]", "[foo] [[bar]]"),
+        ("[foo] ([bar] baz)", "[foo] ([bar] baz)"),
+    ];
+    let ctx = &mut Context::default();
+    let mut arena = ExprArena::default();
+    for (src1, src2) in cases {
+        let e1 = Rc::new(ExprParser::new().parse(ctx, src1).unwrap());
+        let e2 = Rc::new(ExprParser::new().parse(ctx, src2).unwrap());
+        assert!(!Rc::ptr_eq(&e1, &e2));
+        let (c1, id1) = arena.intern(&e1);
+        let (c2, id2) = arena.intern(&e2);
+        assert_eq!(c1, e1, "Failed on {}", src1);
+        assert!(Rc::ptr_eq(&c1, &c2), "Failed on {}", src1);
+        assert_eq!(id1, id2, "Failed on {}", src1);
+        assert_eq!(arena.id(&c1), Some(id1), "Failed on {}", src1);
+    }
+}
+
+#[test]
+fn test_intern_distinct() {
+    let srcs = [
+        "",
+        "swap",
+        "[swap]",
+        "[[swap]]",
+        "foo",
+        "[foo]",
+        "foo bar",
+        "foo [bar]",
+    ];
+    let ctx = &mut Context::default();
+    let mut arena = ExprArena::default();
+    let mut nodes = vec![];
+    for src in srcs {
+        let e = Rc::new(ExprParser::new().parse(ctx, src).unwrap());
+        nodes.push(arena.intern(&e));
+    }
+    for (i, (_, id1)) in nodes.iter().enumerate() {
+        for (j, (_, id2)) in nodes.iter().enumerate() {
+            assert_eq!(id1 == id2, i == j, "Failed on {} and {}", srcs[i], srcs[j]);
+        }
+    }
+}
+
+#[test]
+fn test_intern_shares_children() {
+    let ctx = &mut Context::default();
+    let mut arena = ExprArena::default();
+    let e1 = Rc::new(ExprParser::new().parse(ctx, "[foo]").unwrap());
+    let e2 = Rc::new(ExprParser::new().parse(ctx, "[[foo] bar]").unwrap());
+    let (c1, _) = arena.intern(&e1);
+    let (c2, _) = arena.intern(&e2);
+    match (&*c1, &*c2) {
+        (Expr::Quote(qe1), Expr::Quote(qe2)) => match &**qe2 {
+            Expr::Compose(es) => match &es[0] {
+                Expr::Quote(qe3) => assert!(Rc::ptr_eq(qe1, qe3)),
+                e => panic!("Expected a quotation, found {:?}", e),
+            },
+            e => panic!("Expected a composition, found {:?}", e),
+        },
+        e => panic!("Expected quotations, found {:?}", e),
+    }
+}
+
+#[test]
+fn test_find_compose() {
+    let ctx = &mut Context::default();
+    let mut arena = ExprArena::default();
+    let e = Rc::new(ExprParser::new().parse(ctx, "foo [bar] (baz qux)").unwrap());
+    let (c, id) = arena.intern(&e);
+    match &*c {
+        Expr::Compose(es) => {
+            assert_eq!(arena.find_compose(es), Some(id));
+            assert_eq!(arena.find_compose(&es[..2]), None);
+            assert_eq!(arena.find_compose(&es[1..]), None);
+        }
+        e => panic!("Expected a composition, found {:?}", e),
+    }
+}
+
+#[test]
+fn test_prune() {
+    let ctx = &mut Context::default();
+    let mut arena = ExprArena::default();
+    let kept = Rc::new(ExprParser::new().parse(ctx, "kept").unwrap());
+    let (kept, kept_id) = arena.intern(&kept);
+    for i in 0..10_000 {
+        let sym = Symbol(ctx.interner.get_or_intern(format!("e{}", i)));
+        arena.intern(&Rc::new(Expr::Quote(Rc::new(Expr::Call(sym)))));
+    }
+    assert!(arena.len() < 5000, "{} entries", arena.len());
+    assert_eq!(arena.id(&kept), Some(kept_id));
+}
+
+#[test]
+fn test_intern_deep() {
+    const DEEP: usize = 1_000_000;
+    let ctx = &mut Context::default();
+    let sym = Symbol(ctx.interner.get_or_intern_static("e"));
+    let mut arena = ExprArena::default();
+    let mut e1 = Expr::Call(sym);
+    let mut e2 = Expr::Call(sym);
+    for _ in 0..DEEP {
+        e1 = Expr::Quote(Rc::new(e1));
+        e2 = Expr::Compose(vec![Expr::Quote(Rc::new(e2)), Expr::Call(sym)]);
+    }
+    let e1 = Rc::new(e1);
+    let (c1, id1) = arena.intern(&e1);
+    assert!(Rc::ptr_eq(&e1, &c1));
+    assert_eq!(arena.intern(&Rc::new((*e1).clone())).1, id1);
+    arena.intern(&Rc::new(e2));
+}
@@ -0,0 +1,53 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::assertion::*;
+use crate::builtin::FN_DEF_SRCS;
+use crate::core::*;
+use crate::parse::*;
+
+#[test]
+fn test_check() {
+    let cases = [
+        ("⟨[a] [b]⟩ swap ⟶ ⟨[b] [a]⟩", None),
+        ("⟨⟩ [a] [b] swap ⟶ ⟨[a]⟩ [b] swap", None),
+        ("⟨⟩ [a] [b] swap ⇓ ⟨[b] [a]⟩", None),
+        ("⟨⟩ true [a] [b] ⇓ ⟨true⟩ [a] [b]", None),
+        (
+            "⟨⟩ [clone apply] clone apply ⇓ ⟨⟩ [clone apply] clone apply",
+            None,
+        ),
+        ("⟨⟩ [[clone]] apply ⇓ ⟨[clone]⟩", None),
+        ("⟨⟩ true ⇓ ⟨[swap drop]⟩", None),
+        ("⟨[swap drop]⟩ ⇓ ⟨true⟩", None),
+        ("⟨⟩ true ⇓ ⟨[drop]⟩", Some("mismatch")),
+        ("⟨⟩ n0 succ ⇓ ⟨n1⟩", None),
+        ("⟨⟩ n1 n1 add ⇓ ⟨n2⟩", None),
+        ("⟨⟩ drop ⇓ ⟨⟩", Some("eval")),
+        ("⟨⟩ [clone apply] clone apply ⇓ ⟨⟩", Some("fuel")),
+        ("⟨true⟩ {$s push} ⟶ ⟨⟩ $s⟨[swap drop]⟩", None),
+        ("⟨⟩ $s⟨[a]⟩ $t⟨[b]⟩ {$s {$t pop}} ⇓ ⟨⟩ $s⟨[a] [b]⟩", None),
+        ("⟨[a]⟩ {$s push} ⇓ ⟨⟩ $t⟨[a]⟩", Some("mismatch")),
+        ("⟨[a]⟩ {$s push} ⇓ ⟨⟩ $s⟨[a] [a]⟩", Some("mismatch")),
+        ("⟨⟩ {$s pop} ⇓ ⟨⟩", Some("eval")),
+    ];
+    let mut ctx = Context::default();
+    for fn_def_src in FN_DEF_SRCS.iter() {
+        let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    let parser = AssertionParser::new();
+    for (src, expected) in cases {
+        let assertion = parser.parse(&mut ctx, src).unwrap();
+        let result = match assertion.check(&mut ctx, Fuel(1000)) {
+            Ok(()) => None,
+            Err(AssertionFailure::Mismatch { .. }) => Some("mismatch"),
+            Err(AssertionFailure::Eval(..)) => Some("eval"),
+            Err(AssertionFailure::OutOfFuel(..)) => Some("fuel"),
+        };
+        assert_eq!(result, expected, "Failed on {}", src);
+    }
+}
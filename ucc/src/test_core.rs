@@ -7,7 +7,11 @@
 use crate::builtin::FN_DEF_SRCS;
 use crate::core::*;
 use crate::display::*;
+use crate::machine::Machine;
 use crate::parse::*;
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
 
 #[test]
 fn test_small_step() {
@@ -22,7 +26,7 @@ fn test_small_step() {
     for case in cases {
         let mut ctx = Context::default();
         let mut ssa = SmallStepAssertionParser::new()
-            .parse(&mut ctx.interner, case)
+            .parse(&mut ctx, case)
             .unwrap();
         let result = ctx.small_step(&mut ssa.0, &mut ssa.1);
         assert_eq!(result, Ok(()), "Failed on {}", case);
@@ -60,21 +64,62 @@ fn test_compress() {
     for (input_src, expected_src, expected_result) in cases {
         let mut ctx = Context::default();
         for fn_def_src in FN_DEF_SRCS.iter() {
-            let fn_def = FnDefParser::new()
-                .parse(&mut ctx.interner, fn_def_src)
-                .unwrap();
-            assert_eq!(ctx.define_fn(fn_def), None);
+            let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+            assert_eq!(ctx.define_fn(fn_def), Ok(None));
         }
-        let mut input = ValueStackParser::new()
-            .parse(&mut ctx.interner, input_src)
-            .unwrap();
+        let mut input = ValueStackParser::new().parse(&mut ctx, input_src).unwrap();
         let expected = ValueStackParser::new()
-            .parse(&mut ctx.interner, expected_src)
+            .parse(&mut ctx, expected_src)
             .unwrap();
         let result = ctx.compress(&mut input);
         assert_eq!(
-            (input.resolve(&ctx.interner), result),
-            (expected.resolve(&ctx.interner), expected_result),
+            (input.resolve(&ctx), result),
+            (expected.resolve(&ctx), expected_result),
+            "Failed on ({}, {})",
+            input_src,
+            expected_src
+        );
+    }
+}
+
+#[test]
+fn test_compress_deep() {
+    let cases = [
+        ("⟨[swap drop]⟩", "⟨true⟩", true),
+        ("⟨[x]⟩", "⟨[x]⟩", false),
+        ("⟨[[swap drop] [drop]]⟩", "⟨[true n0]⟩", true),
+        ("⟨[x [[swap drop]]]⟩", "⟨[x [true]]⟩", true),
+        ("⟨[x clone apply y]⟩", "⟨[x or y]⟩", true),
+        (
+            "⟨[quote swap quote swap compose swap quote compose apply]⟩",
+            "⟨[rotate3]⟩",
+            true,
+        ),
+        (
+            "⟨[[clone] [drop] apply [compose] [drop] apply apply]⟩",
+            "⟨n1⟩",
+            true,
+        ),
+        (
+            "⟨[[[clone] [drop] apply [compose] [drop] apply apply]]⟩",
+            "⟨[n1]⟩",
+            true,
+        ),
+    ];
+    for (input_src, expected_src, expected_result) in cases {
+        let mut ctx = Context::default();
+        for fn_def_src in FN_DEF_SRCS.iter() {
+            let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+            assert_eq!(ctx.define_fn(fn_def), Ok(None));
+        }
+        let mut input = ValueStackParser::new().parse(&mut ctx, input_src).unwrap();
+        let expected = ValueStackParser::new()
+            .parse(&mut ctx, expected_src)
+            .unwrap();
+        let result = ctx.compress_deep(&mut input);
+        assert_eq!(
+            (input.resolve(&ctx), result),
+            (expected.resolve(&ctx), expected_result),
             "Failed on ({}, {})",
             input_src,
             expected_src
@@ -82,25 +127,116 @@ fn test_compress() {
     }
 }
 
+#[test]
+fn test_compress_redefined() {
+    let mut ctx = Context::default();
+    for fn_def_src in [
+        "{fn f = [a]}",
+        "{fn g = a b}",
+        "{fn f = [b]}",
+        "{fn g = b a}",
+    ] {
+        let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    let mut vs = ValueStackParser::new()
+        .parse(&mut ctx, "⟨[a] [a b]⟩")
+        .unwrap();
+    let expected = vs.clone();
+    assert!(!ctx.compress_deep(&mut vs));
+    assert_eq!(vs, expected);
+}
+
+#[test]
+fn test_compress_shared_body() {
+    // Redefining one of the functions that share a body keeps the others
+    // compressing.
+    let cases: [(&[&str], &str, &str); 4] = [
+        (&["{fn n0 = [x]}"], "⟨[drop]⟩", "⟨false⟩"),
+        (
+            &["{fn t = [swap drop]}", "{fn t = [drop]}"],
+            "⟨[swap drop] [drop]⟩",
+            "⟨true t⟩",
+        ),
+        (&["{fn or = x}"], "⟨[y clone apply]⟩", "⟨[y clone apply]⟩"),
+        (
+            &["{fn or2 = clone apply}", "{fn or = x}"],
+            "⟨[y clone apply]⟩",
+            "⟨[y or2]⟩",
+        ),
+    ];
+    for (fn_def_srcs, input_src, expected_src) in cases {
+        let mut ctx = Context::default();
+        for fn_def_src in FN_DEF_SRCS.iter().chain(fn_def_srcs) {
+            let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+            ctx.define_fn(fn_def).unwrap();
+        }
+        let mut input = ValueStackParser::new().parse(&mut ctx, input_src).unwrap();
+        ctx.compress_deep(&mut input);
+        assert_eq!(
+            input.resolve(&ctx).to_string(),
+            expected_src,
+            "Failed on {}",
+            input_src
+        );
+    }
+}
+
 #[test]
 fn test_define_fn() {
     let mut ctx = Context::default();
     let sym = Symbol(ctx.interner.get_or_intern_static("foo"));
-    let fn_def1 = FnDefParser::new()
-        .parse(&mut ctx.interner, "{fn foo = e1}")
-        .unwrap();
-    let e1 = ExprParser::new().parse(&mut ctx.interner, "e1").unwrap();
-    let fn_def2 = FnDefParser::new()
-        .parse(&mut ctx.interner, "{fn foo = e2}")
-        .unwrap();
-    let e2 = ExprParser::new().parse(&mut ctx.interner, "e2").unwrap();
+    let fn_def1 = FnDefParser::new().parse(&mut ctx, "{fn foo = e1}").unwrap();
+    let e1 = ExprParser::new().parse(&mut ctx, "e1").unwrap();
+    let fn_def2 = FnDefParser::new().parse(&mut ctx, "{fn foo = e2}").unwrap();
+    let e2 = ExprParser::new().parse(&mut ctx, "e2").unwrap();
     assert_eq!(ctx.fns.get(&sym), None);
-    assert_eq!(ctx.define_fn(fn_def1), None);
+    assert_eq!(ctx.define_fn(fn_def1), Ok(None));
     assert_eq!(ctx.fns.get(&sym), Some(&e1));
-    assert_eq!(ctx.define_fn(fn_def2), Some(FnDef(sym, e1)));
+    assert_eq!(ctx.define_fn(fn_def2), Ok(Some(FnDef(sym, e1, None))));
     assert_eq!(ctx.fns.get(&sym), Some(&e2));
 }
 
+#[test]
+fn test_define_fn_versions() {
+    let mut ctx = Context::default();
+    let f = Symbol(ctx.interner.get_or_intern_static("f"));
+    let define_fn = |ctx: &mut Context, fn_def_src: &str| {
+        let fn_def = FnDefParser::new().parse(ctx, fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    };
+    let eval = |ctx: &mut Context, vs: &mut ValueStack, e_src: &str| {
+        let e = ExprParser::new().parse(ctx, e_src).unwrap();
+        let mut machine_vs = vs.clone();
+        let result = Machine::new(&e).run(ctx, &mut machine_vs, Fuel(10));
+        assert_eq!(ctx.eval(vs, e, Fuel(10)), result);
+        assert_eq!(*vs, machine_vs);
+        result
+    };
+
+    // Redefining a function, even as a non-quotation, doesn't change the
+    // meaning of values that refer to it.
+    define_fn(&mut ctx, "{fn f = [drop]}");
+    let mut vs = ValueStackParser::new().parse(&mut ctx, "⟨x y⟩").unwrap();
+    assert_eq!(eval(&mut ctx, &mut vs, "f"), Ok(Outcome::Normal));
+    assert_eq!(vs.0.last(), Some(&Value::Call(f, ctx.version(f))));
+    let version = ctx.version(f);
+    define_fn(&mut ctx, "{fn f = swap}");
+    assert!(ctx.is_stale(f, version));
+    assert_eq!(eval(&mut ctx, &mut vs, "apply"), Ok(Outcome::Normal));
+    assert_eq!(vs.resolve(&ctx).to_string(), "⟨x⟩");
+
+    // So does clearing it, and quoting a value pins it to its version.
+    define_fn(&mut ctx, "{fn f = [drop]}");
+    assert_eq!(eval(&mut ctx, &mut vs, "f clone"), Ok(Outcome::Normal));
+    ctx.clear_fns();
+    assert_eq!(vs.resolve(&ctx).to_string(), "⟨x f@3 f@3⟩");
+    assert_eq!(eval(&mut ctx, &mut vs, "quote"), Ok(Outcome::Normal));
+    assert_eq!(vs.resolve(&ctx).to_string(), "⟨x f@3 [f@3]⟩");
+    assert_eq!(eval(&mut ctx, &mut vs, "drop apply"), Ok(Outcome::Normal));
+    assert_eq!(vs.resolve(&ctx).to_string(), "⟨⟩");
+}
+
 #[test]
 fn test_big_step() {
     const MAX_SMALL_STEPS: usize = 1000;
@@ -148,30 +284,22 @@ fn test_big_step() {
     ];
     let mut ctx = Context::default();
     for fn_def_src in FN_DEF_SRCS.iter() {
-        let fn_def = FnDefParser::new()
-            .parse(&mut ctx.interner, fn_def_src)
-            .unwrap();
-        assert_eq!(ctx.define_fn(fn_def), None);
+        let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+        assert_eq!(ctx.define_fn(fn_def), Ok(None));
     }
     for case in cases {
         println!("\n{}", case);
-        let mut ssa = BigStepAssertionParser::new()
-            .parse(&mut ctx.interner, case)
-            .unwrap();
+        let mut ssa = BigStepAssertionParser::new().parse(&mut ctx, case).unwrap();
         'eval: for step in 1..=MAX_SMALL_STEPS {
             assert_eq!(
-                ctx.small_step(&mut ssa.0, &mut ssa.1)
-                    .resolve(&ctx.interner),
+                ctx.small_step(&mut ssa.0, &mut ssa.1).resolve(&ctx),
                 Ok(()),
                 "Failed on {}",
                 case
             );
-            println!(
-                "⟶ {} {}",
-                ssa.0.resolve(&ctx.interner),
-                ssa.1.resolve(&ctx.interner)
-            );
-            if ssa.0 == ssa.2 && ssa.1 == ssa.3 {
+            println!("⟶ {} {}", ssa.0.resolve(&ctx), ssa.1.resolve(&ctx));
+            // Quoting a call pins it, which isn't displayed while it's current.
+            if ssa.0.resolve(&ctx) == ssa.2.resolve(&ctx) && ssa.1 == ssa.3 {
                 break 'eval;
             } else if step == MAX_SMALL_STEPS {
                 panic!("Reached MAX_SMALL_STEPS on {}", case);
@@ -179,3 +307,731 @@ fn test_big_step() {
         }
     }
 }
+
+#[test]
+fn test_eval() {
+    let cases = [
+        (1000, "⟨v1 v2⟩ swap swap ⇓ ⟨v1 v2⟩"),
+        (1000, "⟨v1 v2⟩ false apply ⇓ ⟨v1⟩"),
+        (1000, "⟨v1 v2⟩ true apply ⇓ ⟨v2⟩"),
+        (1000, "⟨false true⟩ or ⇓ ⟨true⟩"),
+        (1000, "⟨v1 v2 v3⟩ rotate3 ⇓ ⟨v2 v3 v1⟩"),
+        (1000, "⟨[v1] [v2] [v3]⟩ compose3 ⇓ ⟨[v1 v2 v3]⟩"),
+        (1000, "⟨v1 [clone]⟩ n2 apply ⇓ ⟨v1 v1 v1⟩"),
+        (1000, "⟨v1 [clone] n1 n2⟩ add apply ⇓ ⟨v1 v1 v1 v1⟩"),
+        (1000, "⟨v1 [clone] n2 n2⟩ mul apply ⇓ ⟨v1 v1 v1 v1 v1⟩"),
+        (0, "⟨v1 v2⟩ swap swap ⇓ ⟨v1 v2⟩ swap swap"),
+        (1, "⟨v1 v2⟩ swap swap ⇓ ⟨v2 v1⟩ swap"),
+        (2, "⟨v1 v2⟩ swap swap ⇓ ⟨v1 v2⟩"),
+        (
+            3,
+            "⟨⟩ [clone apply] clone apply ⇓ ⟨[clone apply]⟩ clone apply",
+        ),
+        (1000, "⟨v1 v2⟩ {$s push} {$s push} {$s pop} ⇓ ⟨v1⟩ $s⟨v2⟩"),
+        (1, "⟨⟩ $s⟨v1 v2⟩ {$s swap} drop ⇓ ⟨⟩ $s⟨v2 v1⟩ drop"),
+        (
+            1000,
+            "⟨v1 v2 v3⟩ {$s push} {$s push} {$s push} {$s rotate3} {$s pop} ⇓ ⟨v3⟩ $s⟨v2 v1⟩",
+        ),
+    ];
+    let mut ctx = Context::default();
+    for fn_def_src in FN_DEF_SRCS.iter() {
+        let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+        assert_eq!(ctx.define_fn(fn_def), Ok(None));
+    }
+    for (fuel, case) in cases {
+        let mut bsa = BigStepAssertionParser::new().parse(&mut ctx, case).unwrap();
+        let expected = if bsa.3 == Expr::default() {
+            Outcome::Normal
+        } else {
+            Outcome::OutOfFuel(bsa.3.clone())
+        };
+        let result = ctx.eval(&mut bsa.0, bsa.1, Fuel(fuel));
+        assert_eq!(result, Ok(expected), "Failed on {}", case);
+        assert_eq!(
+            bsa.0.resolve(&ctx),
+            bsa.2.resolve(&ctx),
+            "Failed on {}",
+            case
+        );
+    }
+}
+
+#[test]
+fn test_eval_error() {
+    let mut ctx = Context::default();
+    let mut vs = ValueStackParser::new().parse(&mut ctx, "⟨v1⟩").unwrap();
+    let e = ExprParser::new().parse(&mut ctx, "clone foo").unwrap();
+    let foo = Symbol(ctx.interner.get("foo").unwrap());
+    assert_eq!(
+        ctx.eval(&mut vs, e, Fuel(10)),
+        Err(EvalError::UndefinedFn(foo))
+    );
+}
+
+#[test]
+fn test_named_stacks() {
+    let cases = [
+        "⟨[a]⟩ {$s push} ⟶ ⟨⟩ $s⟨[a]⟩",
+        "⟨⟩ $s⟨[a]⟩ {$s pop} ⟶ ⟨[a]⟩",
+        "⟨⟩ $s⟨[a] [b]⟩ {$s swap} ⟶ ⟨⟩ $s⟨[b] [a]⟩",
+        "⟨⟩ $s⟨[a]⟩ {$s clone drop} ⟶ ⟨⟩ $s⟨[a] [a]⟩ {$s drop}",
+        "⟨⟩ {$s [a]} swap ⟶ ⟨⟩ $s⟨[a]⟩ swap",
+        "⟨⟩ $s⟨[a]⟩ {$s apply} ⟶ ⟨⟩ {$s a}",
+        "⟨⟩ $s⟨[b]⟩ $t⟨[a]⟩ {$s {$t pop}} ⟶ ⟨⟩ $s⟨[b] [a]⟩",
+        "⟨⟩ $s⟨[a]⟩ {$s {$t push}} ⟶ ⟨⟩ $t⟨[a]⟩",
+        "⟨⟩ $s⟨[a]⟩ {$s {$s push}} ⟶ ⟨⟩ $s⟨[a]⟩",
+        "⟨[a]⟩ $s⟨[b]⟩ {$t {$s clone}} ⟶ ⟨[a]⟩ $s⟨[b] [b]⟩",
+        "⟨⟩ {$s} drop ⟶ ⟨⟩ drop",
+    ];
+    for case in cases {
+        let mut ctx = Context::default();
+        let mut ssa = SmallStepAssertionParser::new()
+            .parse(&mut ctx, case)
+            .unwrap();
+        let result = ctx.small_step(&mut ssa.0, &mut ssa.1);
+        assert_eq!(result, Ok(()), "Failed on {}", case);
+        assert_eq!(ssa.0, ssa.2, "Failed on {}", case);
+        assert_eq!(ssa.1, ssa.3, "Failed on {}", case);
+        assert!(ssa.1.is_canonical(), "Failed on {}", case);
+    }
+}
+
+#[test]
+fn test_named_stacks_error() {
+    let cases = [
+        (
+            "⟨⟩",
+            "{$s push}",
+            "`{$s push}` needs 1 value, but the stack is empty.",
+        ),
+        (
+            "⟨[a]⟩",
+            "{$s pop}",
+            "`{$s pop}` needs 1 value, but `$s` is empty.",
+        ),
+        (
+            "⟨[a]⟩",
+            "{$s drop}",
+            "On `$s`: `drop` needs 1 value, but the stack is empty.",
+        ),
+        (
+            "⟨⟩ $t⟨[a]⟩",
+            "{$t {$s pop}}",
+            "On `$t`: `{$s pop}` needs 1 value, but `$s` is empty.",
+        ),
+        ("⟨⟩ $s⟨[a]⟩", "{$s foo}", "`foo` is not defined."),
+    ];
+    for (vs_src, e_src, expected) in cases {
+        let mut ctx = Context::default();
+        let mut vs = ctx.parse_value_stack(vs_src).unwrap();
+        let mut e = ctx.parse_expr(e_src).unwrap();
+        let (vs1, e1) = (vs.clone(), e.clone());
+        let result = ctx.small_step(&mut vs, &mut e);
+        assert_eq!(
+            result.map_err(|err| err.resolve(&ctx).to_string()),
+            Err(expected.to_owned()),
+            "Failed on {}",
+            e_src
+        );
+        assert_eq!((vs, e), (vs1, e1), "Failed on {}", e_src);
+    }
+}
+
+#[test]
+fn test_small_step_with_calls() {
+    let fn_def_srcs = [
+        "{fn f = drop drop}",
+        "{fn g = f clone}",
+        "{fn h = swap g}",
+        "{fn loop = clone loop}",
+    ];
+    let mut ctx = Context::default();
+    let parser = FnDefParser::new();
+    for fn_def_src in fn_def_srcs {
+        let fn_def = parser.parse(&mut ctx, fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    let cases = [
+        ("h swap", 2, vec!["h"]),
+        ("h swap", 3, vec!["g"]),
+        ("h swap", 4, vec!["f", "g"]),
+        ("h swap", 6, vec!["g"]),
+        ("h swap", 7, vec![]),
+        ("loop", 100, vec!["loop"]),
+    ];
+    for (e_src, steps, expected) in cases {
+        let mut vs = ValueStackParser::new()
+            .parse(&mut ctx, "⟨v1 v2 v3⟩")
+            .unwrap();
+        let mut e = ExprParser::new().parse(&mut ctx, e_src).unwrap();
+        let mut calls = CallStack::default();
+        for _ in 0..steps {
+            ctx.small_step_with_calls(&mut vs, &mut e, &mut calls, &mut ())
+                .unwrap();
+        }
+        let calls = calls.resolve(&ctx);
+        assert_eq!(calls, expected, "Failed on {:?}", (e_src, steps));
+    }
+}
+
+#[test]
+fn test_not_a_quotation() {
+    let cases = [
+        ("⟨f⟩", "apply"),
+        ("⟨[e] f⟩", "compose"),
+        ("⟨f [e]⟩", "compose"),
+    ];
+    let mut ctx = Context::default();
+    let fn_def = FnDefParser::new().parse(&mut ctx, "{fn f = swap}").unwrap();
+    ctx.define_fn(fn_def).unwrap();
+    let f = Symbol(ctx.interner.get("f").unwrap());
+    for (vs_src, e_src) in cases {
+        let mut vs = ValueStackParser::new().parse(&mut ctx, vs_src).unwrap();
+        let mut e = ExprParser::new().parse(&mut ctx, e_src).unwrap();
+        let (expected_vs, expected_e) = (vs.clone(), e.clone());
+        let result = ctx.small_step(&mut vs, &mut e);
+        assert_eq!(
+            result,
+            Err(EvalError::NotAQuotation(f)),
+            "Failed on {}",
+            e_src
+        );
+        assert_eq!((vs, e), (expected_vs, expected_e), "Failed on {}", e_src);
+    }
+}
+
+#[test]
+fn test_structural_sharing() {
+    let mut ctx = Context::default();
+    let mut vs = ValueStackParser::new()
+        .parse(&mut ctx, "⟨[e1 e2]⟩")
+        .unwrap();
+    let e = ExprParser::new().parse(&mut ctx, "clone quote").unwrap();
+    assert_eq!(ctx.eval(&mut vs, e, Fuel(10)), Ok(Outcome::Normal));
+    match &vs.0[..] {
+        [Value::Quote(e1), Value::Quote(e2)] => match &**e2 {
+            Expr::Quote(e2) => assert!(Rc::ptr_eq(e1, e2)),
+            _ => panic!("Expected a quotation, found {:?}", e2),
+        },
+        _ => panic!("Expected two quotations, found {:?}", vs),
+    }
+}
+
+#[test]
+fn test_structural_sharing_apply() {
+    let mut ctx = Context::default();
+    let mut vs = ValueStackParser::new()
+        .parse(&mut ctx, "⟨[[e1 e2]]⟩")
+        .unwrap();
+    let e = ExprParser::new().parse(&mut ctx, "clone apply").unwrap();
+    assert_eq!(ctx.eval(&mut vs, e, Fuel(10)), Ok(Outcome::Normal));
+    match &vs.0[..] {
+        [Value::Quote(e1), Value::Quote(e2)] => match &**e1 {
+            Expr::Quote(e1) => assert!(Rc::ptr_eq(e1, e2)),
+            _ => panic!("Expected a quotation, found {:?}", e1),
+        },
+        _ => panic!("Expected two quotations, found {:?}", vs),
+    }
+}
+
+#[test]
+fn test_structural_sharing_call() {
+    let mut ctx = Context::default();
+    let fn_def = FnDefParser::new()
+        .parse(&mut ctx, "{fn f = [e1 e2] [e3]}")
+        .unwrap();
+    assert_eq!(ctx.define_fn(fn_def), Ok(None));
+    let mut vs = ValueStack::default();
+    let e = ExprParser::new().parse(&mut ctx, "f").unwrap();
+    assert_eq!(ctx.eval(&mut vs, e, Fuel(10)), Ok(Outcome::Normal));
+    let f = Symbol(ctx.interner.get("f").unwrap());
+    match (&ctx.fns[&f], &vs.0[..]) {
+        (Expr::Compose(es), [Value::Quote(e1), Value::Quote(e2)]) => match &es[..] {
+            [Expr::Quote(qe1), Expr::Quote(qe2)] => {
+                assert!(Rc::ptr_eq(qe1, e1));
+                assert!(Rc::ptr_eq(qe2, e2));
+            }
+            _ => panic!("Expected two quotations, found {:?}", es),
+        },
+        (body, _) => panic!(
+            "Expected a composition and two quotations, found {:?}",
+            (body, vs)
+        ),
+    }
+}
+
+const DEEP: usize = 1_000_000;
+
+fn deep_compose(depth: usize) -> Expr {
+    let mut e = Expr::Intrinsic(Intrinsic::Swap);
+    for _ in 0..depth {
+        e = Expr::Compose(vec![e, Expr::Intrinsic(Intrinsic::Swap)]);
+    }
+    e
+}
+
+fn deep_quote(depth: usize, e: Expr) -> Expr {
+    let mut e = e;
+    for _ in 0..depth {
+        e = Expr::Quote(Rc::new(e));
+    }
+    e
+}
+
+#[test]
+fn test_small_step_deep() {
+    let mut ctx = Context::default();
+    let mut vs = ValueStackParser::new().parse(&mut ctx, "⟨v1 v2⟩").unwrap();
+    let expected_vs = vs.clone();
+    let mut e = deep_compose(DEEP);
+    assert_eq!(ctx.small_step(&mut vs, &mut e), Ok(()));
+    assert_eq!(ctx.small_step(&mut vs, &mut e), Ok(()));
+    assert_eq!(vs, expected_vs);
+    match &e {
+        Expr::Compose(es) => assert_eq!(es.len(), DEEP - 1),
+        _ => panic!("Expected a composition"),
+    }
+}
+
+#[test]
+fn test_small_step_deep_error() {
+    let mut ctx = Context::default();
+    let mut vs = ValueStack::default();
+    let mut e = deep_compose(DEEP);
+    assert_eq!(
+        ctx.small_step(&mut vs, &mut e),
+        Err(EvalError::TooFewValues {
+            intrinsic: Intrinsic::Swap,
+            available: 0,
+            expected: 2
+        })
+    );
+    let mut depth = 0;
+    let mut e1 = &e;
+    while let Expr::Compose(es) = e1 {
+        assert_eq!(es.len(), 2);
+        e1 = &es[0];
+        depth += 1;
+    }
+    assert_eq!(depth, DEEP);
+}
+
+#[test]
+fn test_compress_deep_deep() {
+    let mut ctx = Context::default();
+    let fn_def = FnDefParser::new()
+        .parse(&mut ctx, "{fn true = [swap drop]}")
+        .unwrap();
+    ctx.define_fn(fn_def).unwrap();
+    let true_sym = Symbol(ctx.interner.get_or_intern_static("true"));
+    let e = ExprParser::new().parse(&mut ctx, "[swap drop]").unwrap();
+    let mut vs = ValueStack::from(vec![Value::Quote(Rc::new(deep_quote(DEEP, e.clone())))]);
+    assert!(ctx.compress_deep(&mut vs));
+    let true_e = Expr::Pinned(true_sym, Version(1), Rc::new(e));
+    let expected = deep_quote(DEEP - 1, Expr::Quote(Rc::new(true_e)));
+    assert_eq!(vs, ValueStack::from(vec![Value::Quote(Rc::new(expected))]));
+}
+
+#[test]
+fn test_clone_and_drop_deep() {
+    let e = deep_compose(DEEP);
+    let e2 = e.clone();
+    drop(e);
+    drop(e2);
+    let e = deep_quote(DEEP, Expr::default());
+    let vs = ValueStack::from(vec![Value::Quote(Rc::new(e))]);
+    drop(vs);
+}
+
+#[test]
+fn test_compose() {
+    let mut ctx = Context::default();
+    let [a, b, c] = ["a", "b", "c"].map(|s| Expr::Call(Symbol(ctx.interner.get_or_intern(s))));
+    let cases = [
+        (vec![], Expr::default()),
+        (vec![a.clone()], a.clone()),
+        (vec![Expr::default(), a.clone()], a.clone()),
+        (
+            vec![Expr::Compose(vec![a.clone(), b.clone()]), c.clone()],
+            Expr::Compose(vec![a.clone(), b.clone(), c.clone()]),
+        ),
+        (
+            vec![
+                Expr::Compose(vec![Expr::Compose(vec![a.clone()])]),
+                b.clone(),
+            ],
+            Expr::Compose(vec![a.clone(), b.clone()]),
+        ),
+    ];
+    for (es, expected) in cases {
+        assert_eq!(Expr::compose(es.clone()), expected, "Failed on {:?}", es);
+    }
+}
+
+#[test]
+fn test_define_fn_canonical() {
+    let mut ctx = Context::default();
+    let [f, a, b, c] = ["f", "a", "b", "c"].map(|s| Symbol(ctx.interner.get_or_intern(s)));
+    let body = Expr::Compose(vec![
+        Expr::Compose(vec![Expr::Call(a), Expr::Call(b)]),
+        Expr::Compose(vec![Expr::Call(c)]),
+    ]);
+    assert_eq!(
+        ctx.define_fn(FnDef(f, Expr::Quote(Rc::new(body)), None)),
+        Ok(None)
+    );
+    assert!(ctx.fns[&f].is_canonical());
+    let mut vs = ValueStackParser::new()
+        .parse(&mut ctx, "⟨[a (b c)]⟩")
+        .unwrap();
+    assert!(ctx.compress(&mut vs));
+    assert_eq!(vs, ValueStack::from(vec![Value::Call(f, ctx.version(f))]));
+}
+
+/// A small xorshift generator, so that property tests are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// Generate an expression that is usually not canonical.
+fn random_expr(rng: &mut Rng, syms: &[Symbol], depth: usize) -> Expr {
+    const INTRINSICS: [Intrinsic; 6] = [
+        Intrinsic::Swap,
+        Intrinsic::Clone,
+        Intrinsic::Drop,
+        Intrinsic::Quote,
+        Intrinsic::Compose,
+        Intrinsic::Apply,
+    ];
+    match rng.below(if depth == 0 { 2 } else { 5 }) {
+        0 => Expr::Intrinsic(INTRINSICS[rng.below(INTRINSICS.len())]),
+        1 => Expr::Call(syms[rng.below(syms.len())]),
+        2 => Expr::Quote(Rc::new(random_expr(rng, syms, depth - 1))),
+        _ => Expr::Compose(
+            (0..rng.below(4))
+                .map(|_| random_expr(rng, syms, depth - 1))
+                .collect(),
+        ),
+    }
+}
+
+fn normalize_values(vs: &ValueStack) -> ValueStack {
+    ValueStack::from(
+        vs.0.iter()
+            .map(|v| match v {
+                Value::Call(sym, version) => Value::Call(*sym, *version),
+                Value::Quote(e) => Value::Quote(Rc::new((**e).clone().normalize())),
+                Value::Marked(usage, e) => {
+                    Value::Marked(*usage, Rc::new((**e).clone().normalize()))
+                }
+                Value::Host(data) => Value::Host(data.clone()),
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+fn is_canonical_values(vs: &ValueStack) -> bool {
+    vs.0.iter().all(|v| match v {
+        Value::Call(..) | Value::Host(_) => true,
+        Value::Quote(e) | Value::Marked(_, e) => e.is_canonical(),
+    })
+}
+
+#[test]
+fn test_canonical_properties() {
+    const FUEL: usize = 1000;
+    let mut ctx = Context::default();
+    for fn_def_src in FN_DEF_SRCS.iter() {
+        let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+        assert_eq!(ctx.define_fn(fn_def), Ok(None));
+    }
+    let syms: Vec<Symbol> = ["true", "false", "or", "n0", "n2", "undefined"]
+        .iter()
+        .map(|s| Symbol(ctx.interner.get_or_intern(s)))
+        .collect();
+    let parser = ExprParser::new();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..2000 {
+        let e = random_expr(&mut rng, &syms, 5);
+        let canonical = e.clone().normalize();
+        let e_src = e.resolve(&ctx).to_string();
+
+        // Normalization produces canonical expressions, and is idempotent.
+        assert!(canonical.is_canonical(), "Failed on {}", e_src);
+        assert_eq!(
+            canonical.clone().normalize(),
+            canonical,
+            "Failed on {}",
+            e_src
+        );
+
+        // The parser produces canonical expressions.
+        let parsed = parser.parse(&mut ctx, &e_src).unwrap();
+        assert_eq!(parsed, canonical, "Failed on {}", e_src);
+
+        // Composition is associative, and the empty expression is its identity.
+        let [e1, e2, e3] = [0, 1, 2].map(|_| random_expr(&mut rng, &syms, 3).normalize());
+        assert_eq!(
+            Expr::compose([Expr::compose([e1.clone(), e2.clone()]), e3.clone()]),
+            Expr::compose([e1.clone(), Expr::compose([e2.clone(), e3.clone()])]),
+        );
+        assert_eq!(Expr::compose([e1.clone(), Expr::default()]), e1);
+        assert_eq!(Expr::compose([Expr::default(), e1.clone()]), e1);
+
+        // Evaluation preserves canonical form, and normalization preserves
+        // the result of evaluation.
+        let vs = ValueStack::from(
+            (0..rng.below(4))
+                .map(|_| Value::Quote(Rc::new(random_expr(&mut rng, &syms, 3))))
+                .collect::<Vec<_>>(),
+        );
+        let mut vs1 = vs.clone();
+        let result = ctx.eval(&mut vs1, e.clone(), Fuel(FUEL));
+        if let Ok(Outcome::OutOfFuel(_)) = result {
+            continue;
+        }
+        let mut vs2 = normalize_values(&vs);
+        let mut e2 = canonical;
+        let mut canonical_result = Ok(Outcome::Normal);
+        for _ in 0..FUEL {
+            if e2 == Expr::default() {
+                break;
+            }
+            if let Err(err) = ctx.small_step(&mut vs2, &mut e2) {
+                canonical_result = Err(err);
+                break;
+            }
+            assert!(e2.is_canonical(), "Failed on {}", e_src);
+            assert!(is_canonical_values(&vs2), "Failed on {}", e_src);
+        }
+        assert_eq!(canonical_result, result, "Failed on {}", e_src);
+        assert_eq!(e2 == Expr::default(), result.is_ok(), "Failed on {}", e_src);
+        assert_eq!(vs2, normalize_values(&vs1), "Failed on {}", e_src);
+    }
+}
+
+#[test]
+fn test_fns_in_dependency_order() {
+    let fn_def_srcs = [
+        "{fn a = c [b]}",
+        "{fn b = }",
+        "{fn c = d undefined}",
+        "{fn d = e}",
+        "{fn e = d}",
+    ];
+    let mut ctx = Context::default();
+    let parser = FnDefParser::new();
+    for fn_def_src in fn_def_srcs {
+        let fn_def = parser.parse(&mut ctx, fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    let names: Vec<_> = ctx
+        .fns_in_dependency_order()
+        .into_iter()
+        .map(|sym| sym.resolve(&ctx))
+        .collect();
+    assert_eq!(names, ["b", "e", "d", "c", "a"]);
+}
+
+#[test]
+fn test_embedding_api() {
+    let mut ctx = Context::default();
+    assert_eq!(ctx.symbol("f"), None);
+    let f = ctx.intern("f");
+    assert_eq!(ctx.symbol("f"), Some(f));
+    assert_eq!(ctx.name(f), "f");
+
+    let fn_def = ctx.parse_fn_def("{fn f = [swap]}").unwrap();
+    assert_eq!(ctx.define_fn(fn_def), Ok(None));
+    let fn_def = ctx.parse_fn_def("{fn g = f f}").unwrap();
+    ctx.define_fn(fn_def).unwrap();
+    let g = ctx.symbol("g").unwrap();
+    let swap = Expr::Intrinsic(Intrinsic::Swap);
+    assert_eq!(ctx.fn_def(f), Some(&Expr::Quote(Rc::new(swap.clone()))));
+    let names: Vec<_> = ctx.fn_defs().map(|(sym, _)| ctx.name(sym)).collect();
+    assert_eq!(names, ["f", "g"]);
+
+    let mut vs = ctx.parse_value_stack("⟨[a]⟩").unwrap();
+    let e = ctx.parse_expr("g").unwrap();
+    assert_eq!(ctx.eval(&mut vs, e, Fuel(10)), Ok(Outcome::Normal));
+    let values = vs.into_values();
+    assert_eq!(values.len(), 3);
+    assert_eq!(values[1], Value::Call(f, ctx.version(f)));
+    assert_eq!(ctx.unquote_value(values[2].clone()), Ok(swap));
+    assert_eq!(ctx.unquote_value(ctx.call_value(g)).ok(), None);
+
+    // A call made by an embedder keeps referring to the version it was made
+    // for.
+    let mut values = ctx.parse_value_stack("⟨[a] [b]⟩").unwrap().into_values();
+    values.push(ctx.call_value(f));
+    let mut vs = ValueStack::from(values);
+    let fn_def = ctx.parse_fn_def("{fn f = [drop]}").unwrap();
+    ctx.define_fn(fn_def).unwrap();
+    let e = ctx.parse_expr("apply").unwrap();
+    assert_eq!(ctx.eval(&mut vs, e, Fuel(10)), Ok(Outcome::Normal));
+    assert_eq!(vs.resolve(&ctx).to_string(), "⟨[b] [a]⟩");
+
+    let err = ctx.parse_expr("swap ]").unwrap_err();
+    assert_eq!(err.span, crate::diagnostic::Span { start: 5, end: 6 });
+}
+
+#[test]
+fn test_native() {
+    let mut ctx = Context::default();
+    let dup = ctx.define_native("dup", 1, |_, args| {
+        Ok(vec![args[0].clone(), args[0].clone()])
+    });
+    let fail = ctx.define_native("fail", 0, |_, _| Err("oops".to_owned()));
+    let fn_def = ctx.parse_fn_def("{fn dup3 = dup dup drop dup}").unwrap();
+    ctx.define_fn(fn_def).unwrap();
+    assert!(ctx.is_native(dup));
+    let cases = [
+        ("⟨[a]⟩ dup", Ok("⟨[a] [a]⟩")),
+        ("⟨[a]⟩ dup3", Ok("⟨[a] [a] [a]⟩")),
+        (
+            "⟨⟩ dup",
+            Err(EvalError::NativeTooFewValues {
+                native: dup,
+                available: 0,
+                expected: 1,
+            }),
+        ),
+        (
+            "⟨[a]⟩ fail",
+            Err(EvalError::NativeFailed(fail, "oops".to_owned())),
+        ),
+    ];
+    for (src, expected) in cases {
+        let (vs_src, e_src) = src.split_at(src.find('⟩').unwrap() + '⟩'.len_utf8());
+        let vs = ctx.parse_value_stack(vs_src).unwrap();
+        let e = ctx.parse_expr(e_src).unwrap();
+        let mut eval_vs = vs.clone();
+        let result = ctx.eval(&mut eval_vs, e.clone(), Fuel(10));
+        let mut machine_vs = vs.clone();
+        let machine_result = Machine::new(&e).run(&mut ctx, &mut machine_vs, Fuel(10));
+        match expected {
+            Ok(expected_vs) => {
+                assert_eq!(result, Ok(Outcome::Normal), "Failed on {}", src);
+                assert_eq!(eval_vs.resolve(&ctx).to_string(), expected_vs);
+                assert_eq!(machine_result, Ok(Outcome::Normal), "Failed on {}", src);
+                assert_eq!(machine_vs, eval_vs, "Failed on {}", src);
+            }
+            Err(err) => {
+                assert_eq!(result, Err(err.clone()), "Failed on {}", src);
+                assert_eq!(eval_vs, vs, "Failed on {}", src);
+                assert_eq!(machine_result, Err(err), "Failed on {}", src);
+                assert_eq!(machine_vs, vs, "Failed on {}", src);
+            }
+        }
+    }
+
+    let fn_def = ctx.parse_fn_def("{fn dup = clone}").unwrap();
+    assert_eq!(ctx.define_fn(fn_def), Ok(None));
+    assert!(!ctx.is_native(dup));
+}
+
+/// A host value that counts how many times it has been dropped
+#[derive(Debug)]
+struct Handle(usize, Rc<Cell<usize>>);
+
+impl fmt::Display for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "handle({})", self.0)
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.1.set(self.1.get() + 1);
+    }
+}
+
+impl HostValue for Handle {
+    fn eq_host(&self, other: &dyn HostValue) -> bool {
+        other.as_any().downcast_ref::<Handle>().map(|h| h.0) == Some(self.0)
+    }
+}
+
+#[test]
+fn test_host_value() {
+    let mut ctx = Context::default();
+    let drops = Rc::new(Cell::new(0));
+    let open_drops = drops.clone();
+    ctx.define_native("open", 0, move |_, _| {
+        Ok(vec![Value::host(Handle(3, open_drops.clone()))])
+    });
+    ctx.define_native("fd", 1, |_, args| match args[0].as_host::<Handle>() {
+        Some(h) => Ok(vec![Value::Quote(Rc::new(Expr::compose(
+            std::iter::repeat_with(|| Expr::Intrinsic(Intrinsic::Clone)).take(h.0),
+        )))]),
+        None => Err("not a handle".to_owned()),
+    });
+    let cases = [
+        ("⟨⟩ open", Ok("⟨handle(3)⟩")),
+        ("⟨⟩ open clone swap drop", Ok("⟨handle(3)⟩")),
+        ("⟨⟩ open fd", Ok("⟨[clone clone clone]⟩")),
+        ("⟨[a]⟩ open drop", Ok("⟨[a]⟩")),
+        ("⟨[a]⟩ fd", Err(("⟨[a]⟩", "`fd` failed: not a handle"))),
+        (
+            "⟨⟩ open apply",
+            Err((
+                "⟨handle(3)⟩",
+                "`handle(3)` is a host value, not a quotation.",
+            )),
+        ),
+        (
+            "⟨[a]⟩ open compose",
+            Err((
+                "⟨[a] handle(3)⟩",
+                "`handle(3)` is a host value, not a quotation.",
+            )),
+        ),
+        (
+            "⟨⟩ open quote",
+            Err((
+                "⟨handle(3)⟩",
+                "`handle(3)` is a host value, not a quotation.",
+            )),
+        ),
+    ];
+    for (src, expected) in cases {
+        let (vs_src, e_src) = src.split_at(src.find('⟩').unwrap() + '⟩'.len_utf8());
+        let vs = ctx.parse_value_stack(vs_src).unwrap();
+        let e = ctx.parse_expr(e_src).unwrap();
+        let mut eval_vs = vs.clone();
+        let result = ctx.eval(&mut eval_vs, e.clone(), Fuel(10));
+        let mut machine_vs = vs.clone();
+        let machine_result = Machine::new(&e).run(&mut ctx, &mut machine_vs, Fuel(10));
+        assert_eq!(machine_result, result, "Failed on {}", src);
+        assert_eq!(machine_vs, eval_vs, "Failed on {}", src);
+        match expected {
+            Ok(expected_vs) => {
+                assert_eq!(result, Ok(Outcome::Normal), "Failed on {}", src);
+                assert_eq!(eval_vs.resolve(&ctx).to_string(), expected_vs);
+            }
+            Err((expected_vs, expected_err)) => {
+                let err = result.unwrap_err();
+                assert_eq!(err.resolve(&ctx).to_string(), expected_err);
+                assert_eq!(eval_vs.resolve(&ctx).to_string(), expected_vs);
+            }
+        }
+    }
+
+    // Clones share the data, which is dropped along with the last of them.
+    drops.set(0);
+    let e = ctx.parse_expr("open clone drop").unwrap();
+    let mut vs = ValueStack::default();
+    assert_eq!(ctx.eval(&mut vs, e, Fuel(10)), Ok(Outcome::Normal));
+    assert_eq!(drops.get(), 0);
+    assert_eq!(vs.0[0].as_host::<Handle>().map(|h| h.0), Some(3));
+    drop(vs);
+    assert_eq!(drops.get(), 1);
+}
@@ -0,0 +1,163 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::*;
+use crate::diagnostic::*;
+use crate::interp::*;
+use crate::parse::ExprParser;
+
+#[test]
+fn test_render() {
+    let cases = [
+        ("swap foo", (5, 8), "1 | swap foo\n  |      ^^^\n"),
+        ("swap", (4, 4), "1 | swap\n  |     ^\n"),
+        ("⟨⟩ foo", (7, 10), "1 | ⟨⟩ foo\n  |    ^^^\n"),
+        (
+            "swap\nfoo bar\nclone",
+            (9, 12),
+            "2 | foo bar\n  |     ^^^\n",
+        ),
+        ("swap\nfoo bar", (2, 7), "1 | swap\n  |   ^^\n"),
+    ];
+    for &(source, (start, end), expected) in cases.iter() {
+        let diagnostic = Diagnostic {
+            span: Span { start, end },
+            message: "Oops.".to_owned(),
+            notes: vec![],
+        };
+        assert_eq!(
+            diagnostic.render(source),
+            format!("Oops.\n{}", expected),
+            "Failed on {:?}",
+            (source, start, end)
+        );
+    }
+}
+
+#[test]
+fn test_render_notes() {
+    let diagnostic = Diagnostic {
+        span: Span { start: 5, end: 8 },
+        message: "Oops.".to_owned(),
+        notes: vec!["first".to_owned(), "second".to_owned()],
+    };
+    assert_eq!(
+        diagnostic.render("swap foo"),
+        "Oops.\n1 | swap foo\n  |      ^^^\n  = first\n  = second\n"
+    );
+}
+
+#[test]
+fn test_parse_error() {
+    let cases = [
+        (
+            "swap )",
+            "Parse error: Unexpected `)`; expected an expression.",
+        ),
+        (
+            "swap ⟩",
+            "Parse error: Unexpected `⟩`; expected an expression.",
+        ),
+        (
+            "[swap",
+            "Parse error: Unexpected end of input; expected an expression or `]`.",
+        ),
+        (
+            "(swap ]",
+            "Parse error: Unexpected `]`; expected an expression or `)`.",
+        ),
+    ];
+    let mut ctx = Context::default();
+    for (src, expected) in cases {
+        assert_eq!(
+            ctx.parse_expr(src).map_err(|d| d.message),
+            Err(expected.to_owned()),
+            "Failed on {}",
+            src
+        );
+    }
+    assert_eq!(
+        ctx.parse_fn_def("{fn f swap}").map_err(|d| d.message),
+        Err("Parse error: Unexpected `swap`; expected `:` or `=`.".to_owned())
+    );
+    assert_eq!(
+        ctx.parse_value_stack("⟨v1 linear⟩").map_err(|d| d.message),
+        Err("Parse error: `linear` is a reserved word; expected a name, `⟩` or `[`.".to_owned())
+    );
+}
+
+#[test]
+fn test_parse_error_recovered() {
+    let cases = [
+        (
+            "swap )",
+            "Parse error: Unexpected `)`; expected an expression or `{`.",
+        ),
+        (
+            "swap ⟩ drop",
+            "Parse error: Unexpected `⟩`; expected an expression or `{`.",
+        ),
+        (
+            "{fn f swap} drop",
+            "Parse error: Unexpected `swap`; expected `:` or `=`.",
+        ),
+        (
+            "drop\n{fn f = [swap}",
+            "Parse error: Unexpected `}`; expected an expression or `]`.",
+        ),
+        (
+            "{fn linear = [x]} linear",
+            "Parse error: `linear` is a reserved word; expected a name.",
+        ),
+    ];
+    let mut ctx = Context::default();
+    for (src, expected) in cases {
+        let messages: Vec<_> = SourceFile::parse(&mut ctx, src)
+            .errors
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(messages, [expected], "Failed on {}", src);
+    }
+}
+
+#[test]
+fn test_parse_error_interns_nothing() {
+    let mut ctx = Context::default();
+    assert!(ctx.parse_expr("swap )").is_err());
+    assert_eq!(ctx.symbol("x"), None);
+}
+
+#[test]
+fn test_terminals() {
+    // Each terminal of the grammar has a sample.
+    let grammar = include_str!("parse.lalrpop");
+    let lexer = &grammar[grammar.rfind("match {").unwrap()..];
+    let mut terminals: Vec<&str> = lexer
+        .lines()
+        .filter_map(|line| line.trim().strip_suffix(',')?.rsplit_once("=> "))
+        .map(|(_, terminal)| terminal)
+        .filter(|terminal| !terminal.starts_with('{'))
+        .collect();
+    terminals.sort_unstable();
+    let names: Vec<&str> = TERMINALS.iter().map(|&(name, _)| name).collect();
+    assert_eq!(names, terminals);
+
+    // The terminals that can start an expression are those expected at the
+    // start of a quotation, other than its end.
+    let err = ExprParser::new()
+        .parse(&mut Context::default(), "[")
+        .unwrap_err();
+    let mut expected = match err {
+        lalrpop_util::ParseError::UnrecognizedEOF { expected, .. } => expected,
+        _ => panic!("{:?}", err),
+    };
+    expected.retain(|terminal| terminal != "RBRACKET");
+    expected.sort_unstable();
+    let mut expr_terminals = EXPR_TERMINALS.to_vec();
+    expr_terminals.sort_unstable();
+    assert_eq!(expected, expr_terminals);
+}
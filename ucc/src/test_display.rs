@@ -0,0 +1,53 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::*;
+use crate::display::*;
+use crate::parse::*;
+use std::rc::Rc;
+
+#[test]
+fn test_display() {
+    let cases = [
+        ("⟨⟩", "", ""),
+        ("⟨[]⟩", "swap", "swap"),
+        ("⟨[e1] [e2 e3]⟩", "e1 e2 [e3]", "e1 e2 [e3]"),
+        ("⟨[[e1]]⟩", "(e1 e2) e3", "e1 e2 e3"),
+        ("⟨⟩", "e1 (e2 (e3 e4)) e5", "e1 e2 e3 e4 e5"),
+        ("⟨⟩", "[e1 (e2 e3) [e4]]", "[e1 e2 e3 [e4]]"),
+    ];
+    for (vs_src, e_src, expected_e_src) in cases {
+        let mut ctx = Context::default();
+        let vs = ValueStackParser::new().parse(&mut ctx, vs_src).unwrap();
+        let e = ExprParser::new().parse(&mut ctx, e_src).unwrap();
+        assert_eq!(
+            format!("{} {}", vs.resolve(&ctx), e.resolve(&ctx)),
+            format!("{} {}", vs_src, expected_e_src)
+        );
+    }
+}
+
+#[test]
+fn test_display_deep() {
+    const DEEP: usize = 1_000_000;
+    let mut ctx = Context::default();
+    let sym = Symbol(ctx.interner.get_or_intern_static("e"));
+    let mut quote = Expr::Call(sym);
+    let mut compose = Expr::Call(sym);
+    for _ in 0..DEEP {
+        quote = Expr::Quote(Rc::new(quote));
+        compose = Expr::Compose(vec![compose, Expr::Call(sym)]);
+    }
+    let vs = ValueStack::from(vec![Value::Quote(Rc::new(quote))]);
+    let s = vs.resolve(&ctx).to_string();
+    assert_eq!(s.len(), "⟨⟩".len() + 2 * (DEEP + 1) + 1);
+    assert!(s.starts_with(&format!("⟨{}e]", "[".repeat(DEEP + 1))));
+    assert!(s.ends_with("]]]⟩"));
+    let s = compose.resolve(&ctx).to_string();
+    assert_eq!(s.len(), 2 * (DEEP - 1) + 2 * DEEP + 1);
+    assert!(s.starts_with(&format!("{}e e) e", "(".repeat(DEEP - 1))));
+    assert!(s.ends_with("e) e) e"));
+}
@@ -0,0 +1,94 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::builtin::FN_DEF_SRCS;
+use crate::core::*;
+use crate::display::*;
+
+#[test]
+fn test_effect() {
+    let mut ctx = Context::default();
+    for fn_def_src in FN_DEF_SRCS.iter() {
+        let fn_def = ctx.parse_fn_def(fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    ctx.define_native("pair", 2, |_, args| Ok(args.to_vec()));
+    for fn_def_src in ["{fn loop = loop}", "{fn f = drop [a] apply}"] {
+        let fn_def = ctx.parse_fn_def(fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    let cases = [
+        ("", Ok("⟨⟩ ⟶ ⟨⟩")),
+        ("swap", Ok("⟨[a] [b]⟩ ⟶ ⟨[b] [a]⟩")),
+        ("clone", Ok("⟨[a]⟩ ⟶ ⟨[a] [a]⟩")),
+        ("drop", Ok("⟨[a]⟩ ⟶ ⟨⟩")),
+        ("quote", Ok("⟨[a]⟩ ⟶ ⟨[[a]]⟩")),
+        ("compose", Ok("⟨[a] [b]⟩ ⟶ ⟨[a b]⟩")),
+        ("apply", Ok("⟨[a]⟩ ⟶ ⟨⟩ a")),
+        ("rotate3", Ok("⟨[a] [b] [c]⟩ ⟶ ⟨[b] [c] [a]⟩")),
+        ("true", Ok("⟨⟩ ⟶ ⟨true⟩")),
+        ("pair", Ok("⟨[a] [b]⟩ ⟶ ⟨[a] [b]⟩")),
+        // Evaluation gets stuck on a call to a variable, leaving the rest.
+        ("swap apply drop", Ok("⟨[a] [b]⟩ ⟶ ⟨[b]⟩ a drop")),
+        ("mul", Ok("⟨[a] [b]⟩ ⟶ ⟨n0 [[a] add]⟩ b")),
+        // Variables are named around the functions that are called.
+        ("[b] drop drop drop", Ok("⟨[a] [c]⟩ ⟶ ⟨⟩")),
+        // And around the functions that they call.
+        ("f", Err("`a` is not defined.")),
+        ("undefined", Err("`undefined` is not defined.")),
+        ("loop", Err("Ran out of fuel at ⟨⟩ ⟶ ⟨⟩ loop.")),
+        // Values are taken from the main stack, but not from named stacks.
+        (
+            "{$s push} {$s push} {$s pop}",
+            Ok("⟨[a] [b]⟩ ⟶ ⟨[a]⟩ $s⟨[b]⟩"),
+        ),
+        ("{$s push} {$s apply}", Ok("⟨[a]⟩ ⟶ ⟨⟩ {$s a}")),
+        (
+            "{$s drop}",
+            Err("On `$s`: `drop` needs 1 value, but the stack is empty."),
+        ),
+    ];
+    for (src, expected) in cases {
+        let e = ctx.parse_expr(src).unwrap();
+        let actual = match ctx.effect(&e, Fuel(100)) {
+            Ok(effect) => Ok(effect.resolve(&ctx).to_string()),
+            Err(err) => Err(err.resolve(&ctx).to_string()),
+        };
+        assert_eq!(
+            actual,
+            expected.map(String::from).map_err(String::from),
+            "Failed on {}",
+            src
+        );
+    }
+}
+
+#[test]
+fn test_effect_residual() {
+    let mut ctx = Context::default();
+    let e = ctx.parse_expr("swap apply drop").unwrap();
+    let effect = ctx.effect(&e, Fuel(100)).unwrap();
+    let a = ctx.symbol("a").unwrap();
+    let b = ctx.symbol("b").unwrap();
+    let quote = |sym| Value::Quote(std::rc::Rc::new(Expr::Call(sym)));
+    assert_eq!(effect.inputs, ValueStack::from(vec![quote(a), quote(b)]));
+    assert_eq!(effect.outputs, ValueStack::from(vec![quote(b)]));
+    assert_eq!(effect.residual, ctx.parse_expr("a drop").unwrap());
+}
+
+#[test]
+fn test_effect_many_inputs() {
+    const INPUTS: usize = 5_000;
+    let mut ctx = Context::default();
+    let e = Expr::Compose(vec![Expr::Intrinsic(Intrinsic::Drop); INPUTS]);
+    let effect = ctx.effect(&e, Fuel(INPUTS)).unwrap();
+    assert_eq!(effect.inputs.0.len(), INPUTS);
+    assert_eq!(effect.outputs, ValueStack::default());
+    // The names of the variables are reused.
+    let interned = ctx.interner.len();
+    ctx.effect(&e, Fuel(INPUTS)).unwrap();
+    assert_eq!(ctx.interner.len(), interned);
+}
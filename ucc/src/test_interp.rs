@@ -4,7 +4,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use crate::interp::{Interp, HELP};
+use crate::core::*;
+use crate::display::*;
+use crate::interp::{Interp, InterpError, SourceError, TestSummary, HELP};
+use std::rc::Rc;
 
 #[test]
 fn test_non_blocking_interp() {
@@ -57,18 +60,283 @@ fn test_non_blocking_interp() {
             ),
         )][..],
         &[(":help", HELP)][..],
+        &[
+            (":compress", "Compression: top.\n"),
+            (
+                "[[swap drop] [drop]]",
+                "⟨⟩ [[swap drop] [drop]]\n⇓ ⟨[[swap drop] [drop]]⟩ \n",
+            ),
+            (":compress deep", "Compression set to deep.\n"),
+            (":compress", "Compression: deep.\n"),
+            (
+                "clone",
+                "⟨[[swap drop] [drop]]⟩ clone\n⇓ ⟨[true n0] [true n0]⟩ \n",
+            ),
+            (
+                "[quote swap quote swap compose x]",
+                concat!(
+                    "⟨[true n0] [true n0]⟩ [quote swap quote swap compose x]\n",
+                    "⇓ ⟨[true n0] [true n0] [quote2 x]⟩ \n",
+                ),
+            ),
+            (
+                ":compress fast",
+                "Unknown compression `fast`. Expected off, top or deep.\n",
+            ),
+            (":compress off", "Compression set to off.\n"),
+            (
+                "drop drop drop [drop]",
+                concat!(
+                    "⟨[true n0] [true n0] [quote2 x]⟩ drop drop drop [drop]\n",
+                    "⇓ ⟨[drop]⟩ \n",
+                ),
+            ),
+        ][..],
+        &[
+            (":compress deep", "Compression set to deep.\n"),
+            (
+                ":trace [swap] [drop] compose quote",
+                concat!(
+                    "⟨⟩ [swap] [drop] compose quote\n",
+                    "⟶ ⟨[swap]⟩ [drop] compose quote\n",
+                    "⟶ ⟨[swap] [drop]⟩ compose quote\n",
+                    "= ⟨[swap] n0⟩ compose quote\n",
+                    "⟶ ⟨[swap drop]⟩ quote\n",
+                    "= ⟨true⟩ quote\n",
+                    "⟶ ⟨[true]⟩ \n",
+                ),
+            ),
+        ][..],
+        &[
+            (":compress deep", "Compression set to deep.\n"),
+            (
+                "v1 v2 [[swap drop]]",
+                "⟨⟩ v1 v2 [[swap drop]]\n⇓ ⟨v1 v2 [true]⟩ \n",
+            ),
+            ("{fn true = [drop]}", "Redefined `true`.\n"),
+            ("apply apply", "⟨v1 v2 [true@1]⟩ apply apply\n⇓ ⟨v2⟩ \n"),
+            ("{fn sd = swap drop}", "Defined `sd`.\n"),
+            (
+                "v1 v2 [swap drop clone]",
+                "⟨v2⟩ v1 v2 [swap drop clone]\n⇓ ⟨v2 v1 v2 [sd clone]⟩ \n",
+            ),
+            ("{fn sd = drop}", "Redefined `sd`.\n"),
+            ("apply", "⟨v2 v1 v2 [sd@1 clone]⟩ apply\n⇓ ⟨v2 v2 v2⟩ \n"),
+        ][..],
+        &[
+            ("v1 v2 true", "⟨⟩ v1 v2 true\n⇓ ⟨v1 v2 true⟩ \n"),
+            ("{fn true = [drop]}", "Redefined `true`.\n"),
+            (
+                "true",
+                "⟨v1 v2 true@1⟩ true\n⇓ ⟨v1 v2 true@1 true⟩ \n",
+            ),
+            (
+                "drop apply",
+                "⟨v1 v2 true@1 true⟩ drop apply\n⇓ ⟨v2⟩ \n",
+            ),
+            (":clear", "Definitions cleared.\n"),
+            ("quote", "⟨v2@1⟩ quote\n⇓ ⟨[v2@1]⟩ \n"),
+        ][..],
+        &[
+            (
+                "v1 v2 true quote",
+                "⟨⟩ v1 v2 true quote\n⇓ ⟨v1 v2 [true]⟩ \n",
+            ),
+            ("{fn true = [drop]}", "Redefined `true`.\n"),
+            ("apply apply", "⟨v1 v2 [true@1]⟩ apply apply\n⇓ ⟨v2⟩ \n"),
+        ][..],
+        &[
+            (":rollback", "Rollback: off.\n"),
+            (":rollback on", "Rollback set to on.\n"),
+            ("v1 v2", "⟨⟩ v1 v2\n⇓ ⟨v1 v2⟩ \n"),
+            (
+                "drop swap",
+                concat!(
+                    "⟨v1 v2⟩ drop swap\n",
+                    "⇓ ⟨v1⟩ swap\n",
+                    "Evaluation error: `swap` needs 2 values, but the stack has only 1.\n",
+                    "1 | drop swap\n",
+                    "  |      ^^^^\n",
+                    "  = stack: ⟨v1⟩\n",
+                    "Rolled back to ⟨v1 v2⟩.\n",
+                ),
+            ),
+            (
+                ":trace drop drop drop",
+                concat!(
+                    "⟨v1 v2⟩ drop drop drop\n",
+                    "⟶ ⟨v1⟩ drop drop\n",
+                    "⟶ ⟨⟩ drop\n",
+                    "Evaluation error: `drop` needs 1 value, but the stack is empty.\n",
+                    "1 | :trace drop drop drop\n",
+                    "  |                  ^^^^\n",
+                    "  = stack: ⟨⟩\n",
+                    "Rolled back to ⟨v1 v2⟩.\n",
+                ),
+            ),
+            (
+                ":rollback maybe",
+                "Unknown rollback `maybe`. Expected on or off.\n",
+            ),
+            (":rollback off", "Rollback set to off.\n"),
+            (
+                "drop swap",
+                concat!(
+                    "⟨v1 v2⟩ drop swap\n",
+                    "⇓ ⟨v1⟩ swap\n",
+                    "Evaluation error: `swap` needs 2 values, but the stack has only 1.\n",
+                    "1 | drop swap\n",
+                    "  |      ^^^^\n",
+                    "  = stack: ⟨v1⟩\n",
+                ),
+            ),
+        ][..],
         &[("n0 succ", "⟨⟩ n0 succ\n⇓ ⟨n1⟩ \n")][..],
+        &[
+            (":type swap", "swap : ∀ρ a b. ρ a b → ρ b a\n"),
+            (
+                ":type clone apply",
+                "Type error: `apply` needs an infinite stack, ρ = σ (ρ → τ).\n",
+            ),
+            (":typecheck", "Typecheck: off.\n"),
+            (":typecheck on", "Typecheck set to on.\n"),
+            ("v1 v2 swap", "⟨⟩ v1 v2 swap\n⇓ ⟨v2 v1⟩ \n"),
+            (
+                "[clone apply] clone apply",
+                "Type error: `apply` needs an infinite stack, ρ = σ (ρ → τ).\n",
+            ),
+            (
+                ":typecheck maybe",
+                "Unknown typecheck `maybe`. Expected on or off.\n",
+            ),
+        ][..],
+        &[
+            ("{fn dup : a -> a a = clone}", "Defined `dup`.\n"),
+            (":show dup", "{fn dup : a -> a a = clone}\n"),
+            (
+                "v1 {fn bad : a b -> a b = swap} bad",
+                concat!(
+                    "⟨⟩ v1\n",
+                    "⇓ ⟨v1⟩ \n",
+                    "Type error: `bad` has the type ∀ρ a b. ρ a b → ρ b a, ",
+                    "which doesn't match its signature a b -> a b.\n",
+                ),
+            ),
+            (":show bad", "Not defined.\n"),
+            ("v2", "⟨v1⟩ v2\n⇓ ⟨v1 v2⟩ \n"),
+        ][..],
+        &[
+            (
+                ":effect rotate3",
+                "rotate3 : ⟨[a] [b] [c]⟩ ⟶ ⟨[b] [c] [a]⟩\n",
+            ),
+            (":effect swap apply", "swap apply : ⟨[a] [b]⟩ ⟶ ⟨[b]⟩ a\n"),
+            (":effect foo", "Effect error: `foo` is not defined.\n"),
+        ][..],
+        &[
+            (":linearity", "Linearity: off.\n"),
+            (":linearity on", "Linearity set to on.\n"),
+            (
+                "[a] linear clone",
+                concat!(
+                    "Linearity error: `clone` can't be applied to a linear value.\n",
+                    "1 | [a] linear clone\n",
+                    "  |            ^^^^^\n",
+                    "  = stack: ⟨⟩\n",
+                ),
+            ),
+            ("{fn dup = clone}", "Defined `dup`.\n"),
+            (
+                "[a] affine dup",
+                concat!(
+                    "⟨⟩ [a] affine dup\n",
+                    "⇓ ⟨[a] affine⟩ clone\n",
+                    "Evaluation error: `clone` can't be applied to an affine value.\n",
+                    "1 | [a] affine dup\n",
+                    "  |            ^^^\n",
+                    "  = stack: ⟨[a] affine⟩\n",
+                    "  = in `dup`\n",
+                ),
+            ),
+            (
+                ":linearity maybe",
+                "Unknown linearity `maybe`. Expected on or off.\n",
+            ),
+        ][..],
         &[("n0 n1 add", "⟨⟩ n0 n1 add\n⇓ ⟨n1⟩ \n")][..],
         &[("n1 n1 add", "⟨⟩ n1 n1 add\n⇓ ⟨n2⟩ \n")][..],
         &[("n1 n1 mul", "⟨⟩ n1 n1 mul\n⇓ ⟨n1⟩ \n")][..],
         &[("n2 n2 mul", "⟨⟩ n2 n2 mul\n⇓ ⟨n4⟩ \n")][..],
         &[(
             "true foo",
-            "⟨⟩ true foo\n⇓ ⟨true⟩ foo\nUndefinedFn(\"foo\")\n",
+            concat!(
+                "⟨⟩ true foo\n",
+                "⇓ ⟨true⟩ foo\n",
+                "Evaluation error: `foo` is not defined.\n",
+                "1 | true foo\n",
+                "  |      ^^^\n",
+                "  = stack: ⟨true⟩\n",
+            ),
         )][..],
         &[(
             ":trace true foo",
-            "⟨⟩ true foo\n⟶ ⟨true⟩ foo\nUndefinedFn(\"foo\")\n",
+            concat!(
+                "⟨⟩ true foo\n",
+                "⟶ ⟨true⟩ foo\n",
+                "Evaluation error: `foo` is not defined.\n",
+                "1 | :trace true foo\n",
+                "  |             ^^^\n",
+                "  = stack: ⟨true⟩\n",
+            ),
+        )][..],
+        &[
+            ("{fn f = drop drop}", "Defined `f`.\n"),
+            (
+                "v1 (f) swap",
+                concat!(
+                    "⟨⟩ v1 f swap\n",
+                    "⇓ ⟨⟩ drop swap\n",
+                    "Evaluation error: `drop` needs 1 value, but the stack is empty.\n",
+                    "1 | v1 (f) swap\n",
+                    "  |     ^\n",
+                    "  = stack: ⟨⟩\n",
+                    "  = in `f`\n",
+                ),
+            ),
+            ("{fn g = v1 f}", "Defined `g`.\n"),
+            ("{fn h = g swap}", "Defined `h`.\n"),
+            (
+                ":trace h",
+                concat!(
+                    "⟨⟩ h\n",
+                    "⟶ ⟨⟩ g swap\n",
+                    "⟶ ⟨⟩ v1 f swap\n",
+                    "⟶ ⟨v1⟩ f swap\n",
+                    "⟶ ⟨v1⟩ drop drop swap\n",
+                    "⟶ ⟨⟩ drop swap\n",
+                    "Evaluation error: `drop` needs 1 value, but the stack is empty.\n",
+                    "1 | :trace h\n",
+                    "  |        ^\n",
+                    "  = stack: ⟨⟩\n",
+                    "  = in `f`, called from `h`\n",
+                ),
+            ),
+        ][..],
+        &[(
+            "swap ]",
+            concat!(
+                "Parse error: Unexpected `]`; expected an expression or `{`.\n",
+                "1 | swap ]\n",
+                "  |      ^\n",
+            ),
+        )][..],
+        &[(
+            "[swap",
+            concat!(
+                "Parse error: Unexpected end of input; expected an expression or `]`.\n",
+                "1 | [swap\n",
+                "  |      ^\n",
+            ),
         )][..],
     ];
     let mut buffer = Vec::with_capacity(4096);
@@ -90,3 +358,444 @@ fn test_non_blocking_interp() {
         }
     }
 }
+
+#[test]
+fn test_interp_step_without_command() {
+    let mut interp = Interp::default();
+    let mut buffer = vec![];
+    assert!(matches!(
+        interp.interp_step(&mut buffer),
+        Err(InterpError::NoCommand)
+    ));
+    assert!(buffer.is_empty());
+}
+
+fn interp_all(interp: &mut Interp, input: &str) -> String {
+    let mut buffer = vec![];
+    interp.interp_start(input, &mut buffer).unwrap();
+    while !interp.is_done() {
+        interp.interp_step(&mut buffer).unwrap();
+    }
+    String::from_utf8(buffer).unwrap()
+}
+
+#[test]
+fn test_load_save() {
+    let dir = std::env::temp_dir().join(format!("ucc-test-load-save-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let lib = dir.join("lib.ucc");
+    let out = dir.join("out.ucc");
+    let missing = dir.join("missing.ucc");
+    std::fs::write(
+        &lib,
+        concat!(
+            "-- A library\n",
+            "{fn twice = clone compose}\n",
+            "{fn g swap}\n",
+            "{fn dup2 = twice twice}\n",
+            "[swap] twice\n",
+        ),
+    )
+    .unwrap();
+    let mut interp = Interp::default();
+    interp_all(&mut interp, ":clear");
+    assert_eq!(
+        interp_all(&mut interp, &format!(":load {}", lib.display())),
+        format!(
+            concat!(
+                "Loading `{}`.\n",
+                "Parse error: Unexpected `swap`; expected `:` or `=`.\n",
+                "3 | {{fn g swap}}\n",
+                "  |       ^^^^\n",
+                "Defined `twice`.\n",
+                "Defined `dup2`.\n",
+                "⟨⟩ [swap] twice\n",
+                "⇓ ⟨[swap swap]⟩ \n",
+            ),
+            lib.display()
+        )
+    );
+    assert_eq!(
+        interp_all(&mut interp, &format!(":save {}", out.display())),
+        format!("Saved 2 definitions to `{}`.\n", out.display())
+    );
+    assert_eq!(
+        std::fs::read_to_string(&out).unwrap(),
+        ":clear\n{fn twice = clone compose}\n{fn dup2 = twice twice}\n"
+    );
+    std::fs::write(&lib, "{fn twice = clone}\n").unwrap();
+    assert_eq!(
+        interp_all(&mut interp, ":reload"),
+        format!("Loading `{}`.\nRedefined `twice`.\n", lib.display())
+    );
+    assert_eq!(
+        interp_all(&mut interp, ":show twice"),
+        "{fn twice = clone}\n"
+    );
+    assert!(
+        interp_all(&mut interp, &format!(":load {}", missing.display()))
+            .starts_with(&format!("Failed to read `{}`: ", missing.display()))
+    );
+    assert_eq!(interp_all(&mut interp, ":reset"), "Reset.\n");
+
+    // Only the builtins that are kept aren't saved, so a builtin redefined
+    // as the same body is.
+    interp_all(
+        &mut interp,
+        "{fn or = apply} {fn true = [swap drop]} {fn twice = clone compose}",
+    );
+    assert_eq!(
+        interp_all(&mut interp, &format!(":save {}", out.display())),
+        format!("Saved 3 definitions to `{}`.\n", out.display())
+    );
+    assert_eq!(
+        std::fs::read_to_string(&out).unwrap(),
+        "{fn or = apply}\n{fn true = [swap drop]}\n{fn twice = clone compose}\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(interp_all(&mut interp, ":reload"), "No files loaded.\n");
+}
+
+#[test]
+fn test_save_cleared() {
+    let dir = std::env::temp_dir().join(format!("ucc-test-save-cleared-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("out.ucc");
+    let mut interp = Interp::default();
+    interp_all(&mut interp, ":clear");
+    interp_all(&mut interp, "{fn true = [swap drop]} {fn f = swap}");
+    assert_eq!(
+        interp_all(&mut interp, &format!(":save {}", out.display())),
+        format!("Saved 2 definitions to `{}`.\n", out.display())
+    );
+    assert_eq!(
+        std::fs::read_to_string(&out).unwrap(),
+        ":clear\n{fn f = swap}\n{fn true = [swap drop]}\n"
+    );
+    interp_all(&mut interp, ":reset");
+    assert_eq!(
+        interp_all(&mut interp, &format!(":load {}", out.display())),
+        format!(
+            "Loading `{}`.\nDefinitions cleared.\nDefined `f`.\nDefined `true`.\n",
+            out.display()
+        )
+    );
+    assert_eq!(interp_all(&mut interp, ":list"), "f true\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_reload_error() {
+    let dir = std::env::temp_dir().join(format!("ucc-test-reload-error-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths = ["a.ucc", "b.ucc", "c.ucc"].map(|name| dir.join(name));
+    std::fs::write(&paths[0], "{fn a = swap}\n[x]\n").unwrap();
+    std::fs::write(&paths[1], "{fn b = drop}\n[y] b b b\n{fn b2 = b}\n").unwrap();
+    std::fs::write(&paths[2], "{fn c = clone}\n").unwrap();
+    let mut interp = Interp::default();
+    interp_all(&mut interp, ":rollback on");
+    for path in &paths {
+        interp_all(&mut interp, &format!(":load {}", path.display()));
+    }
+    interp_all(&mut interp, ":drop");
+    // The error stops `b.ucc`, whose changes to the value stack are rolled
+    // back, but not `c.ucc`.
+    assert_eq!(
+        interp_all(&mut interp, ":reload"),
+        format!(
+            concat!(
+                "Loading `{}`.\n",
+                "Redefined `a`.\n",
+                "⟨⟩ [x]\n",
+                "⇓ ⟨[x]⟩ \n",
+                "Loading `{}`.\n",
+                "Redefined `b`.\n",
+                "⟨[x]⟩ [y] b b b\n",
+                "⇓ ⟨⟩ drop\n",
+                "Evaluation error: `drop` needs 1 value, but the stack is empty.\n",
+                "2 | [y] b b b\n",
+                "  |         ^\n",
+                "  = stack: ⟨⟩\n",
+                "  = in `b`\n",
+                "Rolled back to ⟨[x]⟩.\n",
+                "Loading `{}`.\n",
+                "Redefined `c`.\n",
+            ),
+            paths[0].display(),
+            paths[1].display(),
+            paths[2].display()
+        )
+    );
+    assert_eq!(interp_all(&mut interp, ":show b2"), "Not defined.\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_run() {
+    let cases = [
+        ("{fn f = swap}\n[a] [b] f\n", true, "⟨[b] [a]⟩\n", ""),
+        ("[swap] clone\nfalse drop", true, "⟨[swap] [swap]⟩\n", ""),
+        ("", true, "⟨⟩\n", ""),
+        (
+            "true\n{fn f = drop drop}\nf swap",
+            false,
+            "",
+            concat!(
+                "Evaluation error: `drop` needs 1 value, but the stack is empty.\n",
+                "3 | f swap\n",
+                "  | ^\n",
+                "  = stack: ⟨⟩\n",
+                "  = in `f`\n",
+            ),
+        ),
+        (
+            "[a] [b]\nswap ⟩",
+            false,
+            "",
+            concat!(
+                "Parse error: Unexpected `⟩`; expected an expression or `{`.\n",
+                "2 | swap ⟩\n",
+                "  |      ^\n",
+            ),
+        ),
+    ];
+    for (source, expected_ok, expected_output, expected_err_output) in cases {
+        let mut interp = Interp::default();
+        let (mut output, mut err_output) = (vec![], vec![]);
+        let ok = interp.run(source, &mut output, &mut err_output).unwrap();
+        assert_eq!(
+            (
+                ok,
+                String::from_utf8(output).unwrap(),
+                String::from_utf8(err_output).unwrap()
+            ),
+            (
+                expected_ok,
+                expected_output.to_owned(),
+                expected_err_output.to_owned()
+            ),
+            "Failed on {:?}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_assert() {
+    let cases = [
+        (":assert ⟨[a] [b]⟩ swap ⇓ ⟨[b] [a]⟩", "Passed.\n"),
+        (":assert ⟨⟩ [a] [b] swap ⟶ ⟨[a]⟩ [b] swap", "Passed.\n"),
+        (":assert ⟨⟩ true ⇓ ⟨[swap drop]⟩", "Passed.\n"),
+        (
+            ":assert ⟨[a] [b]⟩ swap ⇓ ⟨[a] [b]⟩",
+            concat!(
+                "Assertion failed.\n",
+                "1 | :assert ⟨[a] [b]⟩ swap ⇓ ⟨[a] [b]⟩\n",
+                "  |         ^^^^^^^^^^^^^^^^^^^^^^^^^^\n",
+                "  = expected: ⟨[a] [b]⟩\n",
+                "  = actual:   ⟨[b] [a]⟩\n",
+                "  =             ^\n",
+            ),
+        ),
+        (
+            ":assert ⟨⟩ drop ⟶ ⟨⟩",
+            concat!(
+                "Assertion failed: `drop` needs 1 value, but the stack is empty.\n",
+                "1 | :assert ⟨⟩ drop ⟶ ⟨⟩\n",
+                "  |         ^^^^^^^^^^^^\n",
+                "  = stack: ⟨⟩\n",
+            ),
+        ),
+    ];
+    let mut interp = Interp::default();
+    for (input, expected_output) in cases {
+        assert_eq!(
+            interp_all(&mut interp, input),
+            expected_output,
+            "Failed on {}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_test_file() {
+    let src = concat!(
+        "-- Tests of f\n",
+        "{fn f = swap}\n",
+        "⟨[a] [b]⟩ f ⇓ ⟨[b] [a]⟩\n",
+        "⟨[a] [b]⟩ f ⟶ ⟨[a] [b]⟩ swap\n",
+        "⟨[a] [b]⟩\n",
+        "  f ⟶ ⟨[b] [a]⟩\n",
+        "{fn g swap}\n",
+        "⟨⟩ v1 ⇓ ⟨v2⟩\n",
+        "{fn t = [a]}\n",
+        "⟨t⟩ apply ⇓ ⟨⟩ a\n",
+    );
+    let mut interp = Interp::default();
+    let mut output = vec![];
+    let summary = interp.test(src, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        concat!(
+            "Assertion failed.\n",
+            "5 | ⟨[a] [b]⟩\n",
+            "  | ^^^^^^^^^\n",
+            "  = expected: ⟨[b] [a]⟩\n",
+            "  = actual:   ⟨[a] [b]⟩ swap\n",
+            "  =             ^\n",
+            "Parse error: Unexpected `swap`; expected `:` or `=`.\n",
+            "7 | {fn g swap}\n",
+            "  |       ^^^^\n",
+        )
+    );
+    assert_eq!(
+        summary,
+        TestSummary {
+            passed: 4,
+            failed: 1,
+            errors: 1
+        }
+    );
+    assert!(!summary.is_success());
+    assert_eq!(summary.to_string(), "4 passed, 1 failed, 1 syntax error");
+}
+
+#[test]
+fn test_eval() {
+    let mut interp = Interp::default();
+    assert_eq!(
+        interp.eval("{fn f = swap} [a] [b] f", Fuel(10)),
+        Ok(Outcome::Normal)
+    );
+    let a = interp.context_mut().intern("a");
+    let b = interp.context_mut().intern("b");
+    assert_eq!(
+        interp.value_stack().values(),
+        [
+            Value::Quote(Rc::new(Expr::Call(b))),
+            Value::Quote(Rc::new(Expr::Call(a)))
+        ]
+    );
+    assert_eq!(interp.eval("drop drop true", Fuel(10)), Ok(Outcome::Normal));
+    let t = interp.context().symbol("true").unwrap();
+    assert!(matches!(
+        interp.value_stack().values(),
+        [Value::Call(sym, _)] if *sym == t
+    ));
+    assert!(matches!(
+        interp.eval("[", Fuel(10)),
+        Err(SourceError::Parse(errors)) if errors.len() == 1
+    ));
+    assert_eq!(
+        interp.eval("drop drop", Fuel(10)),
+        Err(SourceError::Eval(EvalError::TooFewValues {
+            intrinsic: Intrinsic::Drop,
+            available: 0,
+            expected: 1
+        }))
+    );
+    *interp.value_stack_mut() = ValueStack::default();
+    assert_eq!(
+        interp.eval("[clone apply] clone apply", Fuel(11)),
+        Ok(Outcome::OutOfFuel(
+            interp.context_mut().parse_expr("clone apply").unwrap()
+        ))
+    );
+}
+
+#[test]
+fn test_native() {
+    let mut interp = Interp::default();
+    interp.context_mut().define_native("dup", 1, |_, args| {
+        Ok(vec![args[0].clone(), args[0].clone()])
+    });
+    interp.context_mut().define_native("fail", 1, |ctx, args| {
+        Err(format!("got {}", args[0].resolve(ctx)))
+    });
+    let cases = [
+        (":show dup", "{fn dup = <native>}\n"),
+        (
+            ":trace [a] dup swap",
+            concat!(
+                "⟨⟩ [a] dup swap\n",
+                "⟶ ⟨[a]⟩ dup swap\n",
+                "⟶ ⟨[a] [a]⟩ swap\n",
+                "⟶ ⟨[a] [a]⟩ \n",
+            ),
+        ),
+        (":drop", "Values dropped.\n"),
+        (
+            "dup",
+            concat!(
+                "⟨⟩ dup\n",
+                "⇓ ⟨⟩ dup\n",
+                "Evaluation error: `dup` needs 1 value, but the stack is empty.\n",
+                "1 | dup\n",
+                "  | ^^^\n",
+                "  = stack: ⟨⟩\n",
+            ),
+        ),
+        (
+            "true fail",
+            concat!(
+                "⟨⟩ true fail\n",
+                "⇓ ⟨true⟩ fail\n",
+                "Evaluation error: `fail` failed: got true\n",
+                "1 | true fail\n",
+                "  |      ^^^^\n",
+                "  = stack: ⟨true⟩\n",
+            ),
+        ),
+    ];
+    for (input, expected_output) in cases {
+        assert_eq!(
+            interp_all(&mut interp, input),
+            expected_output,
+            "Failed on {}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_named_stacks() {
+    let cases = [
+        (
+            "[a] [b] {$s push} {$t [c]}",
+            "⟨⟩ [a] [b] {$s push} {$t [c]}\n⇓ ⟨[a]⟩ $s⟨[b]⟩ $t⟨[c]⟩ \n",
+        ),
+        (":drop $t", "Values on `$t` dropped.\n"),
+        (
+            "{$s pop} {$s drop}",
+            concat!(
+                "⟨[a]⟩ $s⟨[b]⟩ {$s pop} {$s drop}\n",
+                "⇓ ⟨[a] [b]⟩ {$s drop}\n",
+                "Evaluation error: On `$s`: `drop` needs 1 value, but the stack is empty.\n",
+                "1 | {$s pop} {$s drop}\n",
+                "  |          ^^^^^^^^^\n",
+                "  = stack: ⟨[a] [b]⟩\n",
+            ),
+        ),
+        (":assert ⟨[a] [b]⟩ {$s push} ⇓ ⟨[a]⟩ $s⟨[b]⟩", "Passed.\n"),
+        (
+            ":type {$s push}",
+            "Type error: `{$s push}` uses a named stack, which stack-effect types don't describe.\n",
+        ),
+        (
+            ":effect {$s push} {$s clone}",
+            "{$s push} {$s clone} : ⟨[a]⟩ ⟶ ⟨⟩ $s⟨[a] [a]⟩\n",
+        ),
+        ("{$s}", "⟨[a] [b]⟩ {$s}\n⇓ ⟨[a] [b]⟩ \n"),
+        (":drop", "Values dropped.\n"),
+    ];
+    let mut interp = Interp::default();
+    for (input, expected_output) in cases {
+        assert_eq!(
+            interp_all(&mut interp, input),
+            expected_output,
+            "Failed on {}",
+            input
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::*;
+use crate::display::*;
+
+#[test]
+fn test_eval_linearity() {
+    let mut ctx = Context::default();
+    ctx.set_linearity(true);
+    let cases = [
+        ("[a] linear", Ok("⟨[a] linear⟩")),
+        ("[a] affine drop", Ok("⟨⟩")),
+        ("[a] affine linear", Ok("⟨[a] linear⟩")),
+        ("[a] linear affine", Ok("⟨[a] linear⟩")),
+        ("[] linear apply", Ok("⟨⟩")),
+        ("[a] linear [b] swap", Ok("⟨[b] [a] linear⟩")),
+        ("[a] affine [b] compose", Ok("⟨[a b] affine⟩")),
+        // A quotation of a marked value marks it again when it is applied.
+        ("[a] linear quote", Ok("⟨[[a] linear] linear⟩")),
+        ("[a] linear quote apply", Ok("⟨[a] linear⟩")),
+        (
+            "[a] linear clone",
+            Err("`clone` can't be applied to a linear value."),
+        ),
+        (
+            "[a] affine clone",
+            Err("`clone` can't be applied to an affine value."),
+        ),
+        (
+            "[a] linear drop",
+            Err("`drop` can't be applied to a linear value."),
+        ),
+        (
+            "[a] [b] linear compose clone",
+            Err("`clone` can't be applied to a linear value."),
+        ),
+        ("[a] affine quote drop", Ok("⟨⟩")),
+    ];
+    for (src, expected) in cases {
+        let e = ctx.parse_expr(src).unwrap();
+        let mut vs = ValueStack::default();
+        let actual = match ctx.eval(&mut vs, e, Fuel(100)) {
+            Ok(_) => Ok(vs.resolve(&ctx).to_string()),
+            Err(err) => Err(err.resolve(&ctx).to_string()),
+        };
+        assert_eq!(
+            actual,
+            expected.map(String::from).map_err(String::from),
+            "Failed on {}",
+            src
+        );
+    }
+}
+
+#[test]
+fn test_eval_linearity_off() {
+    let mut ctx = Context::default();
+    assert!(!ctx.linearity());
+    let e = ctx
+        .parse_expr("[a] linear clone [b] affine drop drop")
+        .unwrap();
+    let mut vs = ValueStack::default();
+    ctx.eval(&mut vs, e, Fuel(100)).unwrap();
+    assert_eq!(vs.resolve(&ctx).to_string(), "⟨[a] linear⟩");
+}
+
+#[test]
+fn test_check_linearity() {
+    let mut ctx = Context::default();
+    ctx.define_native("nop", 0, |_, _| Ok(vec![]));
+    for src in ["{fn q = [a]}", "{fn twice = clone compose}"] {
+        let fn_def = ctx.parse_fn_def(src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    let cases = [
+        ("⟨⟩", "[a] linear", Ok(())),
+        ("⟨⟩", "[a] affine drop", Ok(())),
+        (
+            "⟨⟩",
+            "[a] linear clone",
+            Err((2, "`clone` can't be applied to a linear value.")),
+        ),
+        (
+            "⟨⟩",
+            "[a] linear [b] swap drop",
+            Err((4, "`drop` can't be applied to a linear value.")),
+        ),
+        (
+            "⟨⟩",
+            "q q affine compose quote clone",
+            Err((5, "`clone` can't be applied to an affine value.")),
+        ),
+        (
+            "⟨[a] affine⟩",
+            "clone",
+            Err((0, "`clone` can't be applied to an affine value.")),
+        ),
+        ("⟨[a] linear⟩", "[b] swap", Ok(())),
+        // The check stops where the effect depends on the values.
+        ("⟨⟩", "[a] linear apply clone", Ok(())),
+        ("⟨⟩", "[a] linear twice", Ok(())),
+        ("⟨⟩", "[a] linear nop clone", Ok(())),
+        (
+            "⟨⟩",
+            "[a] linear drop drop clone",
+            Err((2, "`drop` can't be applied to a linear value.")),
+        ),
+        ("⟨⟩", "drop [a] linear clone", Ok(())),
+    ];
+    for (vs_src, src, expected) in cases {
+        let vs = ctx.parse_value_stack(vs_src).unwrap();
+        let e = ctx.parse_expr(src).unwrap();
+        let actual = ctx
+            .check_linearity(&vs, &e)
+            .map_err(|(i, err)| (i, err.resolve(&ctx).to_string()));
+        let expected = expected.map_err(|(i, message)| (i, message.to_owned()));
+        assert_eq!(actual, expected, "Failed on {} {}", vs_src, src);
+    }
+}
+
+#[test]
+fn test_parse_marked_values() {
+    let mut ctx = Context::default();
+    let vs = ctx
+        .parse_value_stack("⟨[a] linear [b c] affine [d]⟩")
+        .unwrap();
+    let a = ctx.parse_expr("a").unwrap();
+    let bc = ctx.parse_expr("b c").unwrap();
+    assert_eq!(
+        vs.values()[0],
+        Value::Marked(Usage::Linear, std::rc::Rc::new(a))
+    );
+    assert_eq!(
+        vs.values()[1],
+        Value::Marked(Usage::Affine, std::rc::Rc::new(bc))
+    );
+    assert_eq!(vs.values()[2].usage(), Usage::Unrestricted);
+    assert_eq!(
+        vs.resolve(&ctx).to_string(),
+        "⟨[a] linear [b c] affine [d]⟩"
+    );
+}
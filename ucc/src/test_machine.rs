@@ -0,0 +1,185 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::builtin::FN_DEF_SRCS;
+use crate::core::*;
+use crate::display::*;
+use crate::machine::*;
+use crate::parse::*;
+
+#[test]
+fn test_machine() {
+    let cases = [
+        (1, "⟨[e1] [e2]⟩ swap ⇓ ⟨[e2] [e1]⟩"),
+        (1, "⟨[e1]⟩ clone ⇓ ⟨[e1] [e1]⟩"),
+        (1, "⟨[e1]⟩ drop ⇓ ⟨⟩"),
+        (1, "⟨[e1]⟩ quote ⇓ ⟨[[e1]]⟩"),
+        (1, "⟨[e1] [e2]⟩ compose ⇓ ⟨[e1 e2]⟩"),
+        (1, "⟨[e1]⟩ apply ⇓ ⟨⟩ e1"),
+        (3, "⟨v1 v2⟩ (swap swap) swap ⇓ ⟨v2 v1⟩"),
+        (
+            3,
+            "⟨⟩ [clone apply] clone apply ⇓ ⟨[clone apply]⟩ clone apply",
+        ),
+    ];
+    let mut ctx = Context::default();
+    for (steps, case) in cases {
+        let mut bsa = BigStepAssertionParser::new().parse(&mut ctx, case).unwrap();
+        let mut m = Machine::new(&bsa.1);
+        for _ in 0..steps {
+            assert_eq!(m.step(&mut ctx, &mut bsa.0), Ok(()), "Failed on {}", case);
+        }
+        assert_eq!(bsa.0, bsa.2, "Failed on {}", case);
+        assert_eq!(m.remaining(), bsa.3, "Failed on {}", case);
+    }
+}
+
+#[test]
+fn test_machine_matches_small_step() {
+    const FUEL: Fuel = Fuel(100_000);
+    let stack_srcs = [
+        "⟨⟩",
+        "⟨v1⟩",
+        "⟨v1 v2 v3 v4 v5⟩",
+        "⟨[v1] [v2] [v3] [v4] [v5]⟩",
+        "⟨[clone] [drop] [swap] [quote]⟩",
+        "⟨v1 [clone] n2 n3⟩",
+        "⟨v1 [clone] n3 [clone apply]⟩",
+        "⟨[e] n2 n2⟩",
+        "⟨x y⟩",
+    ];
+    let mut ctx = Context::default();
+    let mut names = vec![];
+    for fn_def_src in FN_DEF_SRCS.iter() {
+        let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+        names.push(fn_def.0.resolve(&ctx));
+        assert_eq!(ctx.define_fn(fn_def), Ok(None));
+    }
+    for name in names {
+        for stack_src in stack_srcs {
+            for e_src in [name.clone(), format!("{} apply", name)] {
+                let vs = ValueStackParser::new().parse(&mut ctx, stack_src).unwrap();
+                let e = ExprParser::new().parse(&mut ctx, &e_src).unwrap();
+
+                let mut small_step_vs = vs.clone();
+                let small_step_result = ctx.eval(&mut small_step_vs, e.clone(), FUEL);
+                if let Ok(Outcome::OutOfFuel(_)) = small_step_result {
+                    continue;
+                }
+
+                let mut machine_vs = vs.clone();
+                let machine_result = Machine::new(&e).run(&mut ctx, &mut machine_vs, FUEL);
+
+                assert_eq!(
+                    (
+                        machine_result.map_err(|err| err.resolve(&ctx)),
+                        machine_vs.resolve(&ctx)
+                    ),
+                    (
+                        small_step_result.map_err(|err| err.resolve(&ctx)),
+                        small_step_vs.resolve(&ctx)
+                    ),
+                    "Failed on {} {}",
+                    stack_src,
+                    e_src
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_machine_deep() {
+    const DEEP: usize = 1_000_000;
+    let mut ctx = Context::default();
+    let mut vs = ValueStackParser::new().parse(&mut ctx, "⟨v1 v2⟩").unwrap();
+    let expected_vs = ValueStackParser::new().parse(&mut ctx, "⟨v2 v1⟩").unwrap();
+    let mut e = Expr::Intrinsic(Intrinsic::Swap);
+    for _ in 0..DEEP {
+        e = Expr::Compose(vec![e, Expr::Intrinsic(Intrinsic::Swap)]);
+    }
+    let result = Machine::new(&e).run(&mut ctx, &mut vs, Fuel(DEEP + 1));
+    assert_eq!(result, Ok(Outcome::Normal));
+    assert_eq!(vs, expected_vs);
+}
+
+#[test]
+fn test_machine_deep_with() {
+    const DEEP: usize = 1_000_000;
+    let mut ctx = Context::default();
+    let mut vs = ValueStackParser::new()
+        .parse(&mut ctx, "⟨⟩ $s⟨v1 v2⟩")
+        .unwrap();
+    let expected_vs = ValueStackParser::new()
+        .parse(&mut ctx, "⟨⟩ $s⟨v2 v1⟩")
+        .unwrap();
+    let s = Symbol(ctx.interner.get_or_intern_static("$s"));
+    let mut e = Expr::Intrinsic(Intrinsic::Swap);
+    for _ in 0..DEEP {
+        e = Expr::With(s, Box::new(e));
+    }
+    let mut m = Machine::new(&e);
+    assert_eq!(m.remaining(), e);
+    assert_eq!(m.run(&mut ctx, &mut vs, Fuel(1)), Ok(Outcome::Normal));
+    assert_eq!(vs, expected_vs);
+}
+
+#[test]
+fn test_machine_named_stacks() {
+    let cases = [
+        ("⟨v1 v2⟩", "{$s push} {$s push} {$s pop}"),
+        (
+            "⟨v1 v2 v3⟩",
+            "{$s push} {$s push} {$s push} {$s rotate3} {$s pop}",
+        ),
+        ("⟨⟩ $s⟨[clone] v1⟩", "{$s swap apply}"),
+        ("⟨⟩ $s⟨v1⟩ $t⟨v2⟩", "{$s {$t pop} swap {$t push}}"),
+        ("⟨v1⟩ $s⟨v2⟩", "{$s {$s pop} clone} {$s}"),
+        ("⟨⟩ $s⟨v1⟩", "{$s drop drop}"),
+        ("⟨⟩", "{$s} {$s pop}"),
+        ("⟨v1⟩", "{$s {$s push}}"),
+    ];
+    let mut ctx = Context::default();
+    for fn_def_src in FN_DEF_SRCS.iter() {
+        let fn_def = FnDefParser::new().parse(&mut ctx, fn_def_src).unwrap();
+        assert_eq!(ctx.define_fn(fn_def), Ok(None));
+    }
+    for (stack_src, e_src) in cases {
+        let vs = ValueStackParser::new().parse(&mut ctx, stack_src).unwrap();
+        let e = ExprParser::new().parse(&mut ctx, e_src).unwrap();
+
+        let mut small_step_vs = vs.clone();
+        let small_step_result = ctx.eval(&mut small_step_vs, e.clone(), Fuel(1000));
+
+        let mut machine_vs = vs.clone();
+        let machine_result = Machine::new(&e).run(&mut ctx, &mut machine_vs, Fuel(1000));
+
+        assert_eq!(
+            (
+                machine_result.map_err(|err| err.resolve(&ctx)),
+                machine_vs.resolve(&ctx)
+            ),
+            (
+                small_step_result.map_err(|err| err.resolve(&ctx)),
+                small_step_vs.resolve(&ctx)
+            ),
+            "Failed on {} {}",
+            stack_src,
+            e_src
+        );
+    }
+
+    let mut bsa = BigStepAssertionParser::new()
+        .parse(
+            &mut ctx,
+            "⟨v1⟩ $s⟨v2⟩ {$s clone clone} drop ⇓ ⟨v1⟩ $s⟨v2 v2⟩ {$s clone} drop",
+        )
+        .unwrap();
+    let mut m = Machine::new(&bsa.1);
+    assert_eq!(m.step(&mut ctx, &mut bsa.0), Ok(()));
+    assert_eq!(bsa.0, bsa.2);
+    assert_eq!(m.remaining(), bsa.3);
+}
@@ -0,0 +1,127 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::*;
+use crate::display::*;
+use crate::interp::Interp;
+use crate::observer::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Records each event along with the state after it.
+#[derive(Default)]
+struct Recorder(Rc<RefCell<Vec<String>>>);
+
+impl EvalObserver for Recorder {
+    fn step(&mut self, ctx: &Context, step: Step, vs: &ValueStack, e: &Expr) {
+        let step = match step {
+            Step::Intrinsic(intr) => intr.to_string(),
+            Step::Call(sym) => format!("call {}", sym.resolve(ctx)),
+            Step::Native(sym) => format!("native {}", sym.resolve(ctx)),
+            Step::Quote => "quote".to_owned(),
+            Step::Push(sym) => format!("push {}", sym.resolve(ctx)),
+            Step::Pop(sym) => format!("pop {}", sym.resolve(ctx)),
+        };
+        self.0
+            .borrow_mut()
+            .push(format!("{}: {} {}", step, vs.resolve(ctx), e.resolve(ctx)));
+    }
+
+    fn compressed(&mut self, ctx: &Context, vs: &ValueStack, e: &Expr) {
+        self.0.borrow_mut().push(format!(
+            "compressed: {} {}",
+            vs.resolve(ctx),
+            e.resolve(ctx)
+        ));
+    }
+
+    fn error(&mut self, ctx: &Context, err: &EvalError, vs: &ValueStack, e: &Expr) {
+        self.0.borrow_mut().push(format!(
+            "error: {} {} {}",
+            err.resolve(ctx),
+            vs.resolve(ctx),
+            e.resolve(ctx)
+        ));
+    }
+}
+
+#[test]
+fn test_eval_observed() {
+    let mut ctx = Context::default();
+    ctx.define_native("nop", 0, |_, _| Ok(vec![]));
+    for src in ["{fn a = [a]}", "{fn twice = clone compose}"] {
+        let fn_def = ctx.parse_fn_def(src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    let cases = [
+        (
+            "a twice nop swap",
+            vec![
+                "call a: ⟨a⟩ twice nop swap",
+                "call twice: ⟨a⟩ clone compose nop swap",
+                "clone: ⟨a a⟩ compose nop swap",
+                "compose: ⟨[a a]⟩ nop swap",
+                "native nop: ⟨[a a]⟩ swap",
+                "error: `swap` needs 2 values, but the stack has only 1. ⟨[a a]⟩ swap",
+            ],
+        ),
+        ("[a] (drop)", vec!["quote: ⟨[a]⟩ drop", "drop: ⟨⟩ "]),
+        (
+            "[a] {$s push} {$s pop}",
+            vec![
+                "quote: ⟨[a]⟩ {$s push} {$s pop}",
+                "push $s: ⟨⟩ $s⟨[a]⟩ {$s pop}",
+                "pop $s: ⟨[a]⟩ ",
+            ],
+        ),
+    ];
+    for (src, expected) in cases {
+        let recorder = Recorder::default();
+        let events = recorder.0.clone();
+        let e = ctx.parse_expr(src).unwrap();
+        let mut vs = ValueStack::default();
+        let _ = ctx.eval_observed(&mut vs, e, Fuel(10), &mut (recorder, ()));
+        assert_eq!(*events.borrow(), expected, "Failed on {}", src);
+    }
+}
+
+#[test]
+fn test_interp_observer() {
+    let mut interp = Interp::default();
+    let recorder = Recorder::default();
+    let events = recorder.0.clone();
+    interp.set_observer(Some(Box::new(recorder)));
+    let mut buffer = vec![];
+    for input in ["{fn q = [swap]}", ":trace [swap] drop clone"] {
+        interp.interp_start(input, &mut buffer).unwrap();
+        while !interp.is_done() {
+            interp.interp_step(&mut buffer).unwrap();
+        }
+    }
+    assert_eq!(
+        String::from_utf8(buffer).unwrap(),
+        concat!(
+            "Defined `q`.\n",
+            "⟨⟩ [swap] drop clone\n",
+            "⟶ ⟨[swap]⟩ drop clone\n",
+            "= ⟨q⟩ drop clone\n",
+            "⟶ ⟨⟩ clone\n",
+            "Evaluation error: `clone` needs 1 value, but the stack is empty.\n",
+            "1 | :trace [swap] drop clone\n",
+            "  |                    ^^^^^\n",
+            "  = stack: ⟨⟩\n",
+        )
+    );
+    assert_eq!(
+        *events.borrow(),
+        [
+            "quote: ⟨[swap]⟩ drop clone",
+            "compressed: ⟨q⟩ drop clone",
+            "drop: ⟨⟩ clone",
+            "error: `clone` needs 1 value, but the stack is empty. ⟨⟩ clone",
+        ]
+    );
+}
@@ -5,14 +5,16 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use crate::core::*;
+use crate::display::*;
 use crate::interp::*;
 use crate::parse::*;
+use std::rc::Rc;
 
 #[test]
 fn test_parse_interp_items() {
-    let interner = &mut Interner::default();
-    let foo_sym = Symbol(interner.get_or_intern_static("foo"));
-    let bar_sym = Symbol(interner.get_or_intern_static("bar"));
+    let ctx = &mut Context::default();
+    let foo_sym = Symbol(ctx.interner.get_or_intern_static("foo"));
+    let bar_sym = Symbol(ctx.interner.get_or_intern_static("bar"));
     let cases = [
         (
             "swap",
@@ -20,62 +22,92 @@ fn test_parse_interp_items() {
         ),
         (
             "{fn foo = }",
-            vec![InterpItem::FnDef(FnDef(foo_sym, Expr::default()))],
+            vec![InterpItem::FnDef(FnDef(foo_sym, Expr::default(), None))],
         ),
         (
             "swap{fn foo = }",
             vec![
                 InterpItem::Expr(Expr::Intrinsic(Intrinsic::Swap)),
-                InterpItem::FnDef(FnDef(foo_sym, Expr::default())),
+                InterpItem::FnDef(FnDef(foo_sym, Expr::default(), None)),
             ],
         ),
         (
             "{fn foo = }{fn bar = }",
             vec![
-                InterpItem::FnDef(FnDef(foo_sym, Expr::default())),
-                InterpItem::FnDef(FnDef(bar_sym, Expr::default())),
+                InterpItem::FnDef(FnDef(foo_sym, Expr::default(), None)),
+                InterpItem::FnDef(FnDef(bar_sym, Expr::default(), None)),
             ],
         ),
         (
             "{fn foo = }\n{fn bar = }",
             vec![
-                InterpItem::FnDef(FnDef(foo_sym, Expr::default())),
-                InterpItem::FnDef(FnDef(bar_sym, Expr::default())),
+                InterpItem::FnDef(FnDef(foo_sym, Expr::default(), None)),
+                InterpItem::FnDef(FnDef(bar_sym, Expr::default(), None)),
             ],
         ),
         (
             "{fn foo = }swap{fn bar = }drop",
             vec![
-                InterpItem::FnDef(FnDef(foo_sym, Expr::default())),
+                InterpItem::FnDef(FnDef(foo_sym, Expr::default(), None)),
                 InterpItem::Expr(Expr::Intrinsic(Intrinsic::Swap)),
-                InterpItem::FnDef(FnDef(bar_sym, Expr::default())),
+                InterpItem::FnDef(FnDef(bar_sym, Expr::default(), None)),
                 InterpItem::Expr(Expr::Intrinsic(Intrinsic::Drop)),
             ],
         ),
     ];
     for (src, expected) in cases {
-        let items = InterpItemsParser::new().parse(interner, src).unwrap();
+        let items = InterpItemsParser::new().parse(ctx, src).unwrap();
         assert_eq!(items, expected, "Failed on {}", src);
     }
 }
 
 #[test]
 fn test_parse_fn_def() {
-    let interner = &mut Interner::default();
-    let cases = &[("{fn foo = }", "foo", "")];
+    let ctx = &mut Context::default();
+    let cases = &[
+        ("{fn foo = }", "foo", ""),
+        ("{fn push = [a] {$s push}}", "push", "[a] {$s push}"),
+        ("{fn pop = push}", "pop", "push"),
+    ];
     for (fn_def_src, sym_src, e_src) in cases {
-        let fn_def = FnDefParser::new().parse(interner, fn_def_src).unwrap();
-        let sym = Symbol(interner.get_or_intern_static(sym_src));
-        let e = ExprParser::new().parse(interner, e_src).unwrap();
-        assert_eq!(fn_def, FnDef(sym, e), "{}", fn_def_src);
+        let fn_def = FnDefParser::new().parse(ctx, fn_def_src).unwrap();
+        let sym = Symbol(ctx.interner.get_or_intern_static(sym_src));
+        let e = ExprParser::new().parse(ctx, e_src).unwrap();
+        assert_eq!(fn_def, FnDef(sym, e, None), "{}", fn_def_src);
+    }
+    // Intrinsics are reserved words.
+    for fn_def_src in ["{fn swap = }", "{fn linear = }", "{fn affine = }"] {
+        assert!(FnDefParser::new().parse(ctx, fn_def_src).is_err());
+    }
+}
+
+#[test]
+fn test_parse_fn_def_signature() {
+    let ctx = &mut Context::default();
+    let cases = &[
+        ("{fn foo : -> = }", "->"),
+        ("{fn f : a b -> b a = swap}", "a b -> b a"),
+        ("{fn f : a b → b a = swap}", "a b -> b a"),
+        ("{fn g : (-> a) -> a = apply}", "(-> a) -> a"),
+        ("{fn f : (a -> (b -> a)) ->=}", "(a -> (b -> a)) ->"),
+        (
+            "{fn ap : ..r a (..r a -> ..r b) -> ..r b = apply}",
+            "..r a (..r a -> ..r b) -> ..r b",
+        ),
+        ("{fn f : ..r -> =}", "..r ->"),
+    ];
+    for (fn_def_src, sig_src) in cases {
+        let FnDef(_, _, sig) = FnDefParser::new().parse(ctx, fn_def_src).unwrap();
+        let sig = sig.unwrap().resolve(ctx).to_string();
+        assert_eq!(sig, *sig_src, "{}", fn_def_src);
     }
 }
 
 #[test]
 fn test_parse_expr_empty() {
-    let interner = &mut Interner::default();
+    let ctx = &mut Context::default();
     let input = "";
-    let e = ExprParser::new().parse(interner, input).unwrap();
+    let e = ExprParser::new().parse(ctx, input).unwrap();
     assert_eq!(e, Expr::default());
 }
 
@@ -90,29 +122,29 @@ fn test_parse_expr_intrinsic() {
         ("apply", Expr::Intrinsic(Intrinsic::Apply)),
     ];
     for (e_src, e_expected) in cases {
-        let interner = &mut Interner::default();
-        let e = ExprParser::new().parse(interner, e_src).unwrap();
+        let ctx = &mut Context::default();
+        let e = ExprParser::new().parse(ctx, e_src).unwrap();
         assert_eq!(e, e_expected);
     }
 }
 
 #[test]
 fn test_parse_expr_call() {
-    let interner = &mut Interner::default();
+    let ctx = &mut Context::default();
     let input = "foo";
-    let e = ExprParser::new().parse(interner, input).unwrap();
-    assert_eq!(e, Expr::Call(Symbol(interner.get("foo").unwrap())));
+    let e = ExprParser::new().parse(ctx, input).unwrap();
+    assert_eq!(e, Expr::Call(Symbol(ctx.interner.get("foo").unwrap())));
 }
 
 #[test]
 fn test_parse_expr_call2() {
-    let interner = &mut Interner::default();
+    let ctx = &mut Context::default();
     let inputs = &["foo bar", "(foo bar)", "((foo bar))"];
     for input in inputs {
-        let e = ExprParser::new().parse(interner, input).unwrap();
+        let e = ExprParser::new().parse(ctx, input).unwrap();
         let e2 = Expr::Compose(vec![
-            Expr::Call(Symbol(interner.get("foo").unwrap())),
-            Expr::Call(Symbol(interner.get("bar").unwrap())),
+            Expr::Call(Symbol(ctx.interner.get("foo").unwrap())),
+            Expr::Call(Symbol(ctx.interner.get("bar").unwrap())),
         ]);
         assert_eq!(e, e2);
     }
@@ -120,25 +152,156 @@ fn test_parse_expr_call2() {
 
 #[test]
 fn test_parse_expr_quote_call() {
-    let interner = &mut Interner::default();
+    let ctx = &mut Context::default();
     let inputs = &["[foo]", "[(foo)]", "[((foo))]"];
     for input in inputs {
-        let e = ExprParser::new().parse(interner, input).unwrap();
-        let e2 = Expr::Quote(Box::new(Expr::Call(Symbol(interner.get("foo").unwrap()))));
+        let e = ExprParser::new().parse(ctx, input).unwrap();
+        let e2 = Expr::Quote(Rc::new(Expr::Call(Symbol(
+            ctx.interner.get("foo").unwrap(),
+        ))));
         assert_eq!(e, e2);
     }
 }
 
 #[test]
 fn test_parse_expr_quote_call2() {
-    let interner = &mut Interner::default();
+    let ctx = &mut Context::default();
     let inputs = &["[foo bar]", "[(foo bar)]", "[((foo bar))]"];
     for input in inputs {
-        let e = ExprParser::new().parse(interner, input).unwrap();
-        let e2 = Expr::Quote(Box::new(Expr::Compose(vec![
-            Expr::Call(Symbol(interner.get("foo").unwrap())),
-            Expr::Call(Symbol(interner.get("bar").unwrap())),
+        let e = ExprParser::new().parse(ctx, input).unwrap();
+        let e2 = Expr::Quote(Rc::new(Expr::Compose(vec![
+            Expr::Call(Symbol(ctx.interner.get("foo").unwrap())),
+            Expr::Call(Symbol(ctx.interner.get("bar").unwrap())),
         ])));
         assert_eq!(e, e2);
     }
 }
+
+#[test]
+fn test_parse_expr_named_stacks() {
+    let ctx = &mut Context::default();
+    let s = Symbol(ctx.interner.get_or_intern_static("$s"));
+    let t = Symbol(ctx.interner.get_or_intern_static("$t"));
+    let cases = [
+        ("{$s push}", Expr::Push(s)),
+        ("{$s pop}", Expr::Pop(s)),
+        ("{$s}", Expr::With(s, Box::default())),
+        (
+            "{$s swap drop}",
+            Expr::With(
+                s,
+                Box::new(Expr::Compose(vec![
+                    Expr::Intrinsic(Intrinsic::Swap),
+                    Expr::Intrinsic(Intrinsic::Drop),
+                ])),
+            ),
+        ),
+        ("{$s {$t pop} }", Expr::With(s, Box::new(Expr::Pop(t)))),
+        ("{$s\n  push }", Expr::Push(s)),
+        (
+            "{$s push swap}",
+            Expr::With(
+                s,
+                Box::new(Expr::Compose(vec![
+                    Expr::Call(Symbol(ctx.interner.get_or_intern_static("push"))),
+                    Expr::Intrinsic(Intrinsic::Swap),
+                ])),
+            ),
+        ),
+        (
+            "push pop",
+            Expr::Compose(vec![
+                Expr::Call(Symbol(ctx.interner.get_or_intern_static("push"))),
+                Expr::Call(Symbol(ctx.interner.get_or_intern_static("pop"))),
+            ]),
+        ),
+        (
+            "clone {$s push}",
+            Expr::Compose(vec![Expr::Intrinsic(Intrinsic::Clone), Expr::Push(s)]),
+        ),
+    ];
+    for (e_src, e_expected) in cases {
+        let e = ExprParser::new().parse(ctx, e_src).unwrap();
+        assert_eq!(e, e_expected, "Failed on {}", e_src);
+    }
+    for e_src in [
+        "{$s push}",
+        "{$s pop}",
+        "{$s}",
+        "{$s swap {$t [clone] apply}} drop",
+    ] {
+        let e = ExprParser::new().parse(ctx, e_src).unwrap();
+        assert_eq!(e.resolve(ctx).to_string(), e_src);
+    }
+}
+
+#[test]
+fn test_parse_value_stack_named_stacks() {
+    let ctx = &mut Context::default();
+    let cases = [
+        ("⟨a⟩ $s⟨[b]⟩", "⟨a⟩ $s⟨[b]⟩"),
+        ("⟨⟩ $t⟨b⟩ $s⟨a⟩", "⟨⟩ $s⟨a⟩ $t⟨b⟩"),
+        ("⟨⟩ $s⟨a⟩ $s⟨b⟩", "⟨⟩ $s⟨a b⟩"),
+        ("⟨a⟩ $s⟨⟩", "⟨a⟩"),
+    ];
+    for (vs_src, expected) in cases {
+        let vs = ValueStackParser::new().parse(ctx, vs_src).unwrap();
+        assert_eq!(
+            vs.resolve(ctx).to_string(),
+            expected,
+            "Failed on {}",
+            vs_src
+        );
+    }
+}
+
+#[test]
+fn test_parse_source_file_errors() {
+    let src = concat!(
+        "{fn f = swap}\n",
+        "{fn g swap}\n",
+        "[drop\n",
+        "{fn h = f f}\n",
+        "clone ⟩ drop\n",
+        "{fn i = (swap}\n",
+        "f h\n",
+    );
+    let ctx = &mut Context::default();
+    let file = SourceFile::parse(ctx, src);
+    let lines: Vec<_> = file
+        .errors
+        .iter()
+        .map(|d| d.render(src).lines().nth(1).unwrap().to_owned())
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "2 | {fn g swap}",
+            "4 | {fn h = f f}",
+            "5 | clone ⟩ drop",
+            "6 | {fn i = (swap}"
+        ]
+    );
+    assert_eq!(
+        file.errors[0].message,
+        "Parse error: Unexpected `swap`; expected `:` or `=`."
+    );
+    let mut defined = vec![];
+    let mut es = vec![];
+    for (item, _) in file.items {
+        match item {
+            InterpItem::FnDef(fn_def) => {
+                defined.push(fn_def.0.resolve(ctx));
+                ctx.define_fn(fn_def).unwrap();
+            }
+            InterpItem::Expr(e) => es.push(e),
+            InterpItem::Clear => ctx.clear_fns(),
+        }
+    }
+    assert_eq!(defined, ["f", "h"]);
+    assert_eq!(es.len(), 3);
+    let mut vs = ValueStackParser::new().parse(ctx, "⟨v1 v2⟩").unwrap();
+    let e = es.pop().unwrap();
+    assert_eq!(ctx.eval(&mut vs, e, Fuel(10)), Ok(Outcome::Normal));
+    assert_eq!(vs.resolve(ctx).to_string(), "⟨v2 v1⟩");
+}
@@ -0,0 +1,279 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::builtin::FN_DEF_SRCS;
+use crate::core::*;
+use crate::display::*;
+use crate::types::*;
+
+#[test]
+fn test_infer() {
+    let mut ctx = Context::default();
+    for fn_def_src in FN_DEF_SRCS.iter() {
+        let fn_def = ctx.parse_fn_def(fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    ctx.define_native("read", 1, |_, args| Ok(args.to_vec()));
+    for src in [
+        "{fn loop = loop}",
+        "{fn even = clone odd}",
+        "{fn odd = drop even}",
+        "{fn grow = [v1] grow}",
+        "{fn bad = v1 undefined}",
+    ] {
+        let fn_def = ctx.parse_fn_def(src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    let cases = [
+        ("", Ok("∀ρ. ρ → ρ")),
+        ("swap", Ok("∀ρ a b. ρ a b → ρ b a")),
+        ("clone", Ok("∀ρ a. ρ a → ρ a a")),
+        ("drop", Ok("∀ρ a. ρ a → ρ")),
+        ("quote", Ok("∀ρ σ a. ρ a → ρ (σ → σ a)")),
+        ("compose", Ok("∀ρ σ τ υ. ρ (σ → τ) (τ → υ) → ρ (σ → υ)")),
+        ("apply", Ok("∀ρ σ. ρ (ρ → σ) → σ")),
+        ("[swap] apply", Ok("∀ρ a b. ρ a b → ρ b a")),
+        ("swap swap", Ok("∀ρ a b. ρ a b → ρ a b")),
+        ("v1 v2", Ok("∀ρ σ τ. ρ → ρ (σ → σ) (τ → τ)")),
+        ("false", Ok("∀ρ σ a. ρ → ρ (σ a → σ)")),
+        ("true", Ok("∀ρ σ a b. ρ → ρ (σ a b → σ b)")),
+        ("rotate3", Ok("∀ρ a b c. ρ a b c → ρ b c a")),
+        // Functions are polymorphic where they are called.
+        ("n2", Ok("∀ρ σ. ρ → ρ (σ (σ → σ) → σ)")),
+        ("loop", Ok("∀ρ σ. ρ → σ")),
+        ("even", Ok("∀ρ σ a. ρ a → σ")),
+        ("read", Ok("∀ρ σ a. ρ a → σ")),
+        (
+            "clone apply",
+            Err("`apply` needs an infinite stack, ρ = σ (ρ → τ)."),
+        ),
+        (
+            "[clone] clone apply",
+            Err("`apply` needs an infinite type, a = (ρ a → ρ a a)."),
+        ),
+        ("undefined", Err("`undefined` is not defined.")),
+        ("[bad]", Err("In `bad`: `undefined` is not defined.")),
+        (
+            "grow",
+            Err("In `grow`: `grow` needs an infinite stack, ρ = ρ (σ → σ (τ → τ))."),
+        ),
+        (
+            "add",
+            Err(concat!(
+                "In `add`, in `succ`: `compose5` needs an infinite stack, ",
+                "ρ = ρ (σ → σ) (σ → τ)."
+            )),
+        ),
+    ];
+    // Parsing interns symbols, so parse the expressions before checking them.
+    let es: Vec<Expr> = cases
+        .iter()
+        .map(|(src, _)| ctx.parse_expr(src).unwrap())
+        .collect();
+    let mut checker = TypeChecker::new(&ctx);
+    for ((src, expected), e) in cases.iter().zip(es.iter()) {
+        let result = checker.infer(e);
+        let actual = match &result {
+            Ok(fn_type) => Ok(fn_type.to_string()),
+            Err(err) => Err(err.resolve(&ctx).to_string()),
+        };
+        assert_eq!(
+            actual.as_deref().map_err(String::as_str),
+            *expected,
+            "Failed on {}",
+            src
+        );
+    }
+    let undefined = ctx.symbol("undefined").unwrap();
+    assert_eq!(checker.fn_type(undefined), None);
+    let loop_ = ctx.symbol("loop").unwrap();
+    assert!(matches!(checker.fn_type(loop_), Some(Ok(_))));
+}
+
+#[test]
+fn test_define_fn_signature() {
+    let mut ctx = Context::default();
+    for fn_def_src in FN_DEF_SRCS.iter() {
+        let fn_def = ctx.parse_fn_def(fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    }
+    let cases = [
+        ("{fn swap2 : a b c d -> c d a b = rotate4 rotate4}", Ok(())),
+        ("{fn dup : x -> x x = clone}", Ok(())),
+        ("{fn q : a -> (-> a) = quote}", Ok(())),
+        ("{fn forever : -> = forever}", Ok(())),
+        // Row variables name the rest of the stack, e.g. the stack that a
+        // quotation is applied to.
+        ("{fn ap : ..r a (..r a -> ..r b) -> ..r b = apply}", Ok(())),
+        ("{fn ap2 : ..r (..r -> ..s) -> ..s = apply}", Ok(())),
+        ("{fn swap3 : ..r a b -> ..r b a = swap}", Ok(())),
+        (
+            "{fn bad : ..r a -> ..s a = clone drop}",
+            Err(concat!(
+                "`bad` has the type ∀ρ a. ρ a → ρ a, ",
+                "which doesn't match its signature ..r a -> ..s a."
+            )),
+        ),
+        (
+            "{fn bad : a (a -> b) -> b = apply}",
+            Err(concat!(
+                "`bad` has the type ∀ρ σ. ρ (ρ → σ) → σ, ",
+                "which doesn't match its signature a (a -> b) -> b."
+            )),
+        ),
+        (
+            "{fn bad : a b -> a b = swap}",
+            Err(concat!(
+                "`bad` has the type ∀ρ a b. ρ a b → ρ b a, ",
+                "which doesn't match its signature a b -> a b."
+            )),
+        ),
+        // A signature can't be more general than the definition.
+        (
+            "{fn bad : a b -> c = drop}",
+            Err("`bad` has the type ∀ρ a. ρ a → ρ, which doesn't match its signature a b -> c."),
+        ),
+        (
+            "{fn bad : (-> a) -> a = apply}",
+            Err(concat!(
+                "`bad` has the type ∀ρ σ. ρ (ρ → σ) → σ, ",
+                "which doesn't match its signature (-> a) -> a."
+            )),
+        ),
+        (
+            "{fn bad : -> = undefined}",
+            Err("In `bad`: `undefined` is not defined."),
+        ),
+        // A rejected redefinition leaves the old one in place.
+        (
+            "{fn dup : x -> x = clone}",
+            Err("`dup` has the type ∀ρ a. ρ a → ρ a a, which doesn't match its signature x -> x."),
+        ),
+    ];
+    for (src, expected) in cases {
+        let fn_def = ctx.parse_fn_def(src).unwrap();
+        let result = ctx.define_fn(fn_def).map(|_| ());
+        let actual = result.map_err(|err| err.resolve(&ctx).to_string());
+        assert_eq!(actual, expected.map_err(String::from), "Failed on {}", src);
+    }
+    let bad = ctx.symbol("bad").unwrap();
+    assert!(!ctx.fns.contains_key(&bad));
+    assert_eq!(ctx.signature(bad), None);
+    let dup = ctx.symbol("dup").unwrap();
+    assert_eq!(
+        ctx.signature(dup).unwrap().resolve(&ctx).to_string(),
+        "x -> x x"
+    );
+    // Functions with signatures are called at them.
+    let es: Vec<Expr> = ["swap2", "[dup] apply q"]
+        .iter()
+        .map(|src| ctx.parse_expr(src).unwrap())
+        .collect();
+    let mut checker = TypeChecker::new(&ctx);
+    let types: Vec<String> = es
+        .iter()
+        .map(|e| checker.infer(e).unwrap().to_string())
+        .collect();
+    assert_eq!(
+        types,
+        [
+            "∀ρ a b c d. ρ a b c d → ρ c d a b",
+            "∀ρ σ a. ρ a → ρ a (σ → σ a)",
+        ]
+    );
+}
+
+#[test]
+fn test_type_cache() {
+    let mut ctx = Context::default();
+    let define_fn = |ctx: &mut Context, fn_def_src: &str| {
+        let fn_def = ctx.parse_fn_def(fn_def_src).unwrap();
+        ctx.define_fn(fn_def).unwrap();
+    };
+    let fn_type = |ctx: &Context, name: &str| {
+        let sym = ctx.symbol(name).unwrap();
+        let fn_type = TypeChecker::new(ctx).fn_type(sym).unwrap();
+        fn_type
+            .map(|f| f.to_string())
+            .map_err(|err| err.resolve(ctx).to_string())
+    };
+    let is_cached = |ctx: &Context, name: &str| {
+        let sym = ctx.symbol(name).unwrap();
+        ctx.types.borrow().fn_types.contains_key(&sym)
+    };
+    for fn_def_src in [
+        "{fn g = swap}",
+        "{fn f = g}",
+        "{fn h = f}",
+        "{fn other = clone}",
+    ] {
+        define_fn(&mut ctx, fn_def_src);
+    }
+    // Inferring the type of a function infers only the functions it calls.
+    assert_eq!(fn_type(&ctx, "h"), Ok("∀ρ a b. ρ a b → ρ b a".to_owned()));
+    assert!(is_cached(&ctx, "g") && is_cached(&ctx, "f") && is_cached(&ctx, "h"));
+    assert!(!is_cached(&ctx, "other"));
+
+    // Redefining a function forgets the types of the functions that call it,
+    // and only theirs.
+    fn_type(&ctx, "other").unwrap();
+    define_fn(&mut ctx, "{fn g = drop}");
+    assert!(!is_cached(&ctx, "g") && !is_cached(&ctx, "f") && !is_cached(&ctx, "h"));
+    assert!(is_cached(&ctx, "other"));
+    assert_eq!(fn_type(&ctx, "h"), Ok("∀ρ a. ρ a → ρ".to_owned()));
+
+    // Types with errors aren't cached.
+    define_fn(&mut ctx, "{fn g = undefined}");
+    assert_eq!(
+        fn_type(&ctx, "h"),
+        Err("In `h`, in `f`, in `g`: `undefined` is not defined.".to_owned())
+    );
+    assert!(!is_cached(&ctx, "h"));
+
+    ctx.clear_fns();
+    assert!(!is_cached(&ctx, "other"));
+}
+
+#[test]
+fn test_define_fn_signature_cache() {
+    let mut ctx = Context::default();
+    let define_fn = |ctx: &mut Context, fn_def_src: &str| {
+        let fn_def = ctx.parse_fn_def(fn_def_src).unwrap();
+        ctx.define_fn(fn_def).map(|_| ())
+    };
+    let is_cached = |ctx: &Context, name: &str| {
+        let sym = ctx.symbol(name).unwrap();
+        ctx.types.borrow().fn_types.contains_key(&sym)
+    };
+    for fn_def_src in [
+        "{fn g = swap}",
+        "{fn other = clone}",
+        "{fn f : a b -> b a = g}",
+    ] {
+        define_fn(&mut ctx, fn_def_src).unwrap();
+    }
+    // Checking a signature infers only the functions that the definition
+    // calls, and keeps their types for the next definitions.
+    assert!(is_cached(&ctx, "g"));
+    assert!(!is_cached(&ctx, "other"));
+
+    // Redefining a function with a signature forgets the types of the
+    // functions that call it, so they are checked again against the new one.
+    define_fn(&mut ctx, "{fn h : a b -> b a = f}").unwrap();
+    assert!(is_cached(&ctx, "f"));
+    define_fn(&mut ctx, "{fn f : a -> a a = clone}").unwrap();
+    assert!(!is_cached(&ctx, "f") && !is_cached(&ctx, "h"));
+    assert!(is_cached(&ctx, "g"));
+    let h = ctx.symbol("h").unwrap();
+    assert!(matches!(
+        TypeChecker::new(&ctx).fn_type(h),
+        Some(Err(TypeError::SignatureMismatch(..)))
+    ));
+
+    // A rejected definition leaves the cached types as they were.
+    assert!(define_fn(&mut ctx, "{fn g : a -> = swap}").is_err());
+    assert!(is_cached(&ctx, "g"));
+}
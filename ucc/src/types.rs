@@ -0,0 +1,767 @@
+// Copyright (c) 2021 Scott J Maddox
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::core::{Context, Expr, FnDef, Intrinsic, Map, Signature, Symbol, TypeAnnotation};
+use fxhash::FxHashSet;
+
+/// A variable that stands for the type of a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TypeVar(pub(crate) usize);
+
+/// A variable that stands for the types of the values at the bottom of a stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RowVar(pub(crate) usize);
+
+/// The type of a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Var(TypeVar),
+    /// The type of a quotation, which has the stack effect of the quoted expression
+    Fn(Box<FnType>),
+}
+
+/// The type of a stack: a row for the values at the bottom, followed by the
+/// types of the values on top of them, bottom-most first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackType {
+    pub row: RowVar,
+    pub types: Vec<Type>,
+}
+
+/// A stack effect, from the type of the stack before to the type after. The
+/// type of an expression or function is universally quantified over all of
+/// its variables, e.g. `swap : ∀ρ a b. ρ a b → ρ b a`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FnType {
+    pub input: StackType,
+    pub output: StackType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeError {
+    UndefinedFn(Symbol),
+    /// Typing the intrinsic or call needs a value type that contains itself,
+    /// e.g. when a quotation is applied to a stack that holds it.
+    InfiniteType(Expr, TypeVar, Type),
+    /// Typing the intrinsic or call needs a stack type that contains itself,
+    /// e.g. when a recursive function grows the stack.
+    InfiniteStack(Expr, RowVar, StackType),
+    /// A function that is called has a type error.
+    InFn(Symbol, Box<TypeError>),
+    /// The type of a function doesn't match its signature.
+    SignatureMismatch(Symbol, Box<Signature>, FnType),
+    /// The stack context, push or pop uses a named stack, which stack-effect
+    /// types don't describe.
+    NamedStack(Expr),
+}
+
+/// A variable that would have to be bound to a type that contains it
+enum Infinite {
+    Type(TypeVar, Type),
+    Stack(RowVar, StackType),
+}
+
+fn infinite(at: Expr, inf: Infinite) -> TypeError {
+    match inf {
+        Infinite::Type(v, t) => TypeError::InfiniteType(at, v, t),
+        Infinite::Stack(r, s) => TypeError::InfiniteStack(at, r, s),
+    }
+}
+
+/// The bindings of the variables created during inference
+#[derive(Default)]
+struct Subst {
+    types: Vec<Option<Type>>,
+    rows: Vec<Option<StackType>>,
+}
+
+impl Subst {
+    fn fresh_type(&mut self) -> Type {
+        self.types.push(None);
+        Type::Var(TypeVar(self.types.len() - 1))
+    }
+
+    fn fresh_stack(&mut self) -> StackType {
+        self.rows.push(None);
+        StackType {
+            row: RowVar(self.rows.len() - 1),
+            types: vec![],
+        }
+    }
+
+    /// `ρ` followed by `n` fresh value types, bottom-most first.
+    fn fresh_types(&mut self, row: RowVar, n: usize) -> StackType {
+        StackType {
+            row,
+            types: (0..n).map(|_| self.fresh_type()).collect(),
+        }
+    }
+
+    /// Follow the bindings of `t` until it is a function type or an unbound variable.
+    fn shallow(&self, t: &Type) -> Type {
+        let mut t = t;
+        while let Type::Var(TypeVar(i)) = t {
+            match &self.types[*i] {
+                Some(bound) => t = bound,
+                None => break,
+            }
+        }
+        t.clone()
+    }
+
+    /// Expand the bindings of the row of `s` until it is unbound.
+    fn expand(&self, s: &StackType) -> StackType {
+        let mut s = s.clone();
+        while let Some(bound) = &self.rows[s.row.0] {
+            let mut types = bound.types.clone();
+            types.append(&mut s.types);
+            s = StackType {
+                row: bound.row,
+                types,
+            };
+        }
+        s
+    }
+
+    /// Apply all bindings to `t`.
+    fn zonk(&self, t: &Type) -> Type {
+        match self.shallow(t) {
+            Type::Var(v) => Type::Var(v),
+            Type::Fn(f) => Type::Fn(Box::new(self.zonk_fn(&f))),
+        }
+    }
+
+    fn zonk_stack(&self, s: &StackType) -> StackType {
+        let s = self.expand(s);
+        StackType {
+            row: s.row,
+            types: s.types.iter().map(|t| self.zonk(t)).collect(),
+        }
+    }
+
+    fn zonk_fn(&self, f: &FnType) -> FnType {
+        FnType {
+            input: self.zonk_stack(&f.input),
+            output: self.zonk_stack(&f.output),
+        }
+    }
+
+    fn unify(&mut self, t1: &Type, t2: &Type) -> Result<(), Infinite> {
+        match (self.shallow(t1), self.shallow(t2)) {
+            (Type::Var(v1), Type::Var(v2)) if v1 == v2 => Ok(()),
+            (Type::Var(v), t) | (t, Type::Var(v)) => {
+                let t = self.zonk(&t);
+                if t.vars().0.contains(&v) {
+                    Err(Infinite::Type(v, t))
+                } else {
+                    self.types[v.0] = Some(t);
+                    Ok(())
+                }
+            }
+            (Type::Fn(f1), Type::Fn(f2)) => self.unify_fns(&f1, &f2),
+        }
+    }
+
+    /// Unify the types of the values on top of the stacks, from the top down,
+    /// until one of them runs out, then bind its row to the rest of the other.
+    fn unify_stacks(&mut self, s1: &StackType, s2: &StackType) -> Result<(), Infinite> {
+        let (mut s1, mut s2) = (s1.clone(), s2.clone());
+        loop {
+            s1 = self.expand(&s1);
+            s2 = self.expand(&s2);
+            let (row, s) = match (s1.types.pop(), s2.types.pop()) {
+                (Some(t1), Some(t2)) => {
+                    self.unify(&t1, &t2)?;
+                    continue;
+                }
+                (None, Some(t2)) => {
+                    s2.types.push(t2);
+                    (s1.row, s2)
+                }
+                (Some(t1), None) => {
+                    s1.types.push(t1);
+                    (s2.row, s1)
+                }
+                (None, None) if s1.row == s2.row => return Ok(()),
+                (None, None) => (s1.row, s2),
+            };
+            let s = self.zonk_stack(&s);
+            if s.vars().1.contains(&row) {
+                return Err(Infinite::Stack(row, s));
+            }
+            self.rows[row.0] = Some(s);
+            return Ok(());
+        }
+    }
+
+    fn unify_fns(&mut self, f1: &FnType, f2: &FnType) -> Result<(), Infinite> {
+        self.unify_stacks(&f1.input, &f2.input)?;
+        self.unify_stacks(&f1.output, &f2.output)
+    }
+
+    /// A copy of `f` with fresh variables in place of its own.
+    fn instantiate(&mut self, f: &FnType) -> FnType {
+        let (types, rows) = f.vars();
+        let types: Map<TypeVar, Type> = types.into_iter().map(|v| (v, self.fresh_type())).collect();
+        let rows: Map<RowVar, RowVar> = rows
+            .into_iter()
+            .map(|r| (r, self.fresh_stack().row))
+            .collect();
+        f.rename(&types, &rows)
+    }
+
+    /// The stack effect of a signature, with fresh variables.
+    fn signature(&mut self, sig: &Signature) -> FnType {
+        self.signature_with(sig, &mut Map::default(), &mut Map::default())
+    }
+
+    /// The stack effect of a signature, with the variables in `vars` and
+    /// `rows` for the names they are given for, and fresh ones for the other
+    /// names.
+    fn signature_with(
+        &mut self,
+        sig: &Signature,
+        vars: &mut Map<Symbol, Type>,
+        rows: &mut Map<Symbol, RowVar>,
+    ) -> FnType {
+        let unnamed = self.fresh_stack().row;
+        let mut stack = |row: Option<Symbol>, types: &[TypeAnnotation], subst: &mut Self| {
+            let row = match row {
+                Some(sym) => *rows.entry(sym).or_insert_with(|| subst.fresh_stack().row),
+                None => unnamed,
+            };
+            StackType {
+                row,
+                types: types
+                    .iter()
+                    .map(|t| subst.annotation(t, vars, rows))
+                    .collect(),
+            }
+        };
+        FnType {
+            input: stack(sig.input_row, &sig.input, self),
+            output: stack(sig.output_row, &sig.output, self),
+        }
+    }
+
+    fn annotation(
+        &mut self,
+        t: &TypeAnnotation,
+        vars: &mut Map<Symbol, Type>,
+        rows: &mut Map<Symbol, RowVar>,
+    ) -> Type {
+        match t {
+            TypeAnnotation::Var(sym) => match vars.get(sym) {
+                Some(v) => v.clone(),
+                None => {
+                    let v = self.fresh_type();
+                    vars.insert(*sym, v.clone());
+                    v
+                }
+            },
+            TypeAnnotation::Fn(sig) => Type::Fn(Box::new(self.signature_with(sig, vars, rows))),
+        }
+    }
+
+    /// Whether the bindings only rename the variables of `f`, each to a
+    /// different variable, so that it is no more specific than it was.
+    fn is_renaming(&self, f: &FnType) -> bool {
+        let mut types: Map<TypeVar, TypeVar> = Map::default();
+        let mut rows: Map<RowVar, RowVar> = Map::default();
+        let zonked = self.zonk_fn(f);
+        let mut stacks = vec![(&f.input, &zonked.input), (&f.output, &zonked.output)];
+        let mut renamed_fns = vec![];
+        while let Some((s, renamed)) = stacks.pop() {
+            if s.types.len() != renamed.types.len()
+                || *rows.entry(s.row).or_insert(renamed.row) != renamed.row
+            {
+                return false;
+            }
+            for (t, renamed_t) in s.types.iter().zip(renamed.types.iter()) {
+                match (t, renamed_t) {
+                    (Type::Var(v), Type::Var(renamed_v)) => {
+                        if *types.entry(*v).or_insert(*renamed_v) != *renamed_v {
+                            return false;
+                        }
+                    }
+                    (Type::Fn(f), Type::Fn(renamed_f)) => renamed_fns.push((f, renamed_f)),
+                    _ => return false,
+                }
+            }
+            if let Some((f, renamed_f)) = renamed_fns.pop() {
+                stacks.push((&f.input, &renamed_f.input));
+                stacks.push((&f.output, &renamed_f.output));
+            }
+        }
+        let distinct_types: FxHashSet<_> = types.values().collect();
+        let distinct_rows: FxHashSet<_> = rows.values().collect();
+        distinct_types.len() == types.len() && distinct_rows.len() == rows.len()
+    }
+
+    /// The stack effect of an intrinsic, with fresh variables.
+    fn intrinsic(&mut self, intr: Intrinsic) -> FnType {
+        let rho = self.fresh_stack();
+        let with = |rho: &StackType, types: Vec<Type>| StackType {
+            row: rho.row,
+            types,
+        };
+        let fn_type =
+            |input: StackType, output: StackType| Type::Fn(Box::new(FnType { input, output }));
+        match intr {
+            Intrinsic::Swap => {
+                let (a, b) = (self.fresh_type(), self.fresh_type());
+                FnType {
+                    input: with(&rho, vec![a.clone(), b.clone()]),
+                    output: with(&rho, vec![b, a]),
+                }
+            }
+            Intrinsic::Clone => {
+                let a = self.fresh_type();
+                FnType {
+                    input: with(&rho, vec![a.clone()]),
+                    output: with(&rho, vec![a.clone(), a]),
+                }
+            }
+            Intrinsic::Drop => {
+                let a = self.fresh_type();
+                FnType {
+                    input: with(&rho, vec![a]),
+                    output: rho,
+                }
+            }
+            Intrinsic::Quote => {
+                let a = self.fresh_type();
+                let sigma = self.fresh_stack();
+                FnType {
+                    input: with(&rho, vec![a.clone()]),
+                    output: with(&rho, vec![fn_type(sigma.clone(), with(&sigma, vec![a]))]),
+                }
+            }
+            Intrinsic::Compose => {
+                let (sigma, tau, upsilon) =
+                    (self.fresh_stack(), self.fresh_stack(), self.fresh_stack());
+                FnType {
+                    input: with(
+                        &rho,
+                        vec![
+                            fn_type(sigma.clone(), tau.clone()),
+                            fn_type(tau, upsilon.clone()),
+                        ],
+                    ),
+                    output: with(&rho, vec![fn_type(sigma, upsilon)]),
+                }
+            }
+            Intrinsic::Linear | Intrinsic::Affine => {
+                let a = self.fresh_type();
+                FnType {
+                    input: with(&rho, vec![a.clone()]),
+                    output: with(&rho, vec![a]),
+                }
+            }
+            Intrinsic::Apply => {
+                let sigma = self.fresh_stack();
+                FnType {
+                    input: with(&rho, vec![fn_type(rho.clone(), sigma.clone())]),
+                    output: sigma,
+                }
+            }
+        }
+    }
+}
+
+impl Type {
+    /// The variables in this type, in the order in which they appear.
+    pub(crate) fn vars(&self) -> (Vec<TypeVar>, Vec<RowVar>) {
+        let mut vars = (vec![], vec![]);
+        self.collect_vars(&mut vars);
+        vars
+    }
+
+    fn collect_vars(&self, vars: &mut (Vec<TypeVar>, Vec<RowVar>)) {
+        match self {
+            Type::Var(v) => {
+                if !vars.0.contains(v) {
+                    vars.0.push(*v);
+                }
+            }
+            Type::Fn(f) => f.collect_vars(vars),
+        }
+    }
+
+    fn rename(&self, types: &Map<TypeVar, Type>, rows: &Map<RowVar, RowVar>) -> Type {
+        match self {
+            Type::Var(v) => types[v].clone(),
+            Type::Fn(f) => Type::Fn(Box::new(f.rename(types, rows))),
+        }
+    }
+}
+
+impl StackType {
+    /// The variables in this stack type, in the order in which they appear.
+    pub(crate) fn vars(&self) -> (Vec<TypeVar>, Vec<RowVar>) {
+        let mut vars = (vec![], vec![]);
+        self.collect_vars(&mut vars);
+        vars
+    }
+
+    fn collect_vars(&self, vars: &mut (Vec<TypeVar>, Vec<RowVar>)) {
+        if !vars.1.contains(&self.row) {
+            vars.1.push(self.row);
+        }
+        for t in self.types.iter() {
+            t.collect_vars(vars);
+        }
+    }
+
+    fn rename(&self, types: &Map<TypeVar, Type>, rows: &Map<RowVar, RowVar>) -> StackType {
+        StackType {
+            row: rows[&self.row],
+            types: self.types.iter().map(|t| t.rename(types, rows)).collect(),
+        }
+    }
+}
+
+impl FnType {
+    /// The variables in this stack effect, in the order in which they appear.
+    pub(crate) fn vars(&self) -> (Vec<TypeVar>, Vec<RowVar>) {
+        let mut vars = (vec![], vec![]);
+        self.collect_vars(&mut vars);
+        vars
+    }
+
+    fn collect_vars(&self, vars: &mut (Vec<TypeVar>, Vec<RowVar>)) {
+        self.input.collect_vars(vars);
+        self.output.collect_vars(vars);
+    }
+
+    fn rename(&self, types: &Map<TypeVar, Type>, rows: &Map<RowVar, RowVar>) -> FnType {
+        FnType {
+            input: self.input.rename(types, rows),
+            output: self.output.rename(types, rows),
+        }
+    }
+}
+
+/// The types of the functions defined in a context that have been inferred,
+/// which are kept until the functions, or those they call, are redefined
+#[derive(Default)]
+pub(crate) struct TypeCache {
+    pub(crate) fn_types: Map<Symbol, FnType>,
+    /// The functions whose cached types depend on each function
+    callers: Map<Symbol, Vec<Symbol>>,
+}
+
+impl TypeCache {
+    fn insert(&mut self, sym: Symbol, e: &Expr, f: FnType) {
+        for callee in e.calls() {
+            self.callers.entry(callee).or_default().push(sym);
+        }
+        self.fn_types.insert(sym, f);
+    }
+
+    /// Forget the type of `sym` and of the functions that call it, directly
+    /// or not.
+    pub(crate) fn invalidate(&mut self, sym: Symbol) {
+        self.fn_types.remove(&sym);
+        let mut stale = vec![sym];
+        while let Some(sym) = stale.pop() {
+            for caller in self.callers.remove(&sym).unwrap_or_default() {
+                if self.fn_types.remove(&caller).is_some() {
+                    stale.push(caller);
+                }
+            }
+        }
+    }
+}
+
+/// Infers the types of expressions, given the types of the functions defined
+/// in a context, which are inferred as they are called and cached in the
+/// context.
+pub struct TypeChecker<'a> {
+    ctx: &'a Context,
+    subst: Subst,
+    /// The types of the functions inferred by this checker, including those
+    /// with type errors, which aren't cached
+    fn_types: Map<Symbol, Result<FnType, TypeError>>,
+    /// The types of the functions whose definitions are being inferred,
+    /// which they call each other at without instantiating them
+    inferring: Map<Symbol, FnType>,
+    /// The function whose definition is being checked, and its signature,
+    /// which the definition calls it at
+    checking: Option<(Symbol, FnType)>,
+}
+
+impl<'a> TypeChecker<'a> {
+    pub fn new(ctx: &'a Context) -> Self {
+        TypeChecker {
+            ctx,
+            subst: Subst::default(),
+            fn_types: Map::default(),
+            inferring: Map::default(),
+            checking: None,
+        }
+    }
+
+    /// The type of the function `sym`, or `None` if it isn't defined by an
+    /// expression.
+    pub fn fn_type(&mut self, sym: Symbol) -> Option<Result<FnType, TypeError>> {
+        self.infer_fn(sym);
+        self.known(sym)
+    }
+
+    /// The type of `sym`, if it has been inferred.
+    fn known(&self, sym: Symbol) -> Option<Result<FnType, TypeError>> {
+        match self.fn_types.get(&sym) {
+            Some(fn_type) => Some(fn_type.clone()),
+            None => self.ctx.types.borrow().fn_types.get(&sym).cloned().map(Ok),
+        }
+    }
+
+    /// Infer the type of `sym`, if it is defined by an expression, and of the
+    /// functions it calls, unless they have been inferred already.
+    fn infer_fn(&mut self, sym: Symbol) {
+        if !self.ctx.fns.contains_key(&sym) || self.known(sym).is_some() {
+            return;
+        }
+        // The functions are inferred as they are defined, so the definition
+        // being checked isn't called at its signature by them.
+        let checking = self.checking.take();
+        for group in recursive_groups(self.ctx, sym, |sym| self.known(sym).is_some()) {
+            self.infer_group(&group);
+        }
+        self.checking = checking;
+    }
+
+    /// Infer the type of `e`.
+    pub fn infer(&mut self, e: &Expr) -> Result<FnType, TypeError> {
+        let f = self.infer_expr(e)?;
+        Ok(self.subst.zonk_fn(&f))
+    }
+
+    /// Check that the type of a definition matches its signature, if any,
+    /// given that recursive calls are at that signature.
+    pub fn check_fn_def(&mut self, fn_def: &FnDef) -> Result<(), TypeError> {
+        let FnDef(sym, e, sig) = fn_def;
+        match sig {
+            Some(sig) => {
+                let f = self.subst.signature(sig);
+                self.checking = Some((*sym, f));
+                let result = self.check_signature(*sym, e, sig);
+                self.checking = None;
+                result
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the type of the definition `e` of `sym` matches `sig`.
+    fn check_signature(&mut self, sym: Symbol, e: &Expr, sig: &Signature) -> Result<(), TypeError> {
+        let inferred = self
+            .infer_expr(e)
+            .map_err(|err| TypeError::InFn(sym, Box::new(err)))?;
+        let inferred = self.subst.zonk_fn(&inferred);
+        let expected = self.subst.signature(sig);
+        if self.subst.unify_fns(&inferred, &expected).is_ok() && self.subst.is_renaming(&expected) {
+            Ok(())
+        } else {
+            Err(TypeError::SignatureMismatch(
+                sym,
+                Box::new(sig.clone()),
+                inferred,
+            ))
+        }
+    }
+
+    /// Infer the types of a group of functions that call each other, so that
+    /// each without a signature is monomorphic in its own definition and
+    /// those of the others.
+    fn infer_group(&mut self, syms: &[Symbol]) {
+        let ctx = self.ctx;
+        let (annotated, group): (Vec<Symbol>, Vec<Symbol>) = syms
+            .iter()
+            .partition(|sym| ctx.signatures.contains_key(sym));
+        // Functions are called at their signatures, if any, which are
+        // checked along with the rest of the group.
+        for &sym in annotated.iter() {
+            let f = self.subst.signature(&ctx.signatures[&sym]);
+            self.fn_types.insert(sym, Ok(f));
+        }
+        for &sym in group.iter() {
+            let input = self.subst.fresh_stack();
+            let output = self.subst.fresh_stack();
+            self.inferring.insert(sym, FnType { input, output });
+        }
+        let mut result = Ok(());
+        for &sym in group.iter() {
+            let inferred = self.infer_expr(&ctx.fns[&sym]).and_then(|f| {
+                let expected = self.inferring[&sym].clone();
+                self.subst
+                    .unify_fns(&expected, &f)
+                    .map_err(|inf| infinite(Expr::Call(sym), inf))
+            });
+            if let Err(err) = inferred {
+                result = Err(TypeError::InFn(sym, Box::new(err)));
+                break;
+            }
+        }
+        for &sym in group.iter() {
+            let f = self.inferring.remove(&sym).unwrap();
+            let fn_type = result.clone().map(|()| self.subst.zonk_fn(&f));
+            self.fn_types.insert(sym, fn_type);
+        }
+        for sym in annotated {
+            if let Err(err) = self.check_signature(sym, &ctx.fns[&sym], &ctx.signatures[&sym]) {
+                self.fn_types.insert(sym, Err(err));
+            }
+        }
+        let mut cache = ctx.types.borrow_mut();
+        for sym in syms {
+            if let Some(Ok(f)) = self.fn_types.get(sym) {
+                cache.insert(*sym, &ctx.fns[sym], f.clone());
+            }
+        }
+    }
+
+    /// The stack effect of calling `sym`.
+    fn call(&mut self, sym: Symbol) -> Result<FnType, TypeError> {
+        if let Some(f) = self.inferring.get(&sym) {
+            return Ok(f.clone());
+        }
+        if let Some((checking, f)) = &self.checking {
+            if *checking == sym {
+                let f = f.clone();
+                return Ok(self.subst.instantiate(&f));
+            }
+        }
+        self.infer_fn(sym);
+        if let Some(fn_type) = self.known(sym) {
+            let f = fn_type?;
+            Ok(self.subst.instantiate(&f))
+        } else if let Some(native) = self.ctx.natives.get(&sym) {
+            // What a native function returns is unknown.
+            let rho = self.subst.fresh_stack();
+            Ok(FnType {
+                input: self.subst.fresh_types(rho.row, native.arity),
+                output: self.subst.fresh_stack(),
+            })
+        } else {
+            Err(TypeError::UndefinedFn(sym))
+        }
+    }
+
+    /// Infer the stack effect of `e`, by threading the type of the stack
+    /// through its elements.
+    fn infer_expr(&mut self, e: &Expr) -> Result<FnType, TypeError> {
+        enum Task<'e> {
+            Infer(&'e Expr),
+            EndQuote,
+        }
+        // Keep the input and current stack type of each enclosing quotation.
+        let input = self.subst.fresh_stack();
+        let mut frames = vec![(input.clone(), input)];
+        let mut tasks = vec![Task::Infer(e)];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Infer(Expr::Compose(es)) => tasks.extend(es.iter().rev().map(Task::Infer)),
+                // A pinned call has the type of the expression it holds.
+                Task::Infer(Expr::Pinned(_, _, pe)) => tasks.push(Task::Infer(pe)),
+                Task::Infer(Expr::Quote(qe)) => {
+                    let input = self.subst.fresh_stack();
+                    frames.push((input.clone(), input));
+                    tasks.push(Task::EndQuote);
+                    tasks.push(Task::Infer(qe));
+                }
+                Task::EndQuote => {
+                    let (input, output) = frames.pop().unwrap();
+                    let t = Type::Fn(Box::new(FnType { input, output }));
+                    frames.last_mut().unwrap().1.types.push(t);
+                }
+                Task::Infer(e) => {
+                    let f = match e {
+                        Expr::Intrinsic(intr) => self.subst.intrinsic(*intr),
+                        Expr::Call(sym) => self.call(*sym)?,
+                        Expr::With(..) | Expr::Push(_) | Expr::Pop(_) => {
+                            return Err(TypeError::NamedStack(e.clone()))
+                        }
+                        _ => unreachable!(),
+                    };
+                    let current = &mut frames.last_mut().unwrap().1;
+                    self.subst
+                        .unify_stacks(current, &f.input)
+                        .map_err(|inf| infinite(e.clone(), inf))?;
+                    *current = f.output;
+                }
+            }
+        }
+        let (input, output) = frames.pop().unwrap();
+        Ok(FnType { input, output })
+    }
+}
+
+/// The groups of functions defined in `ctx` that call each other, of `sym`
+/// and the functions it calls other than those that are `known`, each after
+/// the groups of the functions that it calls, found by Tarjan's algorithm.
+fn recursive_groups(
+    ctx: &Context,
+    sym: Symbol,
+    known: impl Fn(Symbol) -> bool,
+) -> Vec<Vec<Symbol>> {
+    let callees = |sym: Symbol| {
+        let mut callees = ctx.fns[&sym].calls();
+        callees.retain(|&callee| ctx.fns.contains_key(&callee) && !known(callee));
+        callees.sort_unstable_by_key(|callee| ctx.interner.resolve(&callee.0));
+        callees.dedup();
+        callees
+    };
+    let mut indices: Map<Symbol, (usize, usize)> = Map::default();
+    let mut on_stack = FxHashSet::default();
+    let mut stack = vec![];
+    let mut groups = vec![];
+    // Keep the callees of each definition that remain to be visited.
+    let mut visits: Vec<(Symbol, Vec<Symbol>)> = vec![];
+    let mut next = Some(sym);
+    loop {
+        if let Some(sym) = next.take() {
+            indices.insert(sym, (indices.len(), indices.len()));
+            on_stack.insert(sym);
+            stack.push(sym);
+            let mut sym_callees = callees(sym);
+            sym_callees.reverse();
+            visits.push((sym, sym_callees));
+        }
+        let (sym, sym_callees) = match visits.last_mut() {
+            Some((sym, sym_callees)) => (*sym, sym_callees),
+            None => break,
+        };
+        match sym_callees.pop() {
+            Some(callee) if !indices.contains_key(&callee) => next = Some(callee),
+            Some(callee) => {
+                if on_stack.contains(&callee) {
+                    let index = indices[&callee].0;
+                    let low = &mut indices.get_mut(&sym).unwrap().1;
+                    *low = (*low).min(index);
+                }
+            }
+            None => {
+                visits.pop();
+                let (index, low) = indices[&sym];
+                if let Some((caller, _)) = visits.last() {
+                    let caller_low = &mut indices.get_mut(caller).unwrap().1;
+                    *caller_low = (*caller_low).min(low);
+                }
+                if index == low {
+                    let start = stack.iter().rposition(|&s| s == sym).unwrap();
+                    let group = stack.split_off(start);
+                    for s in group.iter() {
+                        on_stack.remove(s);
+                    }
+                    groups.push(group);
+                }
+            }
+        }
+    }
+    groups
+}
@@ -12,6 +12,12 @@ pub struct Ucci {
     buffer: Vec<u8>,
 }
 
+impl Default for Ucci {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl Ucci {
     #[wasm_bindgen(constructor)]
@@ -26,21 +32,31 @@ impl Ucci {
         self.interp.is_done()
     }
 
-    pub fn interp_start(&mut self, input: &str, write_output: &js_sys::Function) {
+    pub fn interp_start(
+        &mut self,
+        input: &str,
+        write_output: &js_sys::Function,
+    ) -> Result<(), JsValue> {
         self.buffer.clear();
-        self.interp.interp_start(input, &mut self.buffer).unwrap();
-        let output = unsafe { std::str::from_utf8_unchecked(&self.buffer[..]) };
-        write_output
-            .call1(&JsValue::null(), &JsValue::from(output))
-            .unwrap();
+        self.interp
+            .interp_start(input, &mut self.buffer)
+            .map_err(|err| JsValue::from(err.to_string()))?;
+        self.write_output(write_output)
     }
 
-    pub fn interp_step(&mut self, write_output: &js_sys::Function) {
+    pub fn interp_step(&mut self, write_output: &js_sys::Function) -> Result<(), JsValue> {
         self.buffer.clear();
-        self.interp.interp_step(&mut self.buffer).unwrap();
-        let output = unsafe { std::str::from_utf8_unchecked(&self.buffer[..]) };
-        write_output
-            .call1(&JsValue::null(), &JsValue::from(output))
-            .unwrap();
+        self.interp
+            .interp_step(&mut self.buffer)
+            .map_err(|err| JsValue::from(err.to_string()))?;
+        self.write_output(write_output)
+    }
+}
+
+impl Ucci {
+    fn write_output(&self, write_output: &js_sys::Function) -> Result<(), JsValue> {
+        let output = String::from_utf8_lossy(&self.buffer[..]);
+        write_output.call1(&JsValue::null(), &JsValue::from(output.as_ref()))?;
+        Ok(())
     }
 }
@@ -6,10 +6,45 @@
 
 use linefeed::{Interface, ReadResult};
 use std::error::Error;
-use std::io::stdout;
-use ucc::interp::Interp;
+use std::io::{stderr, stdin, stdout, IsTerminal, Read};
+use std::process::exit;
+use ucc::interp::{Interp, TestSummary};
+
+static USAGE: &str = "\
+Usage:
+    ucci             start the interactive interpreter, or run a script
+                     from standard input if it isn't a terminal
+    ucci run <path>  run the script at <path>, or standard input if <path>
+                     is -
+    ucci test <path>...
+                     check the assertions in each test file
+";
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] if stdin().is_terminal() => repl(),
+        [] | ["run", "-"] => {
+            let mut source = String::new();
+            stdin().read_to_string(&mut source)?;
+            run(&source)
+        }
+        ["test", ref paths @ ..] if !paths.is_empty() => test(paths),
+        ["run", path] => match std::fs::read_to_string(path) {
+            Ok(source) => run(&source),
+            Err(err) => {
+                eprintln!("Failed to read `{}`: {}.", path, err);
+                exit(1)
+            }
+        },
+        _ => {
+            eprint!("{}", USAGE);
+            exit(2)
+        }
+    }
+}
+
+fn repl() -> Result<(), Box<dyn Error>> {
     let mut interp = Interp::default();
 
     println!("Untyped Concatenative Calculus Interpreter (UCCI)");
@@ -18,10 +53,44 @@ fn main() -> Result<(), Box<dyn Error>> {
     reader.set_prompt("\n>>> ")?;
     while let ReadResult::Input(input) = reader.read_line()? {
         reader.add_history(input.clone());
-        interp.interp_start(input.as_str(), &mut stdout()).unwrap();
+        interp.interp_start(input.as_str(), &mut stdout())?;
         while !interp.is_done() {
-            interp.interp_step(&mut stdout()).unwrap();
+            interp.interp_step(&mut stdout())?;
         }
     }
     Ok(())
 }
+
+/// Run a script, exiting with a non-zero status if it fails.
+fn run(source: &str) -> Result<(), Box<dyn Error>> {
+    let mut interp = Interp::default();
+    if !interp.run(source, &mut stdout(), &mut stderr())? {
+        exit(1)
+    }
+    Ok(())
+}
+
+/// Run test files, exiting with a non-zero status if any fail.
+fn test(paths: &[&str]) -> Result<(), Box<dyn Error>> {
+    let mut is_success = true;
+    for path in paths {
+        println!("Testing `{}`.", path);
+        // A file that can't be read counts as one failure.
+        let summary = match std::fs::read_to_string(path) {
+            Ok(source) => Interp::default().test(&source, &mut stdout())?,
+            Err(err) => {
+                eprintln!("Failed to read `{}`: {}.", path, err);
+                TestSummary {
+                    failed: 1,
+                    ..TestSummary::default()
+                }
+            }
+        };
+        println!("{}.", summary);
+        is_success &= summary.is_success();
+    }
+    if !is_success {
+        exit(1)
+    }
+    Ok(())
+}